@@ -0,0 +1,77 @@
+//! Allocations made while connections come and go, with and without the buffer pool.
+//!
+//! Run with `cargo bench --bench buffer_pool`, each mode opens the same connections one after
+//! the other and prints what was allocated per connection, by the client side included.
+
+use mini_rest::{
+    client,
+    logging::Level,
+    request::Method,
+    response::Response,
+    server::{self, ServerActions},
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+const CONNECTIONS: u64 = 1000;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// System allocator counting the allocations made through it.
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[tokio::main]
+async fn main() {
+    for pooled in [false, true] {
+        let mut metrics = None;
+        let bound = server::ephemeral(|server| {
+            server.log_level(Level::Off);
+            server.read_buffer_size(READ_BUFFER_SIZE);
+            server.use_buffer_pool(pooled);
+            server.add_route(Method::Get, "/", |_req| Response::html("hello"));
+            metrics = Some(server.metrics());
+        })
+        .unwrap();
+        let metrics = metrics.unwrap();
+        let url = bound.url("/");
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+        for _ in 0..CONNECTIONS {
+            let response = client::get(&url).await.unwrap();
+            assert_eq!(response.body(), b"hello");
+            // The buffer goes back to the pool once the server sees the connection closed
+            while metrics.snapshot().active_connections > 0 {
+                tokio::time::sleep(Duration::from_micros(100)).await;
+            }
+        }
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes;
+        bound.shutdown().await.unwrap();
+        println!(
+            "use_buffer_pool({:>5}): {} allocations, {} bytes per connection",
+            pooled,
+            allocations / CONNECTIONS,
+            bytes / CONNECTIONS
+        );
+    }
+}
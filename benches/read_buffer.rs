@@ -0,0 +1,50 @@
+//! Time of large uploads with different read buffer sizes.
+//!
+//! Run with `cargo bench --bench read_buffer`, each size uploads the same body a few times and
+//! prints the mean time of an upload.
+
+use mini_rest::{
+    client,
+    logging::Level,
+    request::Method,
+    response::Response,
+    server::{self, ServerActions},
+};
+use std::time::{Duration, Instant};
+
+const BODY_SIZE: usize = 16 * 1024 * 1024;
+const UPLOADS: u32 = 8;
+const BUFFER_SIZES: [usize; 4] = [1024, 8 * 1024, 64 * 1024, 256 * 1024];
+
+#[tokio::main]
+async fn main() {
+    let body = vec![b'a'; BODY_SIZE];
+    for size in BUFFER_SIZES {
+        let bound = server::ephemeral(|server| {
+            server.log_level(Level::Off);
+            server.read_buffer_size(size);
+            server.add_route(Method::Post, "/upload", |req| {
+                Response::html(req.body().len().to_string())
+            });
+        })
+        .unwrap();
+        let url = bound.url("/upload");
+        let mut total = Duration::ZERO;
+        for _ in 0..UPLOADS {
+            let started = Instant::now();
+            let response = client::request(Method::Post, &url, &[], &body)
+                .await
+                .unwrap();
+            total += started.elapsed();
+            // A buffer size that broke the framing of the body would show up here
+            assert_eq!(response.body(), BODY_SIZE.to_string().as_bytes());
+        }
+        bound.shutdown().await.unwrap();
+        println!(
+            "read_buffer_size {:>6}: {:?} per {} MiB upload",
+            size,
+            total / UPLOADS,
+            BODY_SIZE / (1024 * 1024)
+        );
+    }
+}
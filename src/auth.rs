@@ -0,0 +1,101 @@
+//! Credentials sent in the `Authorization` header.
+
+use crate::request::Request;
+
+/// User and password sent with the `Basic` scheme.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Returns the token of an `Authorization: Bearer <token>` header.
+///
+/// The scheme is matched without case and whitespace around the token is trimmed. `None` for
+/// other schemes, or when the token is empty or has characters a token can't have.
+///
+/// # Example
+/// ```
+/// use mini_rest::{auth, request::Method, response::Response, server::{self, ServerActions}};
+/// let mut server = server::new("127.0.0.1:8080".to_string());
+/// server.add_route(Method::Get, "/me", |req| match auth::bearer_token(req) {
+///     Some(token) => Response::html(format!("token {}", token)),
+///     None => Response::new(401).with_header("WWW-Authenticate", "Bearer"),
+/// });
+/// ```
+pub fn bearer_token(request: &Request) -> Option<&str> {
+    let token = credentials(request, "Bearer")?;
+    is_token68(token).then_some(token)
+}
+
+/// Returns the user and password of an `Authorization: Basic <credentials>` header.
+///
+/// `None` for other schemes, or when the credentials aren't valid base64 of UTF-8 text with
+/// a colon between the user and the password.
+///
+/// # Example
+/// ```
+/// use mini_rest::{auth, request::Method, response::Response, server::{self, ServerActions}};
+/// let mut server = server::new("127.0.0.1:8080".to_string());
+/// server.add_route(Method::Get, "/admin", |req| match auth::basic_credentials(req) {
+///     Some(credentials) if credentials.username == "admin" => Response::html("welcome"),
+///     _ => Response::new(401).with_header("WWW-Authenticate", "Basic realm=\"admin\""),
+/// });
+/// ```
+pub fn basic_credentials(request: &Request) -> Option<BasicCredentials> {
+    let encoded = credentials(request, "Basic")?;
+    let decoded = String::from_utf8(decode_base64(encoded)?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some(BasicCredentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// Returns what follows `scheme` in the `Authorization` header, trimmed.
+fn credentials<'a>(request: &'a Request, scheme: &str) -> Option<&'a str> {
+    let value = request.header("Authorization")?.trim();
+    let (name, rest) = value.split_once(' ')?;
+    name.eq_ignore_ascii_case(scheme).then(|| rest.trim())
+}
+
+/// Returns whether `value` has the `token68` syntax credentials are sent with.
+fn is_token68(value: &str) -> bool {
+    let data = value.trim_end_matches('=');
+    !data.is_empty()
+        && data
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-._~+/".contains(&byte))
+}
+
+/// Decodes standard base64, padding included.
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let bytes = encoded.as_bytes();
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    let data = encoded.trim_end_matches('=').as_bytes();
+    if bytes.len() - data.len() > 2 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(data.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for byte in data {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
@@ -0,0 +1,137 @@
+//! In-memory cache of the responses of a route, enabled with `Route::cache_for`.
+
+use crate::{
+    clock::Clock,
+    request::{Method, Request},
+    response::Response,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Most targets stored by a route, the one stored the longest ago is forgotten first past it.
+const MAX_ENTRIES: usize = 1024;
+
+/// Responses of a route stored by request target, kept for `ttl`.
+pub(crate) struct ResponseCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Vec<Entry>>>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Response stored for a target, with the request headers it varies on.
+struct Entry {
+    stored: Instant,
+    /// Names listed in the `Vary` of the response, with their values in the request.
+    vary: Vec<(String, Option<String>)>,
+    response: Response,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(ttl: Duration, clock: Arc<dyn Clock>) -> ResponseCache {
+        ResponseCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Returns a copy of the response stored for `request`, if it's still fresh.
+    pub(crate) fn get(&self, request: &Request) -> Option<Response> {
+        let key = key(request)?;
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key)?
+            .iter()
+            .find(|entry| {
+                self.clock.since(entry.stored) < self.ttl
+                    && entry
+                        .vary
+                        .iter()
+                        .all(|(name, value)| request.header(name) == value.as_deref())
+            })
+            .and_then(|entry| entry.response.try_clone())
+    }
+
+    /// Stores a copy of `response` for `request` when it can be reused.
+    ///
+    /// Only successful answers to `GET` and `HEAD` with a body in memory are stored, never the
+    /// ones marked `no-store` or `private`, setting a cookie or varying on `*`. Those belong
+    /// to one client and would be replayed to the others, like answers to requests sent with
+    /// `Authorization` unless they're marked `public`, `s-maxage` or `must-revalidate`.
+    pub(crate) fn put(&self, request: &Request, response: &Response) {
+        let key = match key(request) {
+            Some(key) => key,
+            None => return,
+        };
+        let successful = (200..300).contains(&response.status()) && response.status() != 206;
+        let directive = |name: &str| {
+            response.header("Cache-Control").is_some_and(|value| {
+                value.split(',').any(|directive| {
+                    let directive = directive.split('=').next().unwrap_or("").trim();
+                    directive.eq_ignore_ascii_case(name)
+                })
+            })
+        };
+        let shared = directive("public") || directive("s-maxage") || directive("must-revalidate");
+        let personal = directive("private")
+            || response.header("Set-Cookie").is_some()
+            || (request.header("Authorization").is_some() && !shared);
+        let vary: Vec<&str> = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if !successful || directive("no-store") || personal || vary.contains(&"*") {
+            return;
+        }
+        let copy = match response.try_clone() {
+            Some(copy) => copy,
+            None => return,
+        };
+        let vary = vary
+            .iter()
+            .map(|name| (name.to_string(), request.header(name).map(str::to_string)))
+            .collect();
+
+        let mut entries = self.entries.lock().unwrap();
+        // Expired entries are dropped as new ones come, so the cache doesn't grow forever
+        let ttl = self.ttl;
+        let clock = &self.clock;
+        entries.retain(|_, variants| {
+            variants.retain(|entry| clock.since(entry.stored) < ttl);
+            !variants.is_empty()
+        });
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, variants)| variants.iter().map(|entry| entry.stored).max())
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let variants = entries.entry(key).or_default();
+        variants.retain(|entry: &Entry| entry.vary != vary);
+        variants.push(Entry {
+            stored: self.clock.now(),
+            vary,
+            response: copy,
+        });
+    }
+}
+
+/// Returns the key of the responses to `request`, `None` if they can't be cached.
+///
+/// `HEAD` requests are answered by `GET` routes, they share their entries.
+fn key(request: &Request) -> Option<String> {
+    match request.method() {
+        Method::Get | Method::Head => Some(request.path().to_string()),
+        _ => None,
+    }
+}
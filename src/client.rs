@@ -0,0 +1,153 @@
+//! Minimal HTTP client for outbound requests.
+//!
+//! Only plain `http` urls are supported and redirects aren't followed. Every request opens its
+//! own connection, closed once the response is read, and fails with `TimedOut` if the server
+//! still hasn't closed it after 30 seconds.
+
+use crate::{
+    error::ServerError,
+    request::{self, Method},
+    response::{self, Response},
+};
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// How long a request may take, from connecting to the end of the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parts of an `http://host[:port]/path` url.
+pub(crate) struct Url<'a> {
+    /// Authority as written in the url, sent as the `Host` header.
+    pub(crate) authority: &'a str,
+    /// Address to connect to, with port `80` when the url has none.
+    pub(crate) address: String,
+    /// Path with the query string, empty if the url has none.
+    pub(crate) path: &'a str,
+}
+
+/// Splits a plain `http` url into its parts, `None` if it isn't one.
+pub(crate) fn parse_url(url: &str) -> Option<Url<'_>> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(index) => rest.split_at(index),
+        None => (rest, ""),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    // IPv6 addresses are written inside brackets, their colons aren't port separators
+    let has_port = match authority.rfind(']') {
+        Some(bracket) => authority[bracket..].contains(':'),
+        None => authority.contains(':'),
+    };
+    let address = match has_port {
+        true => authority.to_string(),
+        false => format!("{}:80", authority),
+    };
+    Some(Url {
+        authority,
+        address,
+        path,
+    })
+}
+
+/// Sends a `GET` request to `url`.
+///
+/// # Example
+/// ```rust,no_run
+/// use mini_rest::client;
+/// #[tokio::main]
+/// async fn main() {
+///     let response = client::get("http://localhost:8080/health").await.unwrap();
+///     println!("{}", response.status());
+/// }
+/// ```
+pub async fn get(url: &str) -> Result<Response, ServerError> {
+    request(Method::Get, url, &[], &[]).await
+}
+
+/// Sends a request to `url` and returns the response, its body already read.
+///
+/// `Host`, `Content-Length` and `Connection` are added unless they are in `headers`.
+///
+/// # Example
+/// ```rust,no_run
+/// use mini_rest::{client, request::Method};
+/// #[tokio::main]
+/// async fn main() {
+///     let headers = [("Content-Type", "application/json")];
+///     let body = br#"{"name":"ana"}"#;
+///     let response = client::request(Method::Post, "http://localhost:8080/users", &headers, body)
+///         .await
+///         .unwrap();
+///     assert_eq!(response.status(), 201);
+/// }
+/// ```
+pub async fn request(
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<Response, ServerError> {
+    let error = |source: io::Error| ServerError::Request {
+        url: url.to_string(),
+        source,
+    };
+    let parsed = parse_url(url).ok_or_else(|| {
+        error(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not an http url",
+        ))
+    })?;
+
+    let mut all: Vec<(String, String)> = headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let mut add = |name: &str, value: String| {
+        if !all.iter().any(|(key, _)| key.eq_ignore_ascii_case(name)) {
+            all.push((name.to_string(), value));
+        }
+    };
+    add("Host", parsed.authority.to_string());
+    if !body.is_empty() || matches!(method, Method::Post | Method::Put | Method::Patch) {
+        add("Content-Length", body.len().to_string());
+    }
+    add("Connection", "close".to_string());
+    let path = match parsed.path {
+        "" => "/",
+        path => path,
+    };
+    let bytes = request::serialize(&method, path, &all, body);
+    send(&parsed.address, &bytes).await.map_err(error)
+}
+
+/// Sends an already serialized request asking to close the connection, and reads the response
+/// until the server closes it.
+pub(crate) async fn send(address: &str, request: &[u8]) -> io::Result<Response> {
+    send_within(address, request, REQUEST_TIMEOUT).await
+}
+
+/// Same as `send`, failing with `TimedOut` once `timeout` passed, so a server that keeps the
+/// connection open can't hang the request.
+pub(crate) async fn send_within(
+    address: &str,
+    request: &[u8],
+    timeout: Duration,
+) -> io::Result<Response> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(address).await?;
+        stream.write_all(request).await?;
+        let mut answer = vec![];
+        stream.read_to_end(&mut answer).await?;
+        Ok::<_, io::Error>(answer)
+    };
+    let answer = tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no response in time"))??;
+    response::parse_response(&answer)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed response"))
+}
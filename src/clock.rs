@@ -0,0 +1,56 @@
+//! Source of the current time for expiries, replaced by a manual clock in tests.
+
+use std::{sync::Arc, time::Instant};
+#[cfg(test)]
+use std::{sync::Mutex, time::Duration};
+
+/// Tells the time to the caches, the idempotency keys and the idle connections.
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Returns the time gone by since `earlier`, zero if it's in the future.
+    fn since(&self, earlier: Instant) -> std::time::Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+/// Clock of the system, the one used outside tests.
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Returns the clock of the system.
+pub(crate) fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// Clock standing still until the test moves it, so expiries are tested without sleeping.
+#[cfg(test)]
+pub(crate) struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub(crate) fn new() -> Arc<ManualClock> {
+        Arc::new(ManualClock {
+            now: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub(crate) fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,208 @@
+//! Compression of response bodies, available with the `compression` feature.
+
+use crate::{middleware::Middleware, request::Request, response::Response};
+use std::io::{Read, Write};
+
+/// Content codings the server can apply, in the order it prefers them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+pub(crate) const PREFERRED: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+impl Encoding {
+    /// Returns the name of the coding in `Accept-Encoding` and `Content-Encoding`.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    /// Returns the extension of files precompressed with the coding, like `app.js.gz`.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+            Encoding::Identity => "",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+                encoder.write_all(body)?;
+                Ok(encoder.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Identity => Ok(body.to_vec()),
+        }
+    }
+}
+
+/// Reason a request body couldn't be decoded.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DecodeError {
+    /// The coding isn't one the server knows.
+    Unsupported,
+    /// The decoded body is bigger than allowed.
+    TooLarge,
+    /// The body isn't valid for its coding.
+    Invalid,
+}
+
+/// Decodes a request body compressed with `coding`, giving up once it grows past `max` bytes.
+///
+/// `deflate` is meant to be zlib data, raw deflate streams sent by some clients are accepted.
+pub(crate) fn decode(coding: &str, body: &[u8], max: usize) -> Result<Vec<u8>, DecodeError> {
+    let limit = max as u64 + 1;
+    let mut decoded = vec![];
+    let read = match coding.to_ascii_lowercase().as_str() {
+        "identity" => return Ok(body.to_vec()),
+        "gzip" | "x-gzip" => flate2::read::GzDecoder::new(body)
+            .take(limit)
+            .read_to_end(&mut decoded),
+        "deflate" => {
+            let zlib = flate2::read::ZlibDecoder::new(body)
+                .take(limit)
+                .read_to_end(&mut decoded);
+            match zlib {
+                Ok(size) => Ok(size),
+                Err(_) => {
+                    decoded.clear();
+                    flate2::read::DeflateDecoder::new(body)
+                        .take(limit)
+                        .read_to_end(&mut decoded)
+                }
+            }
+        }
+        "br" => brotli::Decompressor::new(body, 4096)
+            .take(limit)
+            .read_to_end(&mut decoded),
+        _ => return Err(DecodeError::Unsupported),
+    };
+    match read {
+        Ok(_) if decoded.len() > max => Err(DecodeError::TooLarge),
+        Ok(_) => Ok(decoded),
+        Err(_) => Err(DecodeError::Invalid),
+    }
+}
+
+/// Picks the coding with the highest quality in an `Accept-Encoding` header.
+///
+/// When nothing is acceptable the body is sent as is.
+pub(crate) fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+    accepted(accept_encoding)
+        .first()
+        .copied()
+        .unwrap_or(Encoding::Identity)
+}
+
+/// Returns the codings an `Accept-Encoding` header accepts, the best one first.
+///
+/// Codings the client didn't list are only acceptable through `*`, ties go to the one the
+/// server prefers.
+pub(crate) fn accepted(accept_encoding: Option<&str>) -> Vec<Encoding> {
+    let accepted: Vec<(String, f32)> = accept_encoding
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|coding| {
+            let mut parameters = coding.split(';');
+            let name = parameters.next()?.trim().to_ascii_lowercase();
+            let mut quality = 1.0;
+            for parameter in parameters {
+                if let Some(value) = parameter.trim().strip_prefix("q=") {
+                    quality = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+            (!name.is_empty()).then_some((name, quality))
+        })
+        .collect();
+    let quality = |name: &str| {
+        let listed = accepted.iter().find(|(coding, _)| coding == name);
+        let any = accepted.iter().find(|(coding, _)| coding == "*");
+        listed.or(any).map_or(0.0, |(_, quality)| *quality)
+    };
+
+    let mut acceptable: Vec<(Encoding, f32)> = PREFERRED
+        .iter()
+        .map(|encoding| (*encoding, quality(encoding.as_str())))
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // The sort is stable, so equal qualities keep the order of preference
+    acceptable.sort_by(|a, b| b.1.total_cmp(&a.1));
+    acceptable
+        .into_iter()
+        .map(|(encoding, _)| encoding)
+        .collect()
+}
+
+/// Middleware compressing response bodies with the coding the client prefers.
+///
+/// Only bodies already in memory are compressed, streamed ones (files, chunked) are sent as
+/// they are, and so are small bodies and media types that are compressed already.
+pub struct Compression {
+    min_size: usize,
+}
+
+impl Compression {
+    pub(crate) fn new() -> Compression {
+        Compression { min_size: 256 }
+    }
+
+    /// Sets the smallest body, in bytes, worth compressing, `256` by default.
+    pub fn min_size(mut self, min_size: usize) -> Compression {
+        self.min_size = min_size;
+        self
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &Request, response: Response) -> Response {
+        let compressible = response.header("Content-Type").is_some_and(is_compressible)
+            && response.header("Content-Encoding").is_none()
+            && response
+                .buffered_body()
+                .is_some_and(|body| body.len() >= self.min_size);
+        if !compressible {
+            return response;
+        }
+        // Caches must keep one copy per coding
+        let response = response.with_header("Vary", "Accept-Encoding");
+        let encoding = negotiate(request.header("Accept-Encoding"));
+        if encoding == Encoding::Identity {
+            return response;
+        }
+        match encoding.encode(response.body()) {
+            Ok(body) => response
+                .with_header("Content-Encoding", encoding.as_str())
+                .with_body(body),
+            Err(_) => response,
+        }
+    }
+}
+
+/// Returns whether bodies of a media type get smaller when compressed.
+fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            media_type,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
@@ -0,0 +1,210 @@
+//! Registry of the connections open in a server.
+
+use crate::{
+    clock::{self, Clock},
+    server::ConnectionInfo,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+/// Every open connection with the last time it did something.
+pub(crate) struct Connections {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, Connection>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Connection {
+    remote_addr: Option<SocketAddr>,
+    last_active: Instant,
+    /// Set while a request is being answered, busy connections are never idle.
+    busy: bool,
+    close: Arc<Notify>,
+    /// Unlike `close`, also interrupts the request being answered.
+    abort: Arc<Notify>,
+}
+
+impl Default for Connections {
+    fn default() -> Connections {
+        Connections {
+            next_id: AtomicU64::default(),
+            open: Mutex::default(),
+            clock: clock::system(),
+        }
+    }
+}
+
+impl Connections {
+    /// Registry whose connections are idle for as long as `clock` says.
+    #[cfg(test)]
+    pub(crate) fn with_clock(clock: Arc<dyn Clock>) -> Connections {
+        Connections {
+            clock,
+            ..Connections::default()
+        }
+    }
+
+    /// Tracks a new connection until the returned guard is dropped.
+    pub(crate) fn register(&self, remote_addr: Option<SocketAddr>) -> Tracked<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let close = Arc::new(Notify::new());
+        let abort = Arc::new(Notify::new());
+        let connection = Connection {
+            remote_addr,
+            last_active: self.clock.now(),
+            busy: false,
+            close: Arc::clone(&close),
+            abort: Arc::clone(&abort),
+        };
+        self.open.lock().unwrap().insert(id, connection);
+        Tracked {
+            connections: self,
+            id,
+            close,
+            abort,
+        }
+    }
+
+    /// Returns the open connections, oldest first.
+    pub(crate) fn list(&self) -> Vec<ConnectionInfo> {
+        let open = self.open.lock().unwrap();
+        let mut list: Vec<ConnectionInfo> = open
+            .iter()
+            .map(|(id, connection)| ConnectionInfo {
+                id: *id,
+                remote_addr: connection.remote_addr,
+                busy: connection.busy,
+                idle: self.clock.since(connection.last_active),
+            })
+            .collect();
+        list.sort_by_key(|connection| connection.id);
+        list
+    }
+
+    /// Aborts the connection `id`, returns false if it isn't open.
+    pub(crate) fn abort(&self, id: u64) -> bool {
+        match self.open.lock().unwrap().get(&id) {
+            Some(connection) => {
+                connection.abort.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Asks every connection idle for longer than `max_idle` to close.
+    ///
+    /// Returns how many connections were asked to close.
+    pub(crate) fn close_idle(&self, max_idle: Duration) -> usize {
+        let open = self.open.lock().unwrap();
+        let idle = open.values().filter(|connection| {
+            !connection.busy && self.clock.since(connection.last_active) > max_idle
+        });
+        let mut closed = 0;
+        for connection in idle {
+            connection.close.notify_one();
+            closed += 1;
+        }
+        closed
+    }
+
+    fn update(&self, id: u64, busy: bool) {
+        if let Some(connection) = self.open.lock().unwrap().get_mut(&id) {
+            connection.last_active = self.clock.now();
+            connection.busy = busy;
+        }
+    }
+}
+
+/// Connections open from each IP address, capped by `ServerActions::max_per_ip`.
+pub(crate) struct PerIp {
+    max: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PerIp {
+    pub(crate) fn new(max: usize) -> PerIp {
+        PerIp {
+            max,
+            open: Mutex::default(),
+        }
+    }
+
+    /// Counts a new connection from `ip` until the returned guard is dropped.
+    ///
+    /// `None` when `ip` has the most connections open already.
+    pub(crate) fn acquire(&self, ip: IpAddr) -> Option<IpSlot<'_>> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot { per_ip: self, ip })
+    }
+}
+
+/// Guard of a connection counted by `PerIp`, counts it out when dropped.
+///
+/// Dropped along with the connection whichever way it ends, a panic of its task included.
+pub(crate) struct IpSlot<'a> {
+    per_ip: &'a PerIp,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot<'_> {
+    fn drop(&mut self) {
+        let mut open = self.per_ip.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            // Forgetting addresses without connections keeps the map from growing with clients
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Guard of a tracked connection, removes it from the registry when dropped.
+pub(crate) struct Tracked<'a> {
+    connections: &'a Connections,
+    id: u64,
+    close: Arc<Notify>,
+    abort: Arc<Notify>,
+}
+
+impl Tracked<'_> {
+    /// Returns the id identifying the connection in logs and in `ConnectionsHandle`.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Records activity on the connection, `busy` tells if a request is being answered.
+    pub(crate) fn touch(&self, busy: bool) {
+        self.connections.update(self.id, busy);
+    }
+
+    /// Resolves once the connection was asked to close.
+    pub(crate) async fn closed(&self) {
+        self.close.notified().await;
+    }
+
+    /// Resolves once the connection was aborted.
+    pub(crate) async fn aborted(&self) {
+        self.abort.notified().await;
+    }
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,77 @@
+//! Verbose logging of every request and response, enabled with `ServerActions::dev_mode`.
+
+use crate::{
+    request::{Request, Version},
+    response::Response,
+};
+use std::fmt::Write;
+
+/// Bytes of a body shown before it's cut.
+const BODY_PREVIEW: usize = 512;
+
+/// Describes a request and the response answering it, headers and bodies included.
+pub(crate) fn transaction(request: &Request, response: &Response) -> String {
+    let version = match request.version() {
+        Version::Http10 => "HTTP/1.0",
+        Version::Http11 => "HTTP/1.1",
+    };
+    let mut log = format!(
+        "--> {} {} {}\n",
+        request.method().as_str(),
+        request.path(),
+        version
+    );
+    write_headers(&mut log, request.headers());
+    write_body(&mut log, Some(request.body()), request.body().len() as u64);
+
+    let _ = writeln!(log, "<-- {} {}", response.status(), response.reason());
+    write_headers(&mut log, response.headers());
+    match response.body_length() {
+        Some(length) => write_body(&mut log, response.buffered_body(), length),
+        None => log.push_str("    <chunked body>\n"),
+    }
+    log
+}
+
+fn write_headers(log: &mut String, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        let _ = writeln!(log, "    {}: {}", name, value);
+    }
+}
+
+/// Shows the start of a body as text, or only its size when it's binary or not in memory.
+fn write_body(log: &mut String, body: Option<&[u8]>, length: u64) {
+    if length == 0 {
+        return;
+    }
+    let body = match body {
+        Some(body) => body,
+        None => {
+            let _ = writeln!(log, "    <streamed body, {} bytes>", length);
+            return;
+        }
+    };
+    let preview = &body[..body.len().min(BODY_PREVIEW)];
+    // A preview may cut a character in two, only what comes before it counts
+    let text = match std::str::from_utf8(preview) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&preview[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => "",
+    };
+    let binary = text.is_empty()
+        || text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'));
+    if binary {
+        let _ = writeln!(log, "    <binary body, {} bytes>", length);
+        return;
+    }
+    for line in text.lines() {
+        let _ = writeln!(log, "    {}", line);
+    }
+    if text.len() < body.len() {
+        let _ = writeln!(log, "    <... {} bytes in total>", length);
+    }
+}
@@ -0,0 +1,109 @@
+//! Machine-readable description of the routes of a server.
+
+use crate::router::RouteInfo;
+
+/// Renders an OpenAPI document listing the paths, methods and path parameters of `routes`.
+///
+/// Nothing is known about the bodies of requests and responses, so the document has no
+/// schemas: every parameter is a string and every operation has a single default response.
+pub(crate) fn openapi(routes: &[RouteInfo]) -> String {
+    // Paths keep the order of their first route, with every method under them
+    let mut paths: Vec<(String, Vec<&RouteInfo>)> = vec![];
+    for route in routes {
+        let path = openapi_path(&route.pattern);
+        match paths.iter_mut().find(|(other, _)| *other == path) {
+            Some((_, operations)) => operations.push(route),
+            None => paths.push((path, vec![route])),
+        }
+    }
+
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|(path, operations)| {
+            let operations: Vec<String> = operations.iter().map(|route| operation(route)).collect();
+            format!("{}:{{{}}}", json_string(path), operations.join(","))
+        })
+        .collect();
+    format!(
+        "{{\"openapi\":\"3.0.3\",\"info\":{{\"title\":\"API\",\"version\":\"1.0.0\"}},\"paths\":{{{}}}}}",
+        paths.join(",")
+    )
+}
+
+/// Renders the operation of a route, keyed by its method.
+fn operation(route: &RouteInfo) -> String {
+    let mut fields = vec![];
+    if let Some(summary) = &route.summary {
+        fields.push(format!("\"summary\":{}", json_string(summary)));
+    }
+    let parameters: Vec<String> = params(&route.pattern)
+        .iter()
+        .map(|name| {
+            format!(
+                "{{\"name\":{},\"in\":\"path\",\"required\":true,\"schema\":{{\"type\":\"string\"}}}}",
+                json_string(name)
+            )
+        })
+        .collect();
+    if !parameters.is_empty() {
+        fields.push(format!("\"parameters\":[{}]", parameters.join(",")));
+    }
+    fields.push(
+        "\"responses\":{\"default\":{\"description\":\"Response of the handler\"}}".to_string(),
+    );
+    format!(
+        "{}:{{{}}}",
+        json_string(&route.method.as_str().to_ascii_lowercase()),
+        fields.join(",")
+    )
+}
+
+/// Writes the `:name` and `*name` segments of a pattern as OpenAPI `{name}` templates.
+fn openapi_path(pattern: &str) -> String {
+    let segments: Vec<String> = segments(pattern)
+        .map(|(segment, param)| match param {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect();
+    segments.join("/")
+}
+
+/// Returns the names of the parameters of a pattern, in order.
+fn params(pattern: &str) -> Vec<&str> {
+    segments(pattern).filter_map(|(_, param)| param).collect()
+}
+
+/// Splits a pattern like the router does, with the name of the parameter of each segment.
+///
+/// A `*name` segment is only a parameter at the end of the pattern.
+fn segments(pattern: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    let last = pattern.split('/').count() - 1;
+    pattern.split('/').enumerate().map(move |(index, segment)| {
+        let name = match (segment.strip_prefix(':'), segment.strip_prefix('*')) {
+            (Some(name), _) => Some(name),
+            (_, Some(name)) if index == last => Some(name),
+            _ => None,
+        };
+        (segment, name.filter(|name| !name.is_empty()))
+    })
+}
+
+/// Quotes and escapes a string as a JSON string.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,125 @@
+//! Errors reported by the server.
+
+use crate::request::Method;
+use std::{any::Any, fmt, io};
+
+/// Error that stops a server from running, or makes an outbound request fail.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The listener couldn't be bound to the address of the server.
+    Bind { address: String, source: io::Error },
+    /// The listener failed while accepting a new connection.
+    Accept(io::Error),
+    /// A request sent with the `client` module failed.
+    Request { url: String, source: io::Error },
+    /// An environment variable read by `server::from_env` has an invalid value.
+    Env { variable: String, value: String },
+}
+
+/// Error ending the body stream of a request, see `Request::body_stream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyError {
+    /// The body is larger than the `max_body_size` of the server.
+    TooLarge,
+    /// The chunked framing of the body is invalid.
+    Malformed,
+    /// The connection ended before the whole body arrived.
+    Incomplete,
+}
+
+impl BodyError {
+    /// Returns the status code fitting an answer to the request, `413` or `400`.
+    pub fn status(&self) -> u16 {
+        match self {
+            BodyError::TooLarge => 413,
+            BodyError::Malformed | BodyError::Incomplete => 400,
+        }
+    }
+}
+
+/// Panic of the handling of a request, reported to the `on_panic` callback.
+#[derive(Debug, Clone)]
+pub struct HandlerPanic {
+    pub method: Method,
+    pub path: String,
+    /// Message the handler panicked with, when it was a string.
+    pub message: Option<String>,
+}
+
+impl HandlerPanic {
+    pub(crate) fn new(method: Method, path: String, payload: &(dyn Any + Send)) -> HandlerPanic {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned());
+        HandlerPanic {
+            method,
+            path,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for HandlerPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "handler of {} {} panicked",
+            self.method.as_str(),
+            self.path
+        )?;
+        match &self.message {
+            Some(message) => write!(f, ": {}", message),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What the server does after reporting an error to the `on_error` callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Keep accepting connections.
+    Continue,
+    /// Stop accepting connections and shut the server down.
+    Shutdown,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Bind { address, source } => {
+                write!(f, "couldn't bind to {}: {}", address, source)
+            }
+            ServerError::Accept(source) => write!(f, "couldn't accept a connection: {}", source),
+            ServerError::Request { url, source } => {
+                write!(f, "request to {} failed: {}", url, source)
+            }
+            ServerError::Env { variable, value } => {
+                write!(f, "invalid value {:?} of {}", value, variable)
+            }
+        }
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::TooLarge => write!(f, "request body is too large"),
+            BodyError::Malformed => write!(f, "request body is malformed"),
+            BodyError::Incomplete => write!(f, "request body ended early"),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Bind { source, .. }
+            | ServerError::Accept(source)
+            | ServerError::Request { source, .. } => Some(source),
+            ServerError::Env { .. } => None,
+        }
+    }
+}
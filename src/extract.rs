@@ -0,0 +1,161 @@
+//! Typed handler arguments pulled out of requests.
+//!
+//! Handlers registered with `add_handler` take extractors instead of the request, each one
+//! answering with an error response when the request doesn't have what it expects.
+
+use crate::{
+    logging,
+    request::Request,
+    response::{IntoResponse, Response},
+};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    str::FromStr,
+    sync::Arc,
+};
+
+/// Values shared with every handler, stored by type.
+pub(crate) type States = HashMap<TypeId, Arc<dyn Any + Send + Sync>>;
+
+/// Values a handler argument can be built from a request with.
+pub trait FromRequest: Sized {
+    /// Builds the value, or returns the response sent instead of running the handler.
+    fn from_request(request: &Request) -> Result<Self, Response>;
+}
+
+/// First segment captured by the route pattern, parsed as `T`.
+///
+/// A segment that doesn't parse gets a `400 Bad Request`. Only the first segment is
+/// extracted, tuples and structs aren't supported: routes capturing several, like
+/// `/users/:id/posts/:post`, are registered with `add_route` to read them with
+/// `Request::param`.
+///
+/// # Example
+/// ```
+/// use mini_rest::{extract::Path, request::Method, response::Response, router::Router};
+/// let mut router = Router::new();
+/// router.add_handler(Method::Get, "/users/:id", |Path(id): Path<u32>| {
+///     Response::html(format!("user {}", id))
+/// });
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path<T>(pub T);
+
+impl<T: FromStr> FromRequest for Path<T> {
+    fn from_request(request: &Request) -> Result<Path<T>, Response> {
+        let (name, value) = request.params.first().ok_or_else(|| Response::new(500))?;
+        value.parse().map(Path).map_err(|_| {
+            Response::new(400)
+                .with_header("Content-Type", "text/plain")
+                .with_body(format!("invalid path parameter `{}`", name))
+        })
+    }
+}
+
+/// Query string deserialized as `T`, see `Request::query`.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(request: &Request) -> Result<Query<T>, Response> {
+        request.query().map(Query)
+    }
+}
+
+/// JSON body deserialized as `T`, see `Request::json`.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &Request) -> Result<Json<T>, Response> {
+        request.json().map(Json)
+    }
+}
+
+/// Copy of the value of type `T` registered with `ServerActions::state`.
+///
+/// Asking for a type that was never registered is a bug of the server, answered with a
+/// `500 Internal Server Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for State<T> {
+    fn from_request(request: &Request) -> Result<State<T>, Response> {
+        let state = request
+            .states
+            .as_ref()
+            .and_then(|states| states.get(&TypeId::of::<T>()))
+            .and_then(|state| state.downcast_ref::<T>());
+        match state {
+            Some(state) => Ok(State(state.clone())),
+            None => {
+                logging::error(format_args!(
+                    "No state of type {} registered",
+                    std::any::type_name::<T>()
+                ));
+                Err(Response::new(500))
+            }
+        }
+    }
+}
+
+/// Copy of the value of type `T` attached to the request, see `Request::extensions`.
+///
+/// A request without one gets a `500 Internal Server Error`, the middleware expected to
+/// attach it didn't run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for Extension<T> {
+    fn from_request(request: &Request) -> Result<Extension<T>, Response> {
+        match request.extensions().get::<T>() {
+            Some(value) => Ok(Extension(value)),
+            None => {
+                logging::error(format_args!(
+                    "No extension of type {} attached",
+                    std::any::type_name::<T>()
+                ));
+                Err(Response::new(500))
+            }
+        }
+    }
+}
+
+/// Functions taking extractors as arguments, `Args` being the tuple of their types.
+pub trait Handler<Args>: Send + Sync + 'static {
+    /// Extracts the arguments from `request` and runs the function.
+    fn call(&self, request: &Request) -> Response;
+}
+
+macro_rules! impl_handler {
+    ($($arg:ident),*) => {
+        #[allow(non_snake_case, unused_variables)]
+        impl<F, R, $($arg),*> Handler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            fn call(&self, request: &Request) -> Response {
+                $(
+                    let $arg = match $arg::from_request(request) {
+                        Ok(value) => value,
+                        Err(response) => return response,
+                    };
+                )*
+                (self)($($arg),*).into_response()
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(A);
+impl_handler!(A, B);
+impl_handler!(A, B, C);
+impl_handler!(A, B, C, D);
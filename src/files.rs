@@ -0,0 +1,439 @@
+//! Serving static files from disk.
+
+#[cfg(feature = "compression")]
+use crate::compression::{self, Encoding};
+use crate::{
+    request::{self, Method, Request},
+    response::{self, CacheControl, Response},
+};
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Files served under a route, registered with `serve_dir` or `serve_file`.
+pub struct StaticFiles {
+    route: String,
+    root: PathBuf,
+    single_file: bool,
+    cache_control: CacheControl,
+    autoindex: bool,
+    /// Set by `serve_spa`, paths to no file get the `index.html` of the root.
+    spa: bool,
+}
+
+impl StaticFiles {
+    pub(crate) fn dir(route: &str, dir: &Path) -> StaticFiles {
+        StaticFiles {
+            route: route.trim_end_matches('/').to_string(),
+            root: dir.to_path_buf(),
+            single_file: false,
+            cache_control: CacheControl::new()
+                .public()
+                .max_age(Duration::from_secs(3600)),
+            autoindex: false,
+            spa: false,
+        }
+    }
+
+    pub(crate) fn spa(route: &str, dir: &Path) -> StaticFiles {
+        StaticFiles {
+            spa: true,
+            ..StaticFiles::dir(route, dir)
+        }
+    }
+
+    pub(crate) fn file(route: &str, file: &Path) -> StaticFiles {
+        StaticFiles {
+            single_file: true,
+            ..StaticFiles::dir(route, file)
+        }
+    }
+
+    /// Replaces the `Cache-Control` sent with the files, one hour for public caches by default.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::{response::CacheControl, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server
+    ///     .serve_dir("/assets", "./dist/assets")
+    ///     .cache_control(CacheControl::new().public().max_age(Duration::from_secs(31536000)).immutable());
+    /// ```
+    pub fn cache_control(&mut self, cache_control: CacheControl) -> &mut StaticFiles {
+        self.cache_control = cache_control;
+        self
+    }
+
+    /// Lists the files of directories without an `index.html`, off by default.
+    ///
+    /// Directories are answered with their `index.html` when they have one, otherwise with an
+    /// HTML page linking their files when this is on and a `403 Forbidden` when it's off.
+    /// Hidden files, whose name starts with a dot, are never listed.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.serve_dir("/downloads", "./downloads").autoindex(true);
+    /// ```
+    pub fn autoindex(&mut self, enabled: bool) -> &mut StaticFiles {
+        self.autoindex = enabled;
+        self
+    }
+
+    /// Returns the file on disk a normalized request path points to, if it's under the route.
+    pub(crate) fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let rest = path.strip_prefix(&self.route)?;
+        match (self.single_file, rest) {
+            (true, "") => Some(self.root.clone()),
+            (true, _) => None,
+            (false, rest) if rest.is_empty() || rest.starts_with('/') => {
+                // Normalized paths have no `..` segments, so the file is always under the root
+                Some(self.root.join(rest.trim_start_matches('/')))
+            }
+            (false, _) => None,
+        }
+    }
+
+    /// Answers a request with the content of `file`.
+    ///
+    /// Files come with an `ETag` and a `Last-Modified` date, so clients revalidating their copy
+    /// with `If-None-Match` or `If-Modified-Since` get a `304 Not Modified`. A single `bytes`
+    /// range is answered with a `206 Partial Content`, unless an `If-Range` validator shows the
+    /// file changed since the client got the first part, then the whole file is sent again.
+    ///
+    /// With the `compression` feature, a precompressed copy next to the file, like `app.js.br`
+    /// or `app.js.gz`, is sent instead when the client accepts its coding.
+    ///
+    /// A directory is answered with its `index.html` or its listing, see `autoindex`. Their
+    /// relative links only work under a path ending with a slash, so a directory asked for
+    /// without one is redirected to it first.
+    ///
+    /// For single-page apps, a path without extension to no file, or to a directory without
+    /// `index.html`, is answered with the `index.html` of the root instead.
+    pub(crate) async fn serve(&self, request: &Request, file: &Path) -> Response {
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return Response::new(405).with_header("Allow", "GET, HEAD");
+        }
+        let app = self.root.join("index.html");
+        let index;
+        let mut file = file;
+        let metadata = tokio::fs::metadata(file).await;
+        // Missing assets still get a `404`, a path of the app never has an extension
+        if self.spa && metadata.is_err() && file.extension().is_none() {
+            file = &app;
+        } else if metadata.is_ok_and(|metadata| metadata.is_dir()) {
+            let (path, query) = match request.path().split_once('?') {
+                Some((path, query)) => (path, Some(query)),
+                None => (request.path(), None),
+            };
+            if !path.ends_with('/') {
+                let location = match query {
+                    Some(query) => format!("{}/?{}", path, query),
+                    None => format!("{}/", path),
+                };
+                return Response::new(301).with_header("Location", &location);
+            }
+            index = file.join("index.html");
+            if tokio::fs::metadata(&index)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                file = &index;
+            } else if self.autoindex {
+                return listing(path, file).await;
+            } else if self.spa {
+                file = &app;
+            } else {
+                return Response::new(403);
+            }
+        }
+        #[cfg(feature = "compression")]
+        let (variant, vary) = precompressed(request, file).await;
+        #[cfg(not(feature = "compression"))]
+        let (variant, vary): (Option<(PathBuf, &str)>, bool) = (None, false);
+        let coding = variant.as_ref().map(|(_, coding)| *coding);
+
+        let sent = variant.as_ref().map_or(file, |(path, _)| path.as_path());
+        let mut opened = match tokio::fs::File::open(sent).await {
+            Ok(opened) => opened,
+            Err(_) => return Response::new(404),
+        };
+        let metadata = match opened.metadata().await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Response::new(404),
+        };
+        let length = metadata.len();
+        let modified = metadata.modified().ok();
+        let validators = Validators::new(length, modified, coding);
+
+        let headers = |response: Response| {
+            let mut response = response
+                .with_header("ETag", &validators.etag)
+                .with_header("Accept-Ranges", "bytes");
+            if let Some(date) = &validators.last_modified {
+                response = response.with_header("Last-Modified", date);
+            }
+            if let Some(coding) = coding {
+                response = response.with_header("Content-Encoding", coding);
+            }
+            if vary {
+                response = response.with_header("Vary", "Accept-Encoding");
+            }
+            response.cache_control(self.cache_control.clone())
+        };
+        if validators.not_modified(request) {
+            return headers(Response::new(304));
+        }
+
+        let range = match request.header("Range") {
+            Some(range) if *request.method() == Method::Get && validators.if_range(request) => {
+                parse_range(range, length)
+            }
+            _ => None,
+        };
+        match range {
+            None => headers(Response::from_reader(opened, length, mime_type(file))),
+            Some(Err(())) => {
+                Response::new(416).with_header("Content-Range", &format!("bytes */{}", length))
+            }
+            Some(Ok((start, end))) => {
+                if opened.seek(SeekFrom::Start(start)).await.is_err() {
+                    return Response::new(500);
+                }
+                let part = end - start + 1;
+                let content_range = format!("bytes {}-{}/{}", start, end, length);
+                headers(Response::from_reader(
+                    opened.take(part),
+                    part,
+                    mime_type(file),
+                ))
+                .with_header("Content-Range", &content_range)
+                .with_status(206)
+            }
+        }
+    }
+}
+
+/// Values identifying a version of a file, to tell whether the copy of a client is current.
+struct Validators {
+    etag: String,
+    /// Modification date with the precision of an HTTP date, in seconds since the epoch.
+    modified: Option<u64>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Validators of a file, or of its copy compressed with `coding`.
+    fn new(length: u64, modified: Option<SystemTime>, coding: Option<&str>) -> Validators {
+        let since_epoch = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        let nanos = since_epoch.map_or(0, |since_epoch| since_epoch.as_nanos());
+        let etag = match coding {
+            Some(coding) => format!("\"{:x}-{:x}-{}\"", length, nanos, coding),
+            None => format!("\"{:x}-{:x}\"", length, nanos),
+        };
+        Validators {
+            etag,
+            modified: since_epoch.map(|since_epoch| since_epoch.as_secs()),
+            last_modified: modified.map(httpdate::fmt_http_date),
+        }
+    }
+
+    /// Returns whether the client already has this version, so a `304` can be answered.
+    ///
+    /// `If-Modified-Since` is only looked at when there's no `If-None-Match`.
+    fn not_modified(&self, request: &Request) -> bool {
+        if let Some(tags) = request.header("If-None-Match") {
+            return tags.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag
+            });
+        }
+        match (
+            request.header("If-Modified-Since").and_then(parse_date),
+            self.modified,
+        ) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Returns whether the `Range` of a request can be answered, false when its `If-Range`
+    /// doesn't match this version.
+    ///
+    /// Entity tags are compared strongly, so weak ones never match, and dates must be the
+    /// exact `Last-Modified` date.
+    fn if_range(&self, request: &Request) -> bool {
+        let validator = match request.header("If-Range") {
+            Some(validator) => validator.trim(),
+            None => return true,
+        };
+        if validator.starts_with('"') || validator.starts_with("W/") {
+            return validator == self.etag;
+        }
+        match (parse_date(validator), self.modified) {
+            (Some(date), Some(modified)) => date == modified,
+            _ => false,
+        }
+    }
+}
+
+/// Looks for a precompressed copy of `file` in a coding the client accepts.
+///
+/// Returns the copy with its `Content-Encoding`, along with whether `file` has any copy, as
+/// then its responses vary with `Accept-Encoding`.
+#[cfg(feature = "compression")]
+async fn precompressed(request: &Request, file: &Path) -> (Option<(PathBuf, &'static str)>, bool) {
+    let mut copies: Vec<(PathBuf, Encoding)> = vec![];
+    for encoding in compression::PREFERRED {
+        let mut path = file.as_os_str().to_owned();
+        path.push(".");
+        path.push(encoding.extension());
+        let path = PathBuf::from(path);
+        if tokio::fs::metadata(&path)
+            .await
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            copies.push((path, encoding));
+        }
+    }
+    let chosen = compression::accepted(request.header("Accept-Encoding"))
+        .into_iter()
+        .find_map(|accepted| copies.iter().find(|(_, encoding)| *encoding == accepted))
+        .map(|(path, encoding)| (path.clone(), encoding.as_str()));
+    (chosen, !copies.is_empty())
+}
+
+/// Answers with a page linking the entries of `dir`, requested at `path`.
+async fn listing(path: &str, dir: &Path) -> Response {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(_) => return Response::new(404),
+    };
+    let mut names = vec![];
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let is_dir = entry.file_type().await.is_ok_and(|kind| kind.is_dir());
+        names.push((name, is_dir));
+    }
+    // Directories first, then files, each alphabetically
+    names.sort_by(|(a, a_dir), (b, b_dir)| b_dir.cmp(a_dir).then_with(|| a.cmp(b)));
+
+    let title = response::escape_html(&request::percent_decode(path));
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    if path != "/" {
+        page.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in names {
+        let slash = if is_dir { "/" } else { "" };
+        page.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            encode_segment(&name),
+            slash,
+            response::escape_html(&name),
+            slash
+        ));
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    Response::html(page)
+}
+
+/// Percent-encodes a file name to be used as a relative link.
+pub(crate) fn encode_segment(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Parses an HTTP date into seconds since the epoch.
+pub(crate) fn parse_date(date: &str) -> Option<u64> {
+    let date = httpdate::parse_http_date(date.trim()).ok()?;
+    Some(date.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Returns the first and last byte of the range asked by a `Range` header.
+///
+/// `None` means the header is ignored and the whole file is sent: it isn't a `bytes` range or
+/// asks for several ranges. `Err` means no byte of the range is in the file.
+fn parse_range(range: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = match (start.is_empty(), end.is_empty()) {
+        (true, true) => return None,
+        // `-n` asks for the last n bytes
+        (true, false) => {
+            let suffix: u64 = end.parse().ok()?;
+            match suffix.min(length) {
+                0 => Err(()),
+                suffix => Ok((length - suffix, length - 1)),
+            }
+        }
+        (false, _) => {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = match end.is_empty() {
+                true => u64::MAX,
+                false => end.parse().ok()?,
+            };
+            match start < length {
+                true if end >= start => Ok((start, end.min(length - 1))),
+                true => return None,
+                false => Err(()),
+            }
+        }
+    };
+    Some(range)
+}
+
+/// Guesses the `Content-Type` of a file from its extension.
+pub(crate) fn mime_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
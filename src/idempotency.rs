@@ -0,0 +1,146 @@
+//! Replay of the responses to requests sent with an `Idempotency-Key`, enabled with
+//! `Route::idempotent`.
+
+use crate::{clock::Clock, request::Request, response::Response};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Most keys remembered by a route, the oldest are forgotten first past it.
+const MAX_KEYS: usize = 1024;
+
+/// Responses of a route stored by idempotency key and path, kept for `ttl`.
+pub(crate) struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<(String, String), Entry>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Entry {
+    stored: Instant,
+    /// Hash of the method and body, a key reused for another request is an error.
+    fingerprint: u64,
+    /// `None` while the first request with the key is being answered.
+    response: Option<Response>,
+}
+
+/// What to do with a request sent with an idempotency key.
+pub(crate) enum Attempt {
+    /// First time the key is seen, the handler runs and `finish` stores its response.
+    Fresh(Pending),
+    Replay(Response),
+    /// The first request with the key is still being answered.
+    InProgress,
+    /// The key was used for a request with another method or body.
+    Mismatch,
+}
+
+/// Key reserved for a request being answered, released if it's dropped before `finish`.
+pub(crate) struct Pending {
+    store: Arc<IdempotencyStore>,
+    key: (String, String),
+    finished: bool,
+}
+
+impl IdempotencyStore {
+    pub(crate) fn new(ttl: Duration, clock: Arc<dyn Clock>) -> IdempotencyStore {
+        IdempotencyStore {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Looks up the key of `request`, `None` if it wasn't sent with one.
+    pub(crate) fn begin(self: &Arc<Self>, request: &Request) -> Option<Attempt> {
+        let key = request.header("Idempotency-Key")?.trim();
+        if key.is_empty() {
+            return None;
+        }
+        let key = (key.to_string(), request.path().to_string());
+        let fingerprint = fingerprint(request);
+
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, entry| self.clock.since(entry.stored) < ttl);
+        if let Some(entry) = entries.get(&key) {
+            if entry.fingerprint != fingerprint {
+                return Some(Attempt::Mismatch);
+            }
+            return Some(
+                match entry.response.as_ref().and_then(Response::try_clone) {
+                    Some(response) => {
+                        Attempt::Replay(response.with_header("Idempotent-Replayed", "true"))
+                    }
+                    None => Attempt::InProgress,
+                },
+            );
+        }
+        if entries.len() >= MAX_KEYS {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key.clone(),
+            Entry {
+                stored: self.clock.now(),
+                fingerprint,
+                response: None,
+            },
+        );
+        Some(Attempt::Fresh(Pending {
+            store: Arc::clone(self),
+            key,
+            finished: false,
+        }))
+    }
+}
+
+impl Pending {
+    /// Stores `response` to be replayed for the key.
+    ///
+    /// Server errors and responses streaming their body aren't stored, the key is released so
+    /// a retry runs the handler again.
+    pub(crate) fn finish(mut self, response: &Response) {
+        let copy = response
+            .try_clone()
+            .filter(|response| response.status() < 500);
+        let mut entries = self.store.entries.lock().unwrap();
+        match copy {
+            Some(copy) => {
+                if let Some(entry) = entries.get_mut(&self.key) {
+                    entry.stored = self.store.clock.now();
+                    entry.response = Some(copy);
+                }
+            }
+            None => {
+                entries.remove(&self.key);
+            }
+        }
+        self.finished = true;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        // The handler was dropped, by a timeout or a closed connection, it may run again
+        if !self.finished {
+            self.store.entries.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+fn fingerprint(request: &Request) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.method().as_str().hash(&mut hasher);
+    request.body().hash(&mut hasher);
+    hasher.finish()
+}
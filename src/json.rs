@@ -0,0 +1,183 @@
+//! JSON request bodies, available with the `serde` feature.
+
+use crate::{
+    request::Request,
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Error answered as a JSON body like `{"status":400,"message":"invalid id"}`.
+///
+/// # Example
+/// ```
+/// use mini_rest::{json::JsonError, request::Request, response::Response};
+/// fn user(req: &Request) -> Result<Response, JsonError> {
+///     let id: u32 = req
+///         .query_param("id")
+///         .and_then(|id| id.parse().ok())
+///         .ok_or_else(|| JsonError::new(400, "invalid id").detail("id", "must be a number"))?;
+///     Ok(Response::html(format!("user {}", id)))
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonError {
+    status: u16,
+    message: String,
+    details: Map<String, Value>,
+}
+
+impl JsonError {
+    pub fn new(status: u16, message: impl Into<String>) -> JsonError {
+        JsonError {
+            status,
+            message: message.into(),
+            details: Map::new(),
+        }
+    }
+
+    /// Adds an entry to the `details` object of the body, which is left out while empty.
+    pub fn detail(mut self, key: &str, value: impl Serialize) -> JsonError {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.details.insert(key.to_string(), value);
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl IntoResponse for JsonError {
+    fn into_response(self) -> Response {
+        let mut body = json!({ "status": self.status, "message": self.message });
+        if !self.details.is_empty() {
+            body["details"] = Value::Object(self.details);
+        }
+        json_response(self.status, body)
+    }
+}
+
+impl From<JsonError> for Response {
+    fn from(error: JsonError) -> Response {
+        error.into_response()
+    }
+}
+
+/// Problem found in one field of a deserialized body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+/// Constraints checked on a body once it's deserialized.
+///
+/// # Example
+/// ```
+/// use mini_rest::json::{FieldError, Validate, Validator};
+/// struct User {
+///     name: String,
+///     email: Option<String>,
+/// }
+///
+/// impl Validate for User {
+///     fn validate(&self) -> Result<(), Vec<FieldError>> {
+///         Validator::new()
+///             .length("name", &self.name, 1, 50)
+///             .required("email", &self.email)
+///             .finish()
+///     }
+/// }
+/// ```
+pub trait Validate {
+    fn validate(&self) -> Result<(), Vec<FieldError>>;
+}
+
+/// Collects the field errors of a body.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator::default()
+    }
+
+    /// Fails `field` with `message` unless `valid`.
+    pub fn check(mut self, field: &str, valid: bool, message: &str) -> Validator {
+        if !valid {
+            self.errors.push(FieldError {
+                field: field.to_string(),
+                message: message.to_string(),
+            });
+        }
+        self
+    }
+
+    /// Fails `field` if it wasn't sent.
+    pub fn required<T>(self, field: &str, value: &Option<T>) -> Validator {
+        self.check(field, value.is_some(), "is required")
+    }
+
+    /// Fails `field` if its amount of characters isn't between `min` and `max`.
+    pub fn length(self, field: &str, value: &str, min: usize, max: usize) -> Validator {
+        let length = value.chars().count();
+        let message = format!("must have between {} and {} characters", min, max);
+        self.check(field, (min..=max).contains(&length), &message)
+    }
+
+    /// Returns every error found, if any.
+    pub fn finish(self) -> Result<(), Vec<FieldError>> {
+        match self.errors.is_empty() {
+            true => Ok(()),
+            false => Err(self.errors),
+        }
+    }
+}
+
+impl Request {
+    /// Deserializes the body as JSON.
+    ///
+    /// A body that isn't JSON gets a `400 Bad Request`, a JSON body that doesn't fit `T`
+    /// (missing fields, wrong types) gets a `422 Unprocessable Content`.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Response> {
+        serde_json::from_slice(self.body()).map_err(|e| {
+            let status = match e.classify() {
+                serde_json::error::Category::Data => 422,
+                _ => 400,
+            };
+            JsonError::new(status, e.to_string()).into_response()
+        })
+    }
+
+    /// Deserializes the body as JSON and checks it with its `Validate` implementation.
+    ///
+    /// When validation fails the handler can return the error as is, a `JsonError` with
+    /// status `422` listing every field error in its details:
+    /// `{"status":422,"message":"validation failed","details":{"errors":[{"field":"name","message":"..."}]}}`
+    pub fn validated_json<T: DeserializeOwned + Validate>(&self) -> Result<T, Response> {
+        let body: T = self.json()?;
+        body.validate().map_err(|errors| {
+            let errors: Vec<_> = errors
+                .iter()
+                .map(|e| json!({ "field": e.field, "message": e.message }))
+                .collect();
+            JsonError::new(422, "validation failed")
+                .detail("errors", errors)
+                .into_response()
+        })?;
+        Ok(body)
+    }
+}
+
+fn json_response(status: u16, body: Value) -> Response {
+    Response::new(status)
+        .with_header("Content-Type", "application/json")
+        .with_body(body.to_string())
+}
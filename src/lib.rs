@@ -3,25 +3,3947 @@
 //!
 //! ## Example of use
 //! ```rust,no_run
-//! use mini_rest::server::{self, ServerActions};
+//! use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
 //! #[tokio::main]
 //! async fn main() {
 //!     let mut server = server::new("127.0.0.1:8080".to_string());
+//!     server.add_route(Method::Get, "/", |_req| Response::html("<h1>Hello, world!</h1>"));
 //!     server.start().await;
 //! }
 //! ```
 
+pub mod auth;
+mod cache;
+pub mod client;
+mod clock;
+#[cfg(feature = "compression")]
+pub mod compression;
+mod connections;
+mod dev;
+mod docs;
+pub mod error;
+pub mod extract;
+pub mod files;
+mod idempotency;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod logging;
+mod macros;
+pub mod metrics;
+pub mod middleware;
+mod pool;
+mod proxy;
+pub mod request;
+pub mod response;
+pub mod router;
 pub mod server;
+#[cfg(test)]
+mod testing;
+pub mod timing;
+#[cfg(feature = "tls")]
+pub mod tls;
 
-// TODO: DOCUMENT THIS
+/// Starts a server, answers a request through a real socket and shuts it down.
 #[tokio::test]
 async fn test_server() {
+    use request::Method;
+    use response::Response;
     use server::{self, ServerActions};
-    // Create a new server
-    let mut server = server::new("127.0.0.1:8080".to_string());
-    server.add_route("/", || {
-        // println!("New handler"); // NOTE: Here should be the implementation for req or res
+    // Create a new server listening on a free port
+    let bound = server::ephemeral(|server| {
+        server.add_route(Method::Get, "/", |_req| {
+            Response::html("<h1>Hello, world!</h1>")
+        });
+    })
+    .unwrap();
+    let response = client::get(&bound.url("/")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(response.body(), b"<h1>Hello, world!</h1>");
+    // Stop listening
+    bound.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_custom_reason_phrase() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| {
+        Response::with_reason(299, "All Good")
+    });
+    let answer = testing::send(server, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert!(answer.starts_with("HTTP/1.1 299 All Good\r\n"));
+}
+
+#[tokio::test]
+async fn test_path_rewrite() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/users", |_req| Response::html("users"));
+    server.path_rewrite(|path| path.trim_start_matches("/v1").to_lowercase());
+    let answer = testing::send(
+        server,
+        "GET /v1//%55sers/./?page=1 HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(answer.ends_with("users"));
+}
+
+#[tokio::test]
+async fn test_try_start_bind_error() {
+    use server::{self, ServerActions};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut server = server::new(taken.local_addr().unwrap().to_string());
+    // Returned only, the callback is left to accept errors
+    let reported = Arc::new(AtomicBool::new(false));
+    let flag = reported.clone();
+    server.on_error(move |_| {
+        flag.store(true, Ordering::SeqCst);
+        error::ErrorAction::Continue
+    });
+    let result = server.try_start().await;
+    assert!(matches!(result, Err(error::ServerError::Bind { .. })));
+    assert!(!reported.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_too_many_headers() {
+    let server = server::new("127.0.0.1:0".to_string());
+    let headers: String = (0..200)
+        .map(|i| format!("X-Header-{}: {}\r\n", i, i))
+        .collect();
+    let raw = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+    let answer = testing::send(server, &raw).await;
+    assert!(answer.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+}
+
+#[tokio::test]
+async fn test_chunked_trailers() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| {
+        let (sender, chunks) = tokio::sync::mpsc::channel(2);
+        tokio::spawn(async move {
+            sender.send(b"hello ".to_vec()).await.unwrap();
+            sender.send(b"world".to_vec()).await.unwrap();
+        });
+        Response::chunked(chunks, "text/plain").with_trailers(&["X-Checksum"], || {
+            vec![("X-Checksum".to_string(), "abc".to_string())]
+        })
+    });
+    let answer = testing::send(server, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert!(answer.contains("Transfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n"));
+    assert!(answer.ends_with("\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\nX-Checksum: abc\r\n\r\n"));
+}
+
+#[tokio::test]
+async fn test_keep_alive_reuses_connection() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/first", |_req| Response::html("first"));
+    server.add_route(Method::Post, "/second", |_req| Response::html("second"));
+    let mut connection = testing::connect(server).await;
+
+    let first = connection.request("GET /first HTTP/1.1\r\n\r\n").await;
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(first.ends_with("\r\n\r\nfirst"));
+    let second = connection
+        .request("POST /second HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody")
+        .await;
+    assert!(second.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(second.ends_with("\r\n\r\nsecond"));
+    // Nothing else was left in the connection by the previous requests
+    let last = connection
+        .request("GET /first HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await;
+    assert!(last.contains("Connection: close\r\n"));
+    assert_eq!(connection.read_to_end().await, "");
+}
+
+#[test]
+fn test_content_negotiation() {
+    let req = testing::request(
+        "GET / HTTP/1.1\r\nAccept: text/html;q=0.5, application/*;q=0.8, application/xml;q=0\r\n\r\n",
+    );
+    assert!(req.accepts("application/json"));
+    assert!(!req.accepts("application/xml"));
+    assert!(!req.accepts("image/png"));
+    assert_eq!(
+        req.preferred(&["text/html", "application/json"]),
+        Some("application/json")
+    );
+    assert_eq!(req.preferred(&["application/xml", "image/png"]), None);
+
+    let req = testing::request("GET / HTTP/1.1\r\nAccept: nonsense;q=x\r\n\r\n");
+    assert!(req.accepts("image/png"));
+    assert_eq!(
+        req.preferred(&["text/html", "application/json"]),
+        Some("text/html")
+    );
+}
+
+#[tokio::test]
+async fn test_eof_in_the_middle_of_body() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| Response::html("unreachable"));
+    let raw = format!(
+        "POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n{}",
+        "a".repeat(400)
+    );
+    let answer = testing::send_and_close(server, &raw).await;
+    assert!(answer.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(!answer.contains("unreachable"));
+}
+
+#[tokio::test]
+async fn test_trace_and_connect_rejected() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| Response::html("root"));
+    // Never answered, nor listed in the `Allow` header
+    server.add_route(Method::Trace, "/", |_req| Response::html("echo"));
+    let mut connection = testing::connect(server).await;
+    let trace = connection.request("TRACE / HTTP/1.1\r\n\r\n").await;
+    assert!(trace.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(trace.contains("Allow: GET, HEAD, OPTIONS\r\n"), "{}", trace);
+    let trace = connection.request("TRACE /missing HTTP/1.1\r\n\r\n").await;
+    assert!(trace.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(trace.contains("Allow: \r\n"), "{}", trace);
+    let connect = connection
+        .request("CONNECT example.com:443 HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(connect.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+}
+
+#[tokio::test]
+async fn test_custom_accept_loop() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| Response::html("root"));
+    let mut incoming = server.incoming().await.unwrap();
+    let address = incoming.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = incoming.accept().await {
+            tokio::spawn(incoming.serve(socket));
+        }
+    });
+
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    client
+        .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut answer = String::new();
+    client.read_to_string(&mut answer).await.unwrap();
+    assert!(answer.ends_with("\r\n\r\nroot"));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_validated_json() {
+    use json::{FieldError, Validate, Validator};
+    #[derive(serde::Deserialize)]
+    struct User {
+        name: String,
+        email: Option<String>,
+    }
+    impl Validate for User {
+        fn validate(&self) -> Result<(), Vec<FieldError>> {
+            Validator::new()
+                .length("name", &self.name, 1, 5)
+                .required("email", &self.email)
+                .finish()
+        }
+    }
+
+    let post = |body: &str| {
+        testing::request(&format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+    };
+    let valid = post(r#"{"name":"ana","email":"a@b.c"}"#);
+    assert_eq!(valid.validated_json::<User>().unwrap().name, "ana");
+
+    let invalid = post(r#"{"name":"anastasia"}"#);
+    let response = invalid.validated_json::<User>().err().unwrap();
+    assert_eq!(response.status(), 422);
+    // Same shape as any other `JsonError`, the field errors are details
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(body["status"], 422);
+    assert_eq!(body["message"], "validation failed");
+    assert_eq!(
+        body["details"]["errors"],
+        serde_json::json!([
+            {"field": "name", "message": "must have between 1 and 5 characters"},
+            {"field": "email", "message": "is required"}
+        ])
+    );
+    assert!(body.get("errors").is_none());
+
+    let malformed = post("{");
+    assert_eq!(
+        malformed.validated_json::<User>().err().unwrap().status(),
+        400
+    );
+}
+
+#[tokio::test]
+async fn test_serve_dir_cache_control() {
+    use server::{self, ServerActions};
+    let dir = testing::temp_dir("serve_dir_cache_control");
+    std::fs::write(dir.join("app.js"), "console.log(1)").unwrap();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.serve_dir("/static", &dir);
+    let mut connection = testing::connect(server).await;
+
+    let asset = connection
+        .request("GET /static/app.js HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(asset.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(asset.contains("Content-Type: text/javascript\r\n"));
+    assert!(asset.contains("Cache-Control: public, max-age=3600\r\n"));
+    assert!(asset.ends_with("\r\n\r\nconsole.log(1)"));
+    let missing = connection
+        .request("GET /static/../app.js HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[tokio::test]
+async fn test_shutdown_token_stops_streams() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    let token = server.shutdown_token();
+    let handle = server.shutdown_handle();
+    server.add_route(Method::Get, "/stream", move |_req| {
+        let (sender, chunks) = tokio::sync::mpsc::channel(1);
+        let mut token = token.clone();
+        tokio::spawn(async move {
+            let _ = sender.send(b"started".to_vec()).await;
+            token.cancelled().await;
+        });
+        Response::chunked(chunks, "text/plain")
+    });
+    let mut connection = testing::connect(server).await;
+    let stream = tokio::spawn(async move {
+        connection.request("GET /stream HTTP/1.1\r\n\r\n").await;
+        connection.read_to_end().await
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    handle.shutdown();
+    let rest = tokio::time::timeout(std::time::Duration::from_secs(5), stream)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rest.ends_with("0\r\n\r\n"));
+}
+
+#[test]
+fn test_client_ip() {
+    let mut req =
+        testing::request("GET / HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7, 10.0.0.1\r\n\r\n");
+    req.remote_addr = Some("10.0.0.1:5000".parse().unwrap());
+    assert_eq!(req.client_ip(), Some("10.0.0.1".parse().unwrap()));
+    req.trust_proxy = true;
+    assert_eq!(req.client_ip(), Some("203.0.113.7".parse().unwrap()));
+
+    let mut req = testing::request("GET / HTTP/1.1\r\nX-Forwarded-For: not-an-ip\r\n\r\n");
+    req.remote_addr = Some("10.0.0.1:5000".parse().unwrap());
+    req.trust_proxy = true;
+    assert_eq!(req.client_ip(), Some("10.0.0.1".parse().unwrap()));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_typed_query() {
+    #[derive(serde::Deserialize)]
+    struct Pagination {
+        page: u32,
+        limit: u32,
+        search: Option<String>,
+    }
+    let req = testing::request("GET /users?page=2&limit=10&search=ana+maria HTTP/1.1\r\n\r\n");
+    let pagination = req.query::<Pagination>().unwrap();
+    assert_eq!((pagination.page, pagination.limit), (2, 10));
+    assert_eq!(pagination.search.as_deref(), Some("ana maria"));
+    assert_eq!(req.query_param("search").as_deref(), Some("ana maria"));
+
+    let req = testing::request("GET /users?page=2 HTTP/1.1\r\n\r\n");
+    assert_eq!(req.query::<Pagination>().err().unwrap().status(), 400);
+}
+
+#[tokio::test]
+async fn test_allow_header() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Delete, "/users", |_req| Response::new(204));
+    server.add_route(Method::Post, "/users", |_req| Response::new(201));
+    server.add_route(Method::Get, "/users", |_req| Response::html("users"));
+    server.add_route(Method::Post, "/users", |_req| Response::new(201));
+    let mut connection = testing::connect(server).await;
+
+    let put = connection.request("PUT /users HTTP/1.1\r\n\r\n").await;
+    assert!(put.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(put.contains("Allow: GET, HEAD, POST, DELETE, OPTIONS\r\n"));
+    let options = connection.request("OPTIONS /users HTTP/1.1\r\n\r\n").await;
+    assert!(options.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(options.contains("Allow: GET, HEAD, POST, DELETE, OPTIONS\r\n"));
+    let head = connection.request("HEAD /users HTTP/1.1\r\n\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(head.ends_with("Content-Length: 5\r\n\r\n"));
+}
+
+#[tokio::test]
+async fn test_metrics_bytes() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/stream", |_req| {
+        let (sender, chunks) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move {
+            for chunk in ["hello", ", ", "world"] {
+                sender.send(chunk.as_bytes().to_vec()).await.unwrap();
+            }
+        });
+        Response::chunked(chunks, "text/plain")
+    });
+    let metrics = server.metrics();
+    let raw = "POST /stream HTTP/1.1\r\nContent-Length: 4\r\n\r\nping\
+               GET /stream HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let response = testing::send(server, raw).await;
+    assert!(response.contains("5\r\nhello\r\n"));
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.requests, 2);
+    assert_eq!(snapshot.active_connections, 0);
+    assert_eq!(snapshot.bytes_received, raw.len() as u64);
+    assert_eq!(snapshot.bytes_sent, response.len() as u64);
+}
+
+#[tokio::test]
+async fn test_from_listener() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut server = server::from_listener(listener).unwrap();
+    server.add_route(Method::Get, "/", |_req| Response::html("inherited"));
+    let handle = server.shutdown_handle();
+    let running = tokio::spawn(server.try_start());
+
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("inherited"));
+
+    handle.shutdown();
+    running.await.unwrap().unwrap();
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_json_error() {
+    use json::JsonError;
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/users", |req| {
+        let id: u32 = req
+            .query_param("id")
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| JsonError::new(400, "invalid id").detail("id", "must be a number"))?;
+        Ok::<_, JsonError>(Response::html(format!("user {}", id)))
+    });
+    let mut connection = testing::connect(server).await;
+
+    let found = connection.request("GET /users?id=7 HTTP/1.1\r\n\r\n").await;
+    assert!(found.ends_with("user 7"));
+    let invalid = connection.request("GET /users?id=x HTTP/1.1\r\n\r\n").await;
+    assert!(invalid.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(invalid.contains("Content-Type: application/json\r\n"));
+    assert!(invalid
+        .ends_with(r#"{"details":{"id":"must be a number"},"message":"invalid id","status":400}"#));
+}
+
+#[tokio::test]
+async fn test_read_buffer_size() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let body = "x".repeat(100_000);
+    let raw = format!(
+        "POST /upload HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    for size in [1, 1024, 1 << 20] {
+        let mut server = server::new("127.0.0.1:0".to_string());
+        server.read_buffer_size(size);
+        server.add_route(Method::Post, "/upload", |req| {
+            Response::html(req.body().len().to_string())
+        });
+        let response = testing::send(server, &raw).await;
+        assert!(
+            response.ends_with("\r\n\r\n100000"),
+            "buffer of {} bytes",
+            size
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_redirect_to_https() {
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.middleware(middleware::redirect_to_https(8443));
+    let mut connection = testing::connect(server).await;
+
+    let response = connection
+        .request("GET /users?page=2 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+    assert!(response.contains("Location: https://example.com:8443/users?page=2\r\n"));
+    let response = connection
+        .request("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n")
+        .await;
+    assert!(response.contains("Location: https://[::1]:8443/\r\n"));
+    let response = connection.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+}
+
+#[tokio::test]
+async fn test_route_params() {
+    use request::Method;
+    use response::Response;
+    use router::RouteInfo;
+    use server::{self, ServerActions, ServerInfo};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/files/*rest", |req| {
+        Response::html(format!("file {}", req.param("rest").unwrap()))
+    });
+    server.add_route(Method::Get, "/users/:id", |req| {
+        Response::html(format!("user {}", req.param("id").unwrap()))
+    });
+    server.add_route(Method::Get, "/users/me", |_req| Response::html("me"));
+    let info = |method, pattern: &str, has_params, has_wildcard| RouteInfo {
+        method,
+        pattern: pattern.to_string(),
+        has_params,
+        has_wildcard,
+        summary: None,
+    };
+    assert_eq!(
+        server.routes(),
+        vec![
+            info(Method::Get, "/users/me", false, false),
+            info(Method::Get, "/users/:id", true, false),
+            info(Method::Get, "/files/*rest", false, true),
+        ]
+    );
+    let mut connection = testing::connect(server).await;
+
+    let me = connection.request("GET /users/me HTTP/1.1\r\n\r\n").await;
+    assert!(me.ends_with("\r\n\r\nme"));
+    let user = connection.request("GET /users/42 HTTP/1.1\r\n\r\n").await;
+    assert!(user.ends_with("user 42"));
+    let file = connection
+        .request("GET /files/a/b.txt HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(file.ends_with("file a/b.txt"));
+    let nested = connection
+        .request("GET /users/42/posts HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(nested.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[tokio::test]
+async fn test_if_range() {
+    use server::{self, ServerActions};
+    let dir = testing::temp_dir("if_range");
+    std::fs::write(dir.join("data.txt"), "0123456789").unwrap();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.serve_file("/data.txt", dir.join("data.txt"));
+    let mut connection = testing::connect(server).await;
+
+    let full = connection.request("GET /data.txt HTTP/1.1\r\n\r\n").await;
+    let etag = full
+        .lines()
+        .find_map(|line| line.strip_prefix("ETag: "))
+        .unwrap()
+        .to_string();
+    let range = |validator: &str| {
+        format!(
+            "GET /data.txt HTTP/1.1\r\nRange: bytes=2-4\r\nIf-Range: {}\r\n\r\n",
+            validator
+        )
+    };
+    let partial = connection.request(&range(&etag)).await;
+    assert!(partial.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert!(partial.contains("Content-Range: bytes 2-4/10\r\n"));
+    assert!(partial.ends_with("\r\n\r\n234"));
+    let changed = connection.request(&range("\"stale\"")).await;
+    assert!(changed.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(changed.ends_with("\r\n\r\n0123456789"));
+    let old_date = connection
+        .request(&range("Sat, 01 Jan 2000 00:00:00 GMT"))
+        .await;
+    assert!(old_date.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    let cached = format!("GET /data.txt HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag);
+    let cached = connection.request(&cached).await;
+    assert!(cached.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+    assert!(!cached.contains("Content-Length"));
+    let outside = connection
+        .request("GET /data.txt HTTP/1.1\r\nRange: bytes=20-\r\n\r\n")
+        .await;
+    assert!(outside.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+    assert!(outside.contains("Content-Range: bytes */10\r\n"));
+}
+
+#[test]
+fn test_routes_macro() {
+    use request::Request;
+    use response::Response;
+    use router::Router;
+    fn create_user(req: &Request) -> Response {
+        Response::new(201).with_body(req.body().to_vec())
+    }
+    let mut router = Router::new();
+    routes! { router =>
+        GET "/" => |_req| Response::html("index"),
+        POST "/users" => create_user,
+        GET "/users/:id" => |req| Response::html(format!("user {}", req.param("id").unwrap())),
+    }
+    let patterns: Vec<String> = router
+        .routes()
+        .into_iter()
+        .map(|route| route.pattern)
+        .collect();
+    assert_eq!(patterns, ["/", "/users", "/users/:id"]);
+}
+
+#[tokio::test]
+async fn test_handler_timeout() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.handler_timeout(Duration::from_millis(50));
+    server.add_async_route(Method::Get, "/slow", |_req| async {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        Response::html("too late")
+    });
+    server.add_async_route(Method::Get, "/users/:id", |req| {
+        let id = req.param("id").unwrap_or_default().to_string();
+        async move { Response::html(format!("user {}", id)) }
+    });
+    let mut connection = testing::connect(server).await;
+
+    let slow = connection.request("GET /slow HTTP/1.1\r\n\r\n").await;
+    assert!(slow.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    let fast = connection.request("GET /users/7 HTTP/1.1\r\n\r\n").await;
+    assert!(fast.ends_with("user 7"));
+}
+
+#[tokio::test]
+async fn test_early_hints() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_async_route(Method::Get, "/", |req| {
+        let writer = req.response_writer();
+        async move {
+            assert!(!writer.send_informational(200, &[]));
+            writer.send_informational(103, &[("Link", "</style.css>; rel=preload; as=style")]);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            Response::html("page")
+        }
+    });
+    let raw = "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.0\r\n\r\n";
+    let response = testing::send(server, raw).await;
+    let (hints, rest) = response.split_once("\r\n\r\n").unwrap();
+    assert_eq!(
+        hints,
+        "HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style"
+    );
+    let (first, second) = rest.split_once("page").unwrap();
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(second.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(second.ends_with("page"));
+
+    let upstream = b"HTTP/1.1 103 Early Hints\r\nLink: </a.js>\r\n\r\nHTTP/1.1 200 OK\r\n\r\npage";
+    let parsed = response::parse_response(upstream).unwrap();
+    assert_eq!((parsed.status(), parsed.body()), (200, &b"page"[..]));
+}
+
+#[test]
+fn test_malformed_header_lines() {
+    use request::{Limits, ParseError};
+    let parse = |raw: &str| {
+        let mut buffer = raw.as_bytes().to_vec();
+        request::take_request(&mut buffer, &Limits::default()).unwrap()
+    };
+    let rejected = [
+        "GET / HTTP/1.1\r\nX-Long: a\r\n folded\r\n\r\n",
+        "GET / HTTP/1.1\r\n Host: example.com\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost : example.com\r\n\r\n",
+        "GET / HTTP/1.1\r\n: empty\r\n\r\n",
+        "GET / HTTP/1.1\r\nX-A: a\rX-B: b\r\n\r\n",
+        "GET /\n HTTP/1.1\r\n\r\n",
+        "G(T / HTTP/1.1\r\n\r\n",
+        "POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n",
+        "POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n",
+    ];
+    for raw in rejected {
+        assert!(
+            matches!(parse(raw), Err(ParseError::Malformed)),
+            "{:?}",
+            raw
+        );
+    }
+    let request = parse("GET / HTTP/1.1\r\nX-Tab:\tvalue\t\r\n\r\n").unwrap();
+    assert_eq!(request.header("X-Tab"), Some("value"));
+}
+
+#[test]
+fn test_random_heads_never_panic() {
+    use request::Limits;
+    // Small xorshift generator, so every run feeds the same inputs
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let valid = b"POST /a?b=c HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabc";
+    let alphabet = b"\r\n :\t/?=GETPOST0123HTP.\x00\xff";
+    for _ in 0..20_000 {
+        let mut bytes = valid.to_vec();
+        for _ in 0..next() % 8 {
+            let index = next() as usize % bytes.len();
+            let byte = alphabet[next() as usize % alphabet.len()];
+            match next() % 3 {
+                0 => bytes[index] = byte,
+                1 => bytes.insert(index, byte),
+                _ => {
+                    bytes.remove(index);
+                }
+            }
+        }
+        bytes.extend_from_slice(b"\r\n\r\n");
+        let mut random: Vec<u8> = (0..next() % 64).map(|_| next() as u8).collect();
+        random.extend_from_slice(b"\r\n\r\n");
+        for mut buffer in [bytes, random] {
+            let _ = request::take_request(&mut buffer, &Limits::default());
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_compression_negotiation() {
+    use compression::{negotiate, Encoding};
+    use middleware::Middleware;
+    use response::Response;
+    use std::io::Read;
+    assert_eq!(negotiate(Some("gzip, br")), Encoding::Brotli);
+    assert_eq!(negotiate(Some("gzip;q=1.0, br;q=0.5")), Encoding::Gzip);
+    assert_eq!(negotiate(Some("*;q=0.2, br;q=0")), Encoding::Gzip);
+    assert_eq!(negotiate(Some("deflate")), Encoding::Identity);
+    assert_eq!(negotiate(None), Encoding::Identity);
+
+    let page = "<p>hello</p>".repeat(100);
+    let compress = |accept: &str, body: &str| {
+        let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", accept);
+        middleware::compression().after(&testing::request(&raw), Response::html(body))
+    };
+    let gzip = compress("br;q=0.9, gzip", &page);
+    assert_eq!(gzip.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(gzip.header("Vary"), Some("Accept-Encoding"));
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(gzip.body())
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, page);
+
+    let brotli = compress("br", &page);
+    assert_eq!(brotli.header("Content-Encoding"), Some("br"));
+    let mut decoded = String::new();
+    brotli::Decompressor::new(brotli.body(), 4096)
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, page);
+
+    assert_eq!(compress("identity", &page).body(), page.as_bytes());
+    assert_eq!(
+        compress("gzip", "<p>tiny</p>").header("Content-Encoding"),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_health_check_fails_before_shutdown() {
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut server = server::from_listener(listener).unwrap();
+    server.health_check("/ready", || true);
+    server.readiness_delay(Duration::from_millis(300));
+    let handle = server.shutdown_handle();
+    let running = tokio::spawn(server.try_start());
+    let probe = || async move {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(address).await?;
+        stream
+            .write_all(b"GET /ready HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+
+    assert!(probe().await.unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+    handle.shutdown();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let draining = probe().await.unwrap();
+    assert!(draining.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    running.await.unwrap().unwrap();
+    assert!(probe().await.is_err());
+}
+
+#[tokio::test]
+async fn test_accept_filter() {
+    use server::{self, ServerActions};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.accept_filter(|addr| !addr.ip().is_loopback());
+    let mut incoming = server.incoming().await.unwrap();
+    let address = incoming.local_addr().unwrap();
+    let accepting = tokio::spawn(async move { incoming.accept().await.map(|_| ()) });
+
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let _ = stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await;
+    let mut answer = vec![];
+    let _ = stream.read_to_end(&mut answer).await;
+    assert!(answer.is_empty());
+    assert!(!accepting.is_finished());
+    accepting.abort();
+}
+
+#[tokio::test]
+async fn test_extractors() {
+    use extract::{Path, State};
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    #[derive(Clone)]
+    struct Greeting(&'static str);
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.state(Greeting("hello"));
+    server.add_handler(
+        Method::Get,
+        "/users/:id",
+        |State(greeting): State<Greeting>, Path(id): Path<u32>| {
+            Response::html(format!("{} user {}", greeting.0, id))
+        },
+    );
+    server.add_handler(Method::Get, "/missing", |State(count): State<u64>| {
+        Response::html(count.to_string())
+    });
+    let mut connection = testing::connect(server).await;
+
+    let found = connection.request("GET /users/7 HTTP/1.1\r\n\r\n").await;
+    assert!(found.ends_with("hello user 7"));
+    let invalid = connection.request("GET /users/abc HTTP/1.1\r\n\r\n").await;
+    assert!(invalid.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(invalid.ends_with("invalid path parameter `id`"));
+    let missing = connection.request("GET /missing HTTP/1.1\r\n\r\n").await;
+    assert!(missing.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_json_extractor() {
+    use extract::{Json, Query};
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    #[derive(serde::Deserialize)]
+    struct User {
+        name: String,
+    }
+    #[derive(serde::Deserialize)]
+    struct Options {
+        greeting: String,
+    }
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_handler(
+        Method::Post,
+        "/users",
+        |Query(options): Query<Options>, Json(user): Json<User>| {
+            Response::new(201).with_body(format!("{} {}", options.greeting, user.name))
+        },
+    );
+    let mut connection = testing::connect(server).await;
+
+    let body = r#"{"name":"ana"}"#;
+    let raw = format!(
+        "POST /users?greeting=hi HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let created = connection.request(&raw).await;
+    assert!(created.starts_with("HTTP/1.1 201 Created\r\n"));
+    assert!(created.ends_with("hi ana"));
+    let raw = "POST /users?greeting=hi HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+    let invalid = connection.request(raw).await;
+    assert!(invalid.starts_with("HTTP/1.1 422 Unprocessable Content\r\n"));
+}
+
+#[tokio::test]
+async fn test_client_gone_mid_response() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let (stopped, mut stopped_rx) = tokio::sync::mpsc::channel(1);
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/stream", move |_req| {
+        let (sender, chunks) = tokio::sync::mpsc::channel(1);
+        let stopped = stopped.clone();
+        tokio::spawn(async move {
+            let chunk = vec![b'x'; 64 * 1024];
+            while sender.send(chunk.clone()).await.is_ok() {}
+            // The server dropped the response once writing to the client failed
+            stopped.send(()).await.unwrap();
+        });
+        Response::chunked(chunks, "text/plain")
+    });
+    let mut connection = testing::connect(server).await;
+    let head = connection.request("GET /stream HTTP/1.1\r\n\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+    drop(connection);
+    tokio::time::timeout(std::time::Duration::from_secs(5), stopped_rx.recv())
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_html_template() {
+    use response::Response;
+    use std::collections::HashMap;
+    let vars = HashMap::from([
+        ("name", "<script>alert('x')</script>".to_string()),
+        ("count", "3".to_string()),
+    ]);
+    let template = "<h1>{{name}}</h1><p>{{ count }} new, {{ missing }} left</p>{{ open";
+    let response = Response::html_template(template, &vars);
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(
+        String::from_utf8_lossy(response.body()),
+        "<h1>&lt;script&gt;alert(&#x27;x&#x27;)&lt;/script&gt;</h1><p>3 new,  left</p>{{ open"
+    );
+    let strict = Response::html_template_strict(template, &vars);
+    assert_eq!(strict.err().unwrap().status(), 500);
+    let strict = Response::html_template_strict("{{ count }}", &vars);
+    assert_eq!(strict.unwrap().body(), b"3");
+}
+
+#[tokio::test]
+async fn test_default_charset() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.default_charset("utf-8");
+    server.add_route(Method::Get, "/html", |_req| Response::html("hi"));
+    server.add_route(Method::Get, "/latin", |_req| {
+        Response::new(200).with_header("Content-Type", "text/plain; Charset=iso-8859-1")
+    });
+    server.add_route(Method::Get, "/json", |_req| {
+        Response::new(200).with_header("Content-Type", "application/json")
+    });
+    let mut connection = testing::connect(server).await;
+
+    let html = connection.request("GET /html HTTP/1.1\r\n\r\n").await;
+    assert!(html.contains("Content-Type: text/html; charset=utf-8\r\n"));
+    let latin = connection.request("GET /latin HTTP/1.1\r\n\r\n").await;
+    assert!(latin.contains("Content-Type: text/plain; Charset=iso-8859-1\r\n"));
+    let json = connection.request("GET /json HTTP/1.1\r\n\r\n").await;
+    assert!(json.contains("Content-Type: application/json\r\n"));
+}
+
+#[tokio::test]
+async fn test_download_progress() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::{Arc, Mutex};
+    let reports = Arc::new(Mutex::new(vec![]));
+    let mut server = server::new("127.0.0.1:0".to_string());
+    let recorded = Arc::clone(&reports);
+    server.add_route(Method::Get, "/download", move |_req| {
+        let recorded = Arc::clone(&recorded);
+        let file = std::io::Cursor::new(vec![b'x'; 100_000]);
+        Response::from_reader(file, 100_000, "application/octet-stream")
+            .on_progress(move |sent| recorded.lock().unwrap().push(sent))
+    });
+    let raw = "GET /download HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let response = testing::send(server, raw).await;
+    assert!(response.ends_with(&"x".repeat(100_000)));
+
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() > 1);
+    assert!(reports.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(reports.last(), Some(&100_000));
+}
+
+#[tokio::test]
+async fn test_client() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut server = server::from_listener(listener).unwrap();
+    server.add_route(Method::Get, "/hello", |req| {
+        Response::html(format!(
+            "hello {}",
+            req.query_param("name").unwrap_or_default()
+        ))
+    });
+    server.add_route(Method::Post, "/echo", |req| {
+        Response::html(String::from_utf8_lossy(req.body()).into_owned())
+    });
+    let handle = server.shutdown_handle();
+    let running = tokio::spawn(server.try_start());
+
+    let response = client::get(&format!("http://{}/hello?name=ana", address))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.body(), b"hello ana");
+
+    let url = format!("http://{}/echo", address);
+    let response = client::request(Method::Post, &url, &[("X-Test", "1")], b"ping")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.body(), b"ping");
+
+    let response = client::get(&format!("http://{}/missing", address))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let error = client::get("https://localhost/").await.unwrap_err();
+    assert!(matches!(error, error::ServerError::Request { .. }));
+
+    handle.shutdown();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_client_timeout() {
+    use std::time::{Duration, Instant};
+    // Accepts the connection but never answers nor closes it
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let held = tokio::spawn(async move { listener.accept().await.unwrap() });
+    let started = Instant::now();
+    let request = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let error = client::send_within(&address, request, Duration::from_millis(200))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    drop(held.await.unwrap());
+}
+
+#[cfg(feature = "compression")]
+#[tokio::test]
+async fn test_precompressed_files() {
+    use server::{self, ServerActions};
+    let dir = testing::temp_dir("precompressed_files");
+    std::fs::write(dir.join("app.js"), "plain").unwrap();
+    std::fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+    std::fs::write(dir.join("app.js.br"), "brotli").unwrap();
+    std::fs::write(dir.join("style.css"), "plain").unwrap();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.serve_dir("/assets", &dir);
+    let mut connection = testing::connect(server).await;
+    let get = |path: &str, accept: &str| {
+        format!(
+            "GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+            path, accept
+        )
+    };
+    let etag = |response: &str| {
+        response
+            .lines()
+            .find_map(|line| line.strip_prefix("ETag: "))
+            .unwrap()
+            .to_string()
+    };
+
+    let brotli = connection.request(&get("/assets/app.js", "gzip, br")).await;
+    assert!(brotli.contains("Content-Encoding: br\r\n"));
+    assert!(brotli.contains("Content-Type: text/javascript"));
+    assert!(brotli.contains("Vary: Accept-Encoding\r\n"));
+    assert!(brotli.ends_with("\r\n\r\nbrotli"));
+    let gzip = connection
+        .request(&get("/assets/app.js", "gzip, br;q=0.5"))
+        .await;
+    assert!(gzip.contains("Content-Encoding: gzip\r\n"));
+    assert!(gzip.ends_with("\r\n\r\ngzipped"));
+    let plain = connection.request(&get("/assets/app.js", "identity")).await;
+    assert!(!plain.contains("Content-Encoding"));
+    assert!(plain.contains("Vary: Accept-Encoding\r\n"));
+    assert!(plain.ends_with("\r\n\r\nplain"));
+    assert_ne!(etag(&brotli), etag(&gzip));
+    assert_ne!(etag(&gzip), etag(&plain));
+
+    let revalidate = format!(
+        "GET /assets/app.js HTTP/1.1\r\nAccept-Encoding: br\r\nIf-None-Match: {}\r\n\r\n",
+        etag(&gzip)
+    );
+    let revalidated = connection.request(&revalidate).await;
+    assert!(revalidated.starts_with("HTTP/1.1 200 OK\r\n"));
+    let only_plain = connection.request(&get("/assets/style.css", "br")).await;
+    assert!(!only_plain.contains("Content-Encoding"));
+    assert!(!only_plain.contains("Vary"));
+}
+
+#[tokio::test]
+async fn test_close_connection() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_async_route(Method::Get, "/slow", |_req| async {
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        Response::html("too late")
+    });
+    let connections = server.connections();
+    let mut connection = testing::connect(server).await;
+    connection
+        .send("GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await;
+
+    let busy = loop {
+        match connections.list().pop() {
+            Some(info) if info.busy => break info,
+            _ => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    assert!(busy.remote_addr.is_some());
+    assert!(connections.close_connection(busy.id));
+    let answer = tokio::time::timeout(Duration::from_secs(5), connection.read_to_end())
+        .await
+        .unwrap();
+    assert_eq!(answer, "");
+    while !connections.list().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!connections.close_connection(busy.id));
+}
+
+#[tokio::test]
+async fn test_api_docs() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.enable_api_docs("/openapi.json");
+    server
+        .add_route(Method::Get, "/users/:id", |_req| Response::new(200))
+        .summary("Returns a \"user\"");
+    server.add_route(Method::Delete, "/users/:id", |_req| Response::new(204));
+    server.add_route(Method::Get, "/files/*rest", |_req| Response::new(200));
+    let mut connection = testing::connect(server).await;
+
+    let docs = connection
+        .request("GET /openapi.json HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(docs.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(docs.contains("Content-Type: application/json\r\n"));
+    let body = docs.split("\r\n\r\n").nth(1).unwrap();
+    let parameter = r#"{"name":"id","in":"path","required":true,"schema":{"type":"string"}}"#;
+    let default = r#""responses":{"default":{"description":"Response of the handler"}}"#;
+    let expected = format!(
+        concat!(
+            r#"{{"openapi":"3.0.3","info":{{"title":"API","version":"1.0.0"}},"paths":{{"#,
+            r#""/users/{{id}}":{{"get":{{"summary":"Returns a \"user\"","parameters":[{p}],{d}}},"#,
+            r#""delete":{{"parameters":[{p}],{d}}}}},"#,
+            r#""/files/{{rest}}":{{"get":{{"parameters":[{r}],{d}}}}}}}}}"#,
+        ),
+        p = parameter,
+        r = parameter.replace("\"id\"", "\"rest\""),
+        d = default,
+    );
+    assert_eq!(body, expected);
+}
+
+#[tokio::test]
+async fn test_header_timeout() {
+    use server::{self, ServerActions};
+    use std::time::{Duration, Instant};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.header_timeout(Duration::from_secs(1));
+    let mut connection = testing::connect(server).await;
+
+    // Every byte is activity, but the head as a whole takes too long
+    let started = Instant::now();
+    for byte in "GET".chars() {
+        connection.send(&byte.to_string()).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+    }
+    let answer = connection.read_to_end().await;
+    let elapsed = started.elapsed();
+    assert!(answer.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    assert!(answer.contains("Connection: close\r\n"));
+    assert!(elapsed >= Duration::from_secs(1));
+    // A timeout restarted by the last byte would only end after 1.6s
+    assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn test_download() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let dir = testing::temp_dir("download");
+    let file = dir.join("data.bin");
+    std::fs::write(&file, "a,b\n1,2\n").unwrap();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    let path = file.clone();
+    server.add_async_route(Method::Get, "/report", move |_req| {
+        Response::download(path.clone(), "report.csv")
+    });
+    server.add_async_route(Method::Get, "/resume", move |_req| {
+        Response::download(file.clone(), "résumé \"v1\".pdf")
+    });
+    server.add_async_route(Method::Get, "/missing", move |_req| {
+        Response::download(dir.join("missing.csv"), "missing.csv")
+    });
+    let mut connection = testing::connect(server).await;
+
+    let report = connection.request("GET /report HTTP/1.1\r\n\r\n").await;
+    assert!(report.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(report.contains("Content-Type: text/csv\r\n"));
+    assert!(report.contains("Content-Disposition: attachment; filename=\"report.csv\"\r\n"));
+    assert!(report.ends_with("\r\n\r\na,b\n1,2\n"));
+    let resume = connection.request("GET /resume HTTP/1.1\r\n\r\n").await;
+    assert!(resume.contains("Content-Type: application/pdf\r\n"));
+    assert!(resume.contains(concat!(
+        "Content-Disposition: attachment; filename=\"r_sum_ \\\"v1\\\".pdf\"; ",
+        "filename*=UTF-8''r%C3%A9sum%C3%A9%20%22v1%22.pdf\r\n"
+    )));
+    let missing = connection.request("GET /missing HTTP/1.1\r\n\r\n").await;
+    assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[tokio::test]
+async fn test_status_endpoint() {
+    use server::{self, ServerActions};
+    let raw = "GET /__mini_rest/status HTTP/1.1\r\n\r\n";
+    let disabled = server::new("127.0.0.1:0".to_string());
+    let mut connection = testing::connect(disabled).await;
+    let response = connection.request(raw).await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.status_endpoint(true);
+    let mut connection = testing::connect(server).await;
+    connection.request("GET /missing HTTP/1.1\r\n\r\n").await;
+    let response = connection.request(raw).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    let expected = format!(
+        "{{\"version\":\"{}\",\"uptime_secs\":0,\"active_connections\":1,\"requests\":2}}",
+        env!("CARGO_PKG_VERSION")
+    );
+    assert!(response.ends_with(&expected), "{}", response);
+}
+
+#[tokio::test]
+async fn test_max_body_size() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.max_body_size(4);
+    server.add_route(Method::Post, "/", |req| {
+        Response::html(String::from_utf8_lossy(req.body()).into_owned())
+    });
+    let mut connection = testing::connect(server).await;
+
+    let small = connection
+        .request("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd")
+        .await;
+    assert!(small.ends_with("\r\n\r\nabcd"));
+    // Rejected from the head alone, before the body arrives
+    let big = connection
+        .request("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n")
+        .await;
+    assert!(big.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_request_body_decoding() {
+    use request::{Limits, ParseError};
+    use std::io::Write;
+    let take = |encoding: &str, body: &[u8], max_body_size: Option<usize>| {
+        let mut buffer = format!(
+            "POST / HTTP/1.1\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+            encoding,
+            body.len()
+        )
+        .into_bytes();
+        buffer.extend_from_slice(body);
+        let limits = Limits {
+            max_body_size,
+            ..Limits::default()
+        };
+        request::take_request(&mut buffer, &limits).unwrap()
+    };
+    let text = b"hello hello hello hello";
+    let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    gzip.write_all(text).unwrap();
+    let gzip = gzip.finish().unwrap();
+    let mut zlib = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+    zlib.write_all(text).unwrap();
+    let zlib = zlib.finish().unwrap();
+    let mut raw = flate2::write::DeflateEncoder::new(vec![], flate2::Compression::default());
+    raw.write_all(text).unwrap();
+    let raw = raw.finish().unwrap();
+
+    let decoded = take("gzip", &gzip, None).unwrap();
+    assert_eq!(decoded.body(), text);
+    assert_eq!(decoded.header("Content-Encoding"), None);
+    assert_eq!(decoded.header("Content-Length"), Some("23"));
+    assert_eq!(take("deflate", &zlib, None).unwrap().body(), text);
+    assert_eq!(take("Deflate", &raw, None).unwrap().body(), text);
+
+    let bomb = vec![0; 100_000];
+    let mut compressed = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+    compressed.write_all(&bomb).unwrap();
+    let compressed = compressed.finish().unwrap();
+    assert!(compressed.len() < 1000);
+    let too_large = take("gzip", &compressed, Some(1000));
+    assert!(matches!(too_large, Err(ParseError::BodyTooLarge)));
+    assert!(take("gzip", &compressed, Some(100_000)).is_ok());
+
+    let unknown = take("zstd", b"data", None);
+    assert!(matches!(unknown, Err(ParseError::UnsupportedEncoding)));
+    assert_eq!(unknown.unwrap_err().status(), 415);
+    assert!(matches!(
+        take("gzip", b"not gzip", None),
+        Err(ParseError::Malformed)
+    ));
+}
+
+#[test]
+fn test_dev_log() {
+    use response::Response;
+    let request = testing::request(
+        "POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\n\r\nname: ana",
+    );
+    let response = Response::html("created").with_header("Location", "/users/1");
+    assert_eq!(
+        dev::transaction(&request, &response),
+        concat!(
+            "--> POST /users HTTP/1.1\n",
+            "    Host: localhost\n",
+            "    Content-Length: 9\n",
+            "    name: ana\n",
+            "<-- 200 OK\n",
+            "    Content-Type: text/html\n",
+            "    Location: /users/1\n",
+            "    created\n",
+        )
+    );
+
+    let request = testing::request("GET / HTTP/1.0\r\n\r\n");
+    let binary = Response::new(200).with_body(vec![0, 159, 146, 150]);
+    let log = dev::transaction(&request, &binary);
+    assert!(log.ends_with("<-- 200 OK\n    <binary body, 4 bytes>\n"));
+    let big = Response::new(200).with_body("é".repeat(1000));
+    let log = dev::transaction(&request, &big);
+    assert!(log.ends_with(&format!(
+        "    {}\n    <... 2000 bytes in total>\n",
+        "é".repeat(256)
+    )));
+}
+
+#[tokio::test]
+async fn test_repeated_headers() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/login", |_req| {
+        Response::html("welcome")
+            .with_header("Set-Cookie", "session=abc; HttpOnly")
+            .with_header("Link", "</app.css>; rel=preload")
+            .with_header("Set-Cookie", "theme=dark")
+    });
+    let raw = "GET /login HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let response = testing::send(server, raw).await;
+
+    let cookies: Vec<&str> = response
+        .lines()
+        .filter(|line| line.starts_with("Set-Cookie: "))
+        .collect();
+    assert_eq!(
+        cookies,
+        vec![
+            "Set-Cookie: session=abc; HttpOnly",
+            "Set-Cookie: theme=dark"
+        ]
+    );
+    // Framing headers are dropped by the parser, only the ones of the handler remain
+    let parsed = response::parse_response(response.as_bytes()).unwrap();
+    let names: Vec<&str> = parsed
+        .headers()
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    assert_eq!(
+        names,
+        vec!["Content-Type", "Set-Cookie", "Link", "Set-Cookie"]
+    );
+}
+
+#[tokio::test]
+async fn test_bind() {
+    use error::ServerError;
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions, ServerInfo};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| Response::html("live"));
+    let address = server.bind().unwrap();
+    assert_eq!(server.bind().unwrap(), address);
+    assert_eq!(server.address(), address.to_string());
+
+    // The port is taken as soon as `bind` returns
+    let mut taken = server::new(address.to_string());
+    assert!(matches!(taken.bind(), Err(ServerError::Bind { .. })));
+
+    let handle = server.shutdown_handle();
+    let running = tokio::spawn(server.try_start());
+    let response = client::get(&format!("http://{}/", address)).await.unwrap();
+    assert_eq!(response.body(), b"live");
+    handle.shutdown();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_method_not_allowed_handler() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/users", |_req| Response::html("users"));
+    server.add_route(Method::Post, "/users", |_req| Response::new(201));
+    server.set_method_not_allowed(|req, allowed: &[Method]| {
+        let allowed: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+        Response::new(405).with_body(format!(
+            "{} not allowed, use {}",
+            req.method().as_str(),
+            allowed.join("/")
+        ))
+    });
+    let mut connection = testing::connect(server).await;
+
+    let delete = connection.request("DELETE /users HTTP/1.1\r\n\r\n").await;
+    assert!(delete.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    assert!(delete.contains("Allow: GET, HEAD, POST, OPTIONS\r\n"));
+    assert!(delete.ends_with("DELETE not allowed, use GET/HEAD/POST/OPTIONS"));
+    let options = connection.request("OPTIONS /users HTTP/1.1\r\n\r\n").await;
+    assert!(options.starts_with("HTTP/1.1 204 No Content\r\n"));
+    let missing = connection.request("DELETE /missing HTTP/1.1\r\n\r\n").await;
+    assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[tokio::test]
+async fn test_max_path_segments() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.max_path_segments(3);
+    server.add_route(Method::Get, "/files/*rest", |req| {
+        Response::html(req.param("rest").unwrap().to_string())
+    });
+    let mut connection = testing::connect(server).await;
+
+    let within = connection.request("GET /files/a/b HTTP/1.1\r\n\r\n").await;
+    assert!(within.ends_with("\r\n\r\na/b"));
+    let deep = connection
+        .request("GET /files/a/b/c HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(deep.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    let slashes = format!("GET /files/{} HTTP/1.1\r\n\r\n", "x/".repeat(5000));
+    let slashes = connection.request(&slashes).await;
+    assert!(slashes.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    // The limit applies to every router, not only the one of the server
+    let mut api = router::Router::new();
+    api.add_route(Method::Get, "/files/*rest", |_req| Response::html("api"));
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.max_path_segments(3);
+    server.vhost("api.example.com", api);
+    let mut connection = testing::connect(server).await;
+    let deep = connection
+        .request("GET /files/a/b/c HTTP/1.1\r\nHost: api.example.com\r\n\r\n")
+        .await;
+    assert!(deep.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.max_path_segments(usize::MAX);
+    server.add_route(Method::Get, "/files/*rest", |_req| Response::html("files"));
+    let mut connection = testing::connect(server).await;
+    let path = format!("GET /files/{} HTTP/1.1\r\n\r\n", "x/".repeat(200));
+    assert!(connection.request(&path).await.ends_with("\r\n\r\nfiles"));
+}
+
+#[tokio::test]
+async fn test_recent_requests() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.record_requests(2);
+    server.status_endpoint(true);
+    server.add_route(Method::Get, "/users", |_req| Response::html("users"));
+    let recent = server.recent_requests();
+    let mut connection = testing::connect(server).await;
+
+    connection.request("GET /users HTTP/1.1\r\n\r\n").await;
+    connection.request("POST /users HTTP/1.1\r\n\r\n").await;
+    connection
+        .request("GET /missing?q=\"x\" HTTP/1.1\r\n\r\n")
+        .await;
+    let recorded: Vec<(Method, String, u16)> = recent
+        .list()
+        .into_iter()
+        .map(|recorded| (recorded.method, recorded.path, recorded.status))
+        .collect();
+    assert_eq!(
+        recorded,
+        vec![
+            (Method::Post, "/users".to_string(), 405),
+            (Method::Get, "/missing?q=\"x\"".to_string(), 404),
+        ]
+    );
+
+    let listed = connection
+        .request("GET /__mini_rest/requests HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(listed.contains("Content-Type: application/json\r\n"));
+    let body = listed.split("\r\n\r\n").nth(1).unwrap();
+    assert!(body.starts_with(r#"[{"method":"POST","path":"/users","status":405,"time":"#));
+    assert!(body.contains(r#"{"method":"GET","path":"/missing?q=\"x\"","status":404,"time":"#));
+    assert_eq!(body.matches("\"method\"").count(), 2);
+}
+
+#[tokio::test]
+async fn test_route_cache() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+    let calls = Arc::new(AtomicU64::new(0));
+    let mut server = server::new("127.0.0.1:0".to_string());
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Get, "/greeting", move |req| {
+            let call = counted.fetch_add(1, Ordering::Relaxed) + 1;
+            let language = req.header("Accept-Language").unwrap_or("en").to_string();
+            Response::html(format!("{} {}", language, call)).with_header("Vary", "Accept-Language")
+        })
+        .cache_for(Duration::from_millis(300));
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Get, "/private", move |_req| {
+            let call = counted.fetch_add(1, Ordering::Relaxed) + 1;
+            Response::html(format!("private {}", call)).no_store()
+        })
+        .cache_for(Duration::from_secs(60));
+    let mut connection = testing::connect(server).await;
+
+    let first = connection.request("GET /greeting HTTP/1.1\r\n\r\n").await;
+    assert!(first.ends_with("en 1"));
+    let cached = connection.request("GET /greeting HTTP/1.1\r\n\r\n").await;
+    assert!(cached.ends_with("en 1"));
+    let head = connection.request("HEAD /greeting HTTP/1.1\r\n\r\n").await;
+    assert!(head.contains("Content-Length: 4\r\n"));
+    let other = "GET /greeting HTTP/1.1\r\nAccept-Language: es\r\n\r\n";
+    assert!(connection.request(other).await.ends_with("es 2"));
+    assert!(connection.request(other).await.ends_with("es 2"));
+    let query = connection
+        .request("GET /greeting?page=2 HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(query.ends_with("en 3"));
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let expired = connection.request("GET /greeting HTTP/1.1\r\n\r\n").await;
+    assert!(expired.ends_with("en 4"));
+
+    let private = connection.request("GET /private HTTP/1.1\r\n\r\n").await;
+    assert!(private.ends_with("private 5"));
+    let private = connection.request("GET /private HTTP/1.1\r\n\r\n").await;
+    assert!(private.ends_with("private 6"));
+}
+
+#[tokio::test]
+async fn test_cache_skips_personal_responses() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+    let calls = Arc::new(AtomicU64::new(0));
+    let mut server = server::new("127.0.0.1:0".to_string());
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Get, "/account", move |_req| {
+            let call = counted.fetch_add(1, Ordering::Relaxed) + 1;
+            Response::html(format!("account {}", call))
+                .with_header("Cache-Control", "private, max-age=60")
+        })
+        .cache_for(Duration::from_secs(60));
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Get, "/login", move |_req| {
+            let call = counted.fetch_add(1, Ordering::Relaxed) + 1;
+            Response::html(format!("login {}", call)).with_header("Set-Cookie", "session=abc")
+        })
+        .cache_for(Duration::from_secs(60));
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Get, "/report", move |_req| {
+            let call = counted.fetch_add(1, Ordering::Relaxed) + 1;
+            Response::html(format!("report {}", call))
+        })
+        .cache_for(Duration::from_secs(60));
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Get, "/news", move |_req| {
+            let call = counted.fetch_add(1, Ordering::Relaxed) + 1;
+            Response::html(format!("news {}", call)).with_header("Cache-Control", "public")
+        })
+        .cache_for(Duration::from_secs(60));
+    let mut connection = testing::connect(server).await;
+
+    let account = "GET /account HTTP/1.1\r\n\r\n";
+    assert!(connection.request(account).await.ends_with("account 1"));
+    assert!(connection.request(account).await.ends_with("account 2"));
+    let login = "GET /login HTTP/1.1\r\n\r\n";
+    assert!(connection.request(login).await.ends_with("login 3"));
+    assert!(connection.request(login).await.ends_with("login 4"));
+    // Authorized answers aren't replayed to anonymous clients, unless marked as shared
+    let authorized = "GET /report HTTP/1.1\r\nAuthorization: Bearer abc\r\n\r\n";
+    assert!(connection.request(authorized).await.ends_with("report 5"));
+    let anonymous = "GET /report HTTP/1.1\r\n\r\n";
+    assert!(connection.request(anonymous).await.ends_with("report 6"));
+    let authorized = "GET /news HTTP/1.1\r\nAuthorization: Bearer abc\r\n\r\n";
+    assert!(connection.request(authorized).await.ends_with("news 7"));
+    let anonymous = "GET /news HTTP/1.1\r\n\r\n";
+    assert!(connection.request(anonymous).await.ends_with("news 7"));
+}
+
+#[test]
+fn test_cache_entries_are_capped() {
+    use cache::ResponseCache;
+    use clock::ManualClock;
+    use response::Response;
+    use std::time::Duration;
+    let clock = ManualClock::new();
+    let cache = ResponseCache::new(Duration::from_secs(60), clock.clone());
+    let get = |target: &str| testing::request(&format!("GET {} HTTP/1.1\r\n\r\n", target));
+    for page in 0..1025 {
+        clock.advance(Duration::from_millis(1));
+        cache.put(
+            &get(&format!("/items?page={}", page)),
+            &Response::html("items"),
+        );
+    }
+    assert!(cache.get(&get("/items?page=0")).is_none());
+    assert!(cache.get(&get("/items?page=1")).is_some());
+    assert!(cache.get(&get("/items?page=1024")).is_some());
+}
+
+#[tokio::test]
+async fn test_secure_headers() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.trust_proxy(true);
+    server.middleware(
+        middleware::secure_headers()
+            .content_security_policy("default-src 'none'")
+            .without("Referrer-Policy"),
+    );
+    server.add_route(Method::Get, "/", |_req| Response::html("home"));
+    server.add_route(Method::Get, "/embed", |_req| {
+        Response::html("embed").with_header("X-Frame-Options", "SAMEORIGIN")
+    });
+    let mut connection = testing::connect(server).await;
+
+    let home = connection.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(home.contains("X-Content-Type-Options: nosniff\r\n"));
+    assert!(home.contains("X-Frame-Options: DENY\r\n"));
+    assert!(home.contains("Content-Security-Policy: default-src 'none'\r\n"));
+    assert!(!home.contains("Referrer-Policy"));
+    assert!(!home.contains("Strict-Transport-Security"));
+    let embed = connection.request("GET /embed HTTP/1.1\r\n\r\n").await;
+    assert_eq!(embed.matches("X-Frame-Options").count(), 1);
+    assert!(embed.contains("X-Frame-Options: SAMEORIGIN\r\n"));
+    let https = connection
+        .request("GET / HTTP/1.1\r\nX-Forwarded-Proto: https\r\n\r\n")
+        .await;
+    assert!(https.contains("Strict-Transport-Security: max-age=31536000; includeSubDomains\r\n"));
+}
+
+#[tokio::test]
+async fn test_replace_router() {
+    use request::Method;
+    use response::Response;
+    use router::Router;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| Response::html("old home"));
+    server.add_async_route(Method::Get, "/slow", |_req| async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        Response::html("old slow")
+    });
+    let address = server.bind().unwrap();
+    let routes = server.router_handle();
+    let shutdown = server.shutdown_handle();
+    let running = tokio::spawn(server.try_start());
+
+    let url = format!("http://{}/slow", address);
+    let slow = tokio::spawn(async move { client::get(&url).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut router = Router::new();
+    router.add_route(Method::Get, "/", |_req| Response::html("new home"));
+    routes.replace_router(router);
+
+    let home = client::get(&format!("http://{}/", address)).await.unwrap();
+    assert_eq!(home.body(), b"new home");
+    let gone = client::get(&format!("http://{}/slow", address))
+        .await
+        .unwrap();
+    assert_eq!(gone.status(), 404);
+    // Routed before the swap, answered by the old routes
+    let slow = slow.await.unwrap().unwrap();
+    assert_eq!(slow.body(), b"old slow");
+
+    shutdown.shutdown();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_ephemeral() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let first = server::ephemeral(|server| {
+        server.add_route(Method::Get, "/", |_req| Response::html("first"));
+    })
+    .unwrap();
+    let second = server::ephemeral(|server| {
+        server.add_route(Method::Get, "/", |_req| Response::html("second"));
+    })
+    .unwrap();
+    assert!(first.address().ip().is_loopback());
+    assert_ne!(first.address().port(), second.address().port());
+    assert_eq!(first.url("/a"), format!("http://{}/a", first.address()));
+
+    let response = client::get(&first.url("/")).await.unwrap();
+    assert_eq!(response.body(), b"first");
+    let response = client::get(&second.url("/")).await.unwrap();
+    assert_eq!(response.body(), b"second");
+    first.shutdown().await.unwrap();
+    second.shutdown().await.unwrap();
+}
+
+#[test]
+fn test_chunked_body_decoded_once() {
+    use request::{Limits, Taken};
+    let limits = Limits::default();
+    let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+    let mut buffer = head.as_bytes().to_vec();
+    let mut pending = None;
+    let mut sent = String::new();
+    // A body trickled in a byte at a time never makes the buffer hold more than a chunk line
+    let chunks = "3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n";
+    for (index, byte) in chunks.bytes().enumerate() {
+        buffer.push(byte);
+        let taken = request::take_request_or_head(&mut buffer, &mut pending, &limits, |_| false);
+        if index + 1 < chunks.len() {
+            assert!(taken.is_none());
+            assert!(buffer.len() <= head.len() + 3, "{:?}", buffer);
+            continue;
+        }
+        match taken {
+            Some(Ok(Taken::Whole(request))) => {
+                sent = String::from_utf8_lossy(request.body()).into_owned()
+            }
+            _ => panic!("the request should be whole"),
+        }
+    }
+    assert_eq!(sent, "abcdef");
+    assert!(buffer.is_empty());
+    assert!(pending.is_none());
+}
+
+#[test]
+fn test_chunked_request_body() {
+    use request::{Limits, ParseError};
+    let limits = Limits {
+        max_body_size: Some(10),
+        ..Limits::default()
+    };
+    let take = |raw: &str| {
+        let mut buffer = raw.as_bytes().to_vec();
+        let taken = request::take_request(&mut buffer, &limits);
+        (taken, String::from_utf8(buffer).unwrap())
+    };
+    let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    let (taken, rest) = take(&format!(
+        "{}4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        head
+    ));
+    let request = taken.unwrap().unwrap();
+    assert_eq!(request.body(), b"Wikipedia");
+    assert_eq!(request.header("Content-Length"), Some("9"));
+    assert_eq!(request.header("Transfer-Encoding"), None);
+    assert_eq!(rest, "GET / HTTP/1.1\r\n\r\n");
+
+    // The decoded bytes are drained, only the head stays until the last chunk
+    let (incomplete, rest) = take(&format!("{}4\r\nWi", head));
+    assert!(incomplete.is_none());
+    assert_eq!(rest, head);
+    let (missing_end, _) = take(&format!("{}4\r\nWiki\r\n0\r\n", head));
+    assert!(missing_end.is_none());
+
+    let rejected = |raw: String| match take(&raw).0 {
+        Some(Err(e)) => e,
+        other => panic!("expected an error, got {:?}", other.map(|r| r.is_ok())),
+    };
+    assert!(matches!(
+        rejected(format!("{}x4\r\nWiki\r\n0\r\n\r\n", head)),
+        ParseError::Malformed
+    ));
+    assert!(matches!(
+        rejected(format!("{}{}", head, "1".repeat(2000))),
+        ParseError::Malformed
+    ));
+    assert!(matches!(
+        rejected(format!("{}4\r\nWikiX\r\n0\r\n\r\n", head)),
+        ParseError::Malformed
+    ));
+    assert!(matches!(
+        rejected(format!("{}8\r\n12345678\r\n8\r\n", head)),
+        ParseError::BodyTooLarge
+    ));
+    assert!(matches!(
+        rejected(format!("{}ffffffffffffffffff\r\n", head)),
+        ParseError::Malformed
+    ));
+    let smuggled = "POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n";
+    assert!(matches!(
+        rejected(format!("{}0\r\n\r\n", smuggled)),
+        ParseError::Malformed
+    ));
+    let not_last = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n";
+    assert!(matches!(
+        rejected(format!("{}0\r\n\r\n", not_last)),
+        ParseError::Malformed
+    ));
+}
+
+/// Serves a route over TLS, the session is only seen by requests of TLS connections.
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tls_info() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (config, roots) = testing::tls_config();
+    let configure = |server: &mut server::Server| {
+        server.add_route(Method::Get, "/", |req| match req.tls_info() {
+            Some(info) => Response::html(format!(
+                "{:?} {} {} {}",
+                info.alpn,
+                info.protocol_version,
+                info.peer_certificates.len(),
+                req.is_https()
+            )),
+            None => Response::html("plain"),
+        });
+    };
+    let plain = server::ephemeral(configure).unwrap();
+    let secure = server::ephemeral(|server| {
+        configure(server);
+        server.tls(Arc::new(config));
+    })
+    .unwrap();
+
+    let response = client::get(&plain.url("/")).await.unwrap();
+    assert_eq!(response.body(), b"plain");
+
+    let mut stream = testing::tls_connect(secure.address(), roots, &["http/1.1"])
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut answer = vec![];
+    stream.read_to_end(&mut answer).await.unwrap();
+    let answer = String::from_utf8(answer).unwrap();
+    assert!(answer.starts_with("HTTP/1.1 200"), "{}", answer);
+    assert!(
+        answer.ends_with("Some(\"http/1.1\") TLSv1_3 0 true"),
+        "{}",
+        answer
+    );
+
+    plain.shutdown().await.unwrap();
+    secure.shutdown().await.unwrap();
+}
+
+/// Only HTTP/1 is negotiated with ALPN, clients offering nothing else fail the handshake.
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_alpn() {
+    use server::{self, ServerActions, ServerInfo};
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+
+    let (config, roots) = testing::tls_config();
+    let config = Arc::new(config);
+    let secure = server::ephemeral(|server| {
+        // HTTP/2 is left out rather than advertised
+        server.alpn(&["h2", "http/1.1"]);
+        assert_eq!(server.alpn_protocols(), ["http/1.1"]);
+        server.tls(Arc::clone(&config));
+    })
+    .unwrap();
+    let stream = testing::tls_connect(secure.address(), roots.clone(), &["h2", "http/1.1"])
+        .await
+        .unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+    assert!(
+        testing::tls_connect(secure.address(), roots.clone(), &["h2"])
+            .await
+            .is_err()
+    );
+    // Clients not using ALPN at all get HTTP/1
+    assert!(testing::tls_connect(secure.address(), roots.clone(), &[])
+        .await
+        .is_ok());
+    secure.shutdown().await.unwrap();
+
+    // A protocol other than HTTP/1 only gets the handshake
+    let secure = server::ephemeral(|server| {
+        server.alpn(&["acme-tls/1"]);
+        server.tls(Arc::clone(&config));
+    })
+    .unwrap();
+    let mut stream = testing::tls_connect(secure.address(), roots, &["acme-tls/1"])
+        .await
+        .unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"acme-tls/1"[..]));
+    let mut answer = vec![];
+    stream.read_to_end(&mut answer).await.unwrap();
+    assert!(answer.is_empty());
+    secure.shutdown().await.unwrap();
+}
+
+/// Connections waiting for their handshake are listed, closed and shut down like the others.
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_stalled_tls_handshake() {
+    use server::{self, ServerActions};
+    use std::{sync::Arc, time::Duration};
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    let (config, _) = testing::tls_config();
+    let mut connections = None;
+    let secure = server::ephemeral(|server| {
+        server.tls(Arc::new(config));
+        connections = Some(server.connections());
+    })
+    .unwrap();
+    let connections = connections.unwrap();
+    let mut stalled = TcpStream::connect(secure.address()).await.unwrap();
+    let id = loop {
+        match connections.list().first() {
+            Some(connection) => break connection.id,
+            None => tokio::time::sleep(Duration::from_millis(5)).await,
+        }
+    };
+    assert!(connections.close_connection(id));
+    let mut answer = vec![];
+    stalled.read_to_end(&mut answer).await.unwrap();
+    assert!(answer.is_empty());
+
+    let _stalled = TcpStream::connect(secure.address()).await.unwrap();
+    while connections.list().is_empty() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let shutdown = tokio::time::timeout(Duration::from_secs(5), secure.shutdown());
+    assert!(shutdown.await.is_ok());
+}
+
+/// The handler of a streamed body gets each line before the rest of the body is sent.
+#[tokio::test]
+async fn test_body_stream() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use tokio::sync::mpsc;
+
+    let (lines, mut received) = mpsc::unbounded_channel();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.max_body_size(32);
+    server
+        .add_async_route(Method::Post, "/lines", move |req| {
+            let mut body = req.body_stream();
+            let lines = lines.clone();
+            async move {
+                let mut pending = vec![];
+                let mut count = 0;
+                while let Some(chunk) = body.next().await {
+                    match chunk {
+                        Ok(chunk) => pending.extend_from_slice(&chunk),
+                        Err(e) => return Response::new(e.status()),
+                    }
+                    while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                        let line: Vec<u8> = pending.drain(..=end).collect();
+                        lines.send(String::from_utf8(line).unwrap()).unwrap();
+                        count += 1;
+                    }
+                }
+                Response::html(format!("{} lines", count))
+            }
+        })
+        .stream_body();
+    server.add_async_route(Method::Post, "/buffered", |req| {
+        let mut body = req.body_stream();
+        async move {
+            let chunk = body.next().await.unwrap().unwrap();
+            assert!(body.next().await.is_none());
+            Response::html(String::from_utf8(chunk).unwrap())
+        }
+    });
+    let mut connection = testing::connect(server).await;
+
+    connection
+        .send("POST /lines HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n8\r\n{\"a\":1}\n\r\n")
+        .await;
+    assert_eq!(received.recv().await.unwrap(), "{\"a\":1}\n");
+    let response = connection.request("4\r\n{}\n\n\r\n0\r\n\r\n").await;
+    assert!(response.ends_with("\r\n\r\n3 lines"), "{}", response);
+    let response = connection
+        .request("POST /lines HTTP/1.1\r\nContent-Length: 6\r\n\r\n1\n2\n3\n")
+        .await;
+    assert!(response.ends_with("\r\n\r\n3 lines"), "{}", response);
+    let response = connection
+        .request("POST /buffered HTTP/1.1\r\nContent-Length: 5\r\n\r\nwhole")
+        .await;
+    assert!(response.ends_with("\r\n\r\nwhole"), "{}", response);
+
+    // The limit ends the stream once the decoded chunks exceed it
+    connection
+        .send("POST /lines HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+        .await;
+    let response = connection
+        .request("10\r\n0123456789abcde\n\r\n11\r\n0123456789abcdef\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+    assert!(response.contains("Connection: close\r\n"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_idempotent_route() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+    let calls = Arc::new(AtomicU64::new(0));
+    let mut server = server::new("127.0.0.1:0".to_string());
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Post, "/orders", move |req| {
+            let call = counted.fetch_add(1, Ordering::Relaxed) + 1;
+            match req.body() {
+                b"fail" => Response::new(500),
+                _ => Response::html(format!("order {}", call)).with_status(201),
+            }
+        })
+        .idempotent(Duration::from_secs(60));
+    let mut connection = testing::connect(server).await;
+
+    let order = "POST /orders HTTP/1.1\r\nIdempotency-Key: a\r\nContent-Length: 2\r\n\r\n{}";
+    let first = connection.request(order).await;
+    assert!(first.starts_with("HTTP/1.1 201 "));
+    assert!(first.ends_with("order 1"));
+    let replayed = connection.request(order).await;
+    assert!(replayed.contains("Idempotent-Replayed: true\r\n"));
+    assert!(replayed.ends_with("order 1"));
+    // Another key, or no key at all, runs the handler
+    let other = connection
+        .request("POST /orders HTTP/1.1\r\nIdempotency-Key: c\r\nContent-Length: 2\r\n\r\n{}")
+        .await;
+    assert!(other.ends_with("order 2"));
+    let unkeyed = connection
+        .request("POST /orders HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}")
+        .await;
+    assert!(unkeyed.ends_with("order 3"));
+    let reused = connection
+        .request("POST /orders HTTP/1.1\r\nIdempotency-Key: a\r\nContent-Length: 3\r\n\r\n{ }")
+        .await;
+    assert!(reused.starts_with("HTTP/1.1 422 "));
+
+    // Server errors are retried
+    let failing = "POST /orders HTTP/1.1\r\nIdempotency-Key: b\r\nContent-Length: 4\r\n\r\nfail";
+    assert!(connection
+        .request(failing)
+        .await
+        .starts_with("HTTP/1.1 500 "));
+    assert!(connection
+        .request(failing)
+        .await
+        .starts_with("HTTP/1.1 500 "));
+    assert_eq!(calls.load(Ordering::Relaxed), 5);
+}
+
+#[tokio::test]
+async fn test_default_headers() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.default_header("X-Powered-By", "first");
+    server.default_header("x-powered-by", "mini_rest");
+    server.append_default_header("Vary", "Origin");
+    server.add_route(Method::Get, "/", |_req| Response::html("home"));
+    server.add_route(Method::Get, "/custom", |_req| {
+        Response::html("custom")
+            .with_header("X-Powered-By", "handler")
+            .with_header("Vary", "Accept-Encoding")
+    });
+    let mut connection = testing::connect(server).await;
+
+    let home = connection.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(home.contains("x-powered-by: mini_rest\r\n"), "{}", home);
+    assert!(!home.contains("first"));
+    assert!(home.contains("Vary: Origin\r\n"));
+    let custom = connection.request("GET /custom HTTP/1.1\r\n\r\n").await;
+    assert!(custom.contains("X-Powered-By: handler\r\n"), "{}", custom);
+    assert!(!custom.contains("mini_rest"));
+    assert!(custom.contains("Vary: Accept-Encoding\r\n"));
+    assert!(custom.contains("Vary: Origin\r\n"));
+}
+
+#[tokio::test]
+async fn test_close_connection_response() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| Response::html("home"));
+    server.add_route(Method::Get, "/broken", |_req| {
+        Response::new(400).close_connection()
+    });
+    // The request pipelined after the closing response is never answered
+    let answer = testing::send(
+        server,
+        "GET / HTTP/1.1\r\n\r\nGET /broken HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+    )
+    .await;
+    assert_eq!(answer.matches("HTTP/1.1 ").count(), 2, "{}", answer);
+    assert!(answer.ends_with("Connection: close\r\n\r\n"), "{}", answer);
+}
+
+#[test]
+fn test_authorization() {
+    let bearer = |value: &str| {
+        let request = testing::request(&format!(
+            "GET / HTTP/1.1\r\nAuthorization: {}\r\n\r\n",
+            value
+        ));
+        request.bearer_token().map(str::to_string)
+    };
+    assert_eq!(
+        bearer("Bearer abc.def-ghi_~+/=="),
+        Some("abc.def-ghi_~+/==".to_string())
+    );
+    assert_eq!(bearer("bearer   token  "), Some("token".to_string()));
+    assert_eq!(bearer("Basic dXNlcjpwYXNz"), None);
+    assert_eq!(bearer("Bearer"), None);
+    assert_eq!(bearer("Bearer "), None);
+    assert_eq!(bearer("Bearer two tokens"), None);
+    assert_eq!(bearer("Bearer =="), None);
+    assert!(testing::request("GET / HTTP/1.1\r\n\r\n")
+        .bearer_token()
+        .is_none());
+
+    let basic = |value: &str| {
+        let request = testing::request(&format!(
+            "GET / HTTP/1.1\r\nAuthorization: {}\r\n\r\n",
+            value
+        ));
+        request
+            .basic_credentials()
+            .map(|credentials| (credentials.username, credentials.password))
+    };
+    assert_eq!(
+        basic("Basic dXNlcjpwYTpzcw=="),
+        Some(("user".to_string(), "pa:ss".to_string()))
+    );
+    assert_eq!(basic("basic YTo="), Some(("a".to_string(), String::new())));
+    assert_eq!(basic("Basic dXNlcg=="), None);
+    assert_eq!(basic("Basic dXNlcjpwYXNz="), None);
+    assert_eq!(basic("Basic !!!!"), None);
+    assert_eq!(basic("Bearer dXNlcjpwYXNz"), None);
+}
+
+/// Panicking handlers get a `500` while the server, and the same connection, keep serving.
+#[tokio::test]
+async fn test_handler_panics() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let panics = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&panics);
+    let bound = server::ephemeral(|server| {
+        server.on_panic(move |panic| {
+            assert_eq!(panic.message.as_deref(), Some("broken handler"));
+            counted.fetch_add(1, Ordering::Relaxed);
+        });
+        server.add_route(Method::Get, "/ok", |_req| Response::html("ok"));
+        server.add_route(Method::Get, "/panic", |_req| -> Response {
+            panic!("broken handler")
+        });
+        server.add_async_route(Method::Get, "/async-panic", |_req| async {
+            tokio::task::yield_now().await;
+            let message = String::from("broken handler");
+            if !message.is_empty() {
+                panic!("{}", message);
+            }
+            Response::new(200)
+        });
+    })
+    .unwrap();
+
+    let mut clients = vec![];
+    for client in 0..8 {
+        let address = bound.address();
+        clients.push(tokio::spawn(async move {
+            let socket = tokio::net::TcpStream::connect(address).await.unwrap();
+            let mut connection = testing::Connection::new(socket);
+            for round in 0..10 {
+                let path = match (client + round) % 3 {
+                    0 => "/ok",
+                    1 => "/panic",
+                    _ => "/async-panic",
+                };
+                let answer = connection
+                    .request(&format!("GET {} HTTP/1.1\r\n\r\n", path))
+                    .await;
+                match path {
+                    "/ok" => assert!(answer.ends_with("\r\n\r\nok"), "{}", answer),
+                    _ => assert!(answer.starts_with("HTTP/1.1 500 "), "{}", answer),
+                }
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+    let expected = (0..8)
+        .flat_map(|client| (0..10).map(move |round| (client + round) % 3))
+        .filter(|kind| *kind != 0)
+        .count();
+    assert_eq!(panics.load(Ordering::Relaxed), expected);
+    bound.shutdown().await.unwrap();
+}
+
+/// Requests refused from their head are answered before their body arrives.
+#[tokio::test]
+async fn test_reject_early() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&calls);
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.reject_early(|req| match req.bearer_token() {
+        Some("secret") => None,
+        _ => Some(Response::new(401)),
+    });
+    server.add_route(Method::Post, "/upload", move |req| {
+        counted.fetch_add(1, Ordering::Relaxed);
+        Response::html(format!("{} bytes", req.body().len()))
+    });
+    let mut connection = testing::connect(server).await;
+
+    // Refused along with its whole body, the connection stays usable
+    let refused = connection
+        .request("POST /upload HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc")
+        .await;
+    assert!(refused.starts_with("HTTP/1.1 401 "), "{}", refused);
+    assert!(!refused.contains("Connection: close"));
+    let accepted = connection
+        .request(
+            "POST /upload HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: 3\r\n\r\nabc",
+        )
+        .await;
+    assert!(accepted.ends_with("3 bytes"), "{}", accepted);
+
+    // Only the start of a large body is sent, the answer doesn't wait for the rest
+    connection
+        .send("POST /upload HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n")
+        .await;
+    let refused = connection.request("partial").await;
+    assert!(refused.starts_with("HTTP/1.1 401 "), "{}", refused);
+    assert!(refused.contains("Connection: close\r\n"), "{}", refused);
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+}
+
+/// Path params and the query string of the same request, through a real server.
+#[tokio::test]
+async fn test_params_and_query() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let bound = server::ephemeral(|server| {
+        server.add_route(Method::Get, "/users/:id", |req| {
+            Response::html(format!(
+                "id={} verbose={:?} query_id={:?} pairs={:?}",
+                req.param("id").unwrap_or_default(),
+                req.query_param("verbose"),
+                req.query_param("id"),
+                req.query_pairs().len()
+            ))
+        });
+        server.add_route(Method::Get, "/users/:id/posts/*rest", |req| {
+            Response::html(format!(
+                "id={} rest={} sort={:?}",
+                req.param("id").unwrap_or_default(),
+                req.param("rest").unwrap_or_default(),
+                req.query_param("sort")
+            ))
+        });
+    })
+    .unwrap();
+    let get = |path: &str| {
+        let url = bound.url(path);
+        async move {
+            let response = client::get(&url).await.unwrap();
+            assert_eq!(response.status(), 200, "{}", url);
+            String::from_utf8(response.body().to_vec()).unwrap()
+        }
+    };
+
+    assert_eq!(
+        get("/users/42?verbose=true").await,
+        "id=42 verbose=Some(\"true\") query_id=None pairs=1"
+    );
+    // A query parameter named like a path param doesn't replace it
+    assert_eq!(
+        get("/users/42?id=99&verbose").await,
+        "id=42 verbose=Some(\"\") query_id=Some(\"99\") pairs=2"
+    );
+    // Both are percent-decoded, `+` only means a space in the query
+    assert_eq!(
+        get("/users/j%C3%BCrgen+x?verbose=a%20b+c").await,
+        "id=jürgen+x verbose=Some(\"a b c\") query_id=None pairs=1"
+    );
+    assert_eq!(
+        get("/users//42/?verbose=1").await,
+        "id=42 verbose=Some(\"1\") query_id=None pairs=1"
+    );
+    assert_eq!(
+        get("/users/7/posts/2024/05?sort=desc").await,
+        "id=7 rest=2024/05 sort=Some(\"desc\")"
+    );
+    assert_eq!(get("/users/7/posts/a?b/c").await, "id=7 rest=a sort=None");
+    bound.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_directory_index() {
+    use server::{self, ServerActions};
+    let dir = testing::temp_dir("directory_index");
+    std::fs::create_dir_all(dir.join("site")).unwrap();
+    std::fs::write(dir.join("site/index.html"), "<h1>site</h1>").unwrap();
+    std::fs::create_dir_all(dir.join("files/nested")).unwrap();
+    std::fs::write(dir.join("files/a <b>&.txt"), "a").unwrap();
+    std::fs::write(dir.join("files/.secret"), "hidden").unwrap();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.serve_dir("/open", &dir).autoindex(true);
+    server.serve_dir("/closed", &dir);
+    let mut connection = testing::connect(server).await;
+
+    let index = connection
+        .request("GET /closed/site/ HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(index.ends_with("\r\n\r\n<h1>site</h1>"), "{}", index);
+    let redirect = connection
+        .request("GET /closed/site?lang=es HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(redirect.starts_with("HTTP/1.1 301 "));
+    assert!(redirect.contains("Location: /closed/site/?lang=es\r\n"));
+    let forbidden = connection
+        .request("GET /closed/files/ HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(forbidden.starts_with("HTTP/1.1 403 "), "{}", forbidden);
+
+    let listing = connection
+        .request("GET /open/files/ HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(listing.starts_with("HTTP/1.1 200 "), "{}", listing);
+    assert!(listing.contains("<title>Index of /open/files/</title>"));
+    assert!(listing.contains("<a href=\"../\">"));
+    let nested = listing.find("<a href=\"nested/\">nested/</a>").unwrap();
+    let file = listing
+        .find("<a href=\"a%20%3Cb%3E%26.txt\">a &lt;b&gt;&amp;.txt</a>")
+        .unwrap();
+    assert!(nested < file);
+    assert!(!listing.contains("secret"));
+    let file = connection
+        .request("GET /open/files/a%20%3Cb%3E%26.txt HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(file.ends_with("\r\n\r\na"), "{}", file);
+}
+
+#[tokio::test]
+async fn test_route_metrics() {
+    use metrics::LATENCY_BUCKETS;
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/fast", |_req| Response::html("fast"));
+    server.add_async_route(Method::Get, "/slow/:id", |_req| async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Response::html("slow")
+    });
+    let metrics = server.metrics();
+    let raw = "GET /fast HTTP/1.1\r\n\r\n\
+               HEAD /fast HTTP/1.1\r\n\r\n\
+               GET /slow/1 HTTP/1.1\r\n\r\n\
+               GET /slow/2 HTTP/1.1\r\n\r\n\
+               GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n";
+    testing::send(server, raw).await;
+
+    let routes = metrics.routes();
+    assert_eq!(routes.len(), 2);
+    let fast = &routes["GET /fast"];
+    assert_eq!(fast.requests, 2);
+    assert_eq!(fast.buckets.iter().sum::<u64>(), 2);
+    let slow = &routes["GET /slow/:id"];
+    assert_eq!(slow.requests, 2);
+    assert!(slow.total_time >= Duration::from_millis(40));
+    let fastest = LATENCY_BUCKETS
+        .iter()
+        .position(|bound| *bound >= Duration::from_millis(20))
+        .unwrap();
+    assert_eq!(slow.buckets[..fastest].iter().sum::<u64>(), 0);
+    assert_eq!(slow.buckets[fastest..].iter().sum::<u64>(), 2);
+}
+
+#[tokio::test]
+async fn test_write_timeout() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut metrics = None;
+    let bound = server::ephemeral(|server| {
+        server.write_timeout(Duration::from_millis(100));
+        server.add_route(Method::Get, "/large", |_req| {
+            Response::new(200).with_body(vec![b'x'; 64 * 1024 * 1024])
+        });
+        server.add_route(Method::Get, "/small", |_req| Response::html("small"));
+        metrics = Some(server.metrics());
+    })
+    .unwrap();
+    let metrics = metrics.unwrap();
+
+    // Never reading the response, the server gives up on it
+    let mut stream = tokio::net::TcpStream::connect(bound.address())
+        .await
+        .unwrap();
+    stream
+        .write_all(b"GET /large HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        while metrics.snapshot().requests == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        while metrics.snapshot().active_connections > 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(closed.is_ok());
+    let mut received = vec![];
+    let _ = stream.read_to_end(&mut received).await;
+    assert!(received.len() < 64 * 1024 * 1024);
+
+    // Clients reading their responses aren't affected
+    let response = client::get(&bound.url("/small")).await.unwrap();
+    assert_eq!(response.body(), b"small");
+    bound.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_io_error_responses() {
+    use request::Method;
+    use response::{IntoResponse, Response};
+    use server::{self, ServerActions};
+    use std::io::{Error, ErrorKind};
+    assert_eq!(
+        Error::from(ErrorKind::NotFound).into_response().status(),
+        404
+    );
+    assert_eq!(
+        Error::from(ErrorKind::PermissionDenied)
+            .into_response()
+            .status(),
+        403
+    );
+    assert_eq!(Error::other("disk on fire").into_response().status(), 500);
+
+    let routes = |server: &mut server::Server| {
+        server.add_route(
+            Method::Get,
+            "/missing",
+            |_req| -> std::io::Result<Response> {
+                Ok(Response::html(std::fs::read_to_string(
+                    "/nonexistent/mini_rest",
+                )?))
+            },
+        );
+        server.add_route(
+            Method::Get,
+            "/denied",
+            |_req| -> std::io::Result<Response> { Err(ErrorKind::PermissionDenied.into()) },
+        );
+        server.add_route(
+            Method::Get,
+            "/broken",
+            |_req| -> std::io::Result<Response> { Err(ErrorKind::InvalidData.into()) },
+        );
+    };
+    let raw = "GET /missing HTTP/1.1\r\n\r\n\
+               GET /denied HTTP/1.1\r\n\r\n\
+               GET /broken HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let statuses = |response: String| -> Vec<String> {
+        response
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|answer| answer[..3].to_string())
+            .collect()
+    };
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    routes(&mut server);
+    let response = testing::send(server, raw).await;
+    assert_eq!(statuses(response), ["404", "403", "500"]);
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    routes(&mut server);
+    server.set_io_error_status(|kind| match kind {
+        ErrorKind::PermissionDenied => 404,
+        kind => response::io_error_status(kind),
+    });
+    let response = testing::send(server, raw).await;
+    assert_eq!(statuses(response), ["404", "404", "500"]);
+}
+
+#[tokio::test]
+async fn test_buffer_pool() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::atomic::Ordering;
+    let read_buffers = |pooled: bool| async move {
+        let mut metrics = None;
+        let bound = server::ephemeral(|server| {
+            server.use_buffer_pool(pooled);
+            server.add_route(Method::Get, "/", |_req| Response::html("hello"));
+            metrics = Some(server.metrics());
+        })
+        .unwrap();
+        let metrics = metrics.unwrap();
+        for _ in 0..5 {
+            let response = client::get(&bound.url("/")).await.unwrap();
+            assert_eq!(response.body(), b"hello");
+            // The buffer is given back once the server sees the connection closed
+            while metrics.snapshot().active_connections > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        }
+        let read_buffers = metrics.counters.read_buffers.load(Ordering::Relaxed);
+        bound.shutdown().await.unwrap();
+        read_buffers
+    };
+    assert_eq!(read_buffers(false).await, 5);
+    assert_eq!(read_buffers(true).await, 1);
+}
+
+#[tokio::test]
+async fn test_wrong_content_length() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/lying", |_req| {
+        Response::html("hello").with_header("Content-Length", "42")
+    });
+    server.add_route(Method::Get, "/honest", |_req| {
+        Response::html("hello").with_header("content-length", "5")
+    });
+    server.add_route(Method::Head, "/size", |_req| {
+        Response::new(200).with_header("Content-Length", "1024")
+    });
+    let response = testing::send(
+        server,
+        "GET /lying HTTP/1.1\r\n\r\n\
+         GET /honest HTTP/1.1\r\n\r\n\
+         HEAD /size HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    let answers: Vec<&str> = response.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+    assert_eq!(answers.len(), 3);
+    for answer in &answers[..2] {
+        assert_eq!(
+            answer
+                .to_ascii_lowercase()
+                .matches("content-length")
+                .count(),
+            1
+        );
+        assert!(answer.contains("Content-Length: 5\r\n"));
+        assert!(answer.ends_with("\r\n\r\nhello"));
+    }
+    assert!(answers[2].contains("Content-Length: 1024\r\n"));
+    assert!(answers[2].ends_with("\r\n\r\n"));
+}
+
+#[tokio::test]
+async fn test_expect_continue() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let routes = || {
+        let mut server = server::new("127.0.0.1:0".to_string());
+        server.max_body_size(16);
+        server.add_route(Method::Post, "/upload", |req| {
+            Response::html(format!("got {}", String::from_utf8_lossy(req.body())))
+        });
+        server
+    };
+    let mut connection = testing::connect(routes()).await;
+    let head = "POST /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n";
+    assert_eq!(
+        connection.request(head).await,
+        "HTTP/1.1 100 Continue\r\n\r\n"
+    );
+    let response = connection.request("hello").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("got hello"));
+
+    // A body sent along the head needs no go-ahead
+    let response = connection
+        .request("POST /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhi")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // Too large to be accepted, the client is told not to send it
+    let response = connection
+        .request("POST /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 1000\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+    assert_eq!(connection.read_to_end().await, "");
+
+    // Without the header an oversized body is refused as usual
+    let response = testing::send(
+        routes(),
+        "POST /upload HTTP/1.1\r\nContent-Length: 1000\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 413 "));
+}
+
+#[tokio::test]
+async fn test_error_handler() {
+    use request::Method;
+    use response::{IntoResponse, Response};
+    use server::{self, ServerActions};
+    #[derive(Debug)]
+    enum AppError {
+        NotFound(String),
+        Invalid,
+    }
+    impl IntoResponse for AppError {
+        fn into_response(self) -> Response {
+            Response::new(500)
+        }
+    }
+    let find = |id: &str| match id {
+        "1" => Ok("alice"),
+        "bad" => Err(AppError::Invalid),
+        _ => Err(AppError::NotFound(id.to_string())),
+    };
+    let routes = |server: &mut server::Server| {
+        server.add_route(Method::Get, "/users/:id", move |req| {
+            let name = find(req.param("id").unwrap_or_default())?;
+            Ok::<_, AppError>(Response::html(name))
+        });
+        server.add_async_route(Method::Get, "/async/:id", move |req| {
+            let id = req.param("id").unwrap_or_default().to_string();
+            async move {
+                let name = find(&id)?;
+                Ok::<_, AppError>(Response::html(name))
+            }
+        });
+        server.add_route(Method::Get, "/other", |_req| {
+            Err::<Response, _>(Response::new(418))
+        });
+    };
+    let raw = "GET /users/1 HTTP/1.1\r\n\r\n\
+               GET /users/7 HTTP/1.1\r\n\r\n\
+               GET /async/bad HTTP/1.1\r\n\r\n\
+               GET /async/7 HTTP/1.1\r\n\r\n\
+               GET /other HTTP/1.1\r\nConnection: close\r\n\r\n";
+    let statuses = |response: String| -> Vec<String> {
+        response
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|answer| answer[..3].to_string())
+            .collect()
+    };
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    routes(&mut server);
+    let response = testing::send(server, raw).await;
+    assert_eq!(statuses(response), ["200", "500", "500", "500", "418"]);
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    routes(&mut server);
+    server.error_handler(|error: AppError| match error {
+        AppError::NotFound(id) => Response::new(404).with_body(format!("no user {}", id)),
+        AppError::Invalid => Response::new(400),
+    });
+    let response = testing::send(server, raw).await;
+    assert!(response.contains("no user 7"));
+    assert_eq!(statuses(response), ["200", "404", "400", "404", "418"]);
+    // Outside of a server the error answers for itself
+    assert_eq!(
+        Err::<Response, _>(AppError::Invalid)
+            .into_response()
+            .status(),
+        500
+    );
+}
+
+#[tokio::test]
+async fn test_h2c_upgrade_ignored() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let upstream = server::ephemeral(|server| {
+        server.add_route(Method::Get, "/up/headers", |req| {
+            let names: Vec<&str> = req
+                .headers()
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect();
+            Response::html(names.join(","))
+        });
+    })
+    .unwrap();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| Response::html("hello"));
+    server.proxy_pass("/up", &upstream.url(""));
+    let mut connection = testing::connect(server).await;
+
+    let probe = "GET / HTTP/1.1\r\nHost: x\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                 Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n";
+    let response = connection.request(probe).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(!response.contains("Upgrade"));
+    assert!(!response.contains("Connection:"));
+    assert!(response.ends_with("hello"));
+
+    // The connection is still usable, and the probe isn't passed on upstream
+    let response = connection
+        .request(&probe.replace("GET / ", "GET /up/headers "))
+        .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("Host,Connection"));
+    upstream.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_request_extensions() {
+    use extract::Extension;
+    use middleware::Middleware;
+    use request::{Method, Request};
+    use response::Response;
+    use server::{self, ServerActions};
+    #[derive(Debug, Clone, PartialEq)]
+    struct User(String);
+    struct Identify;
+    impl Middleware for Identify {
+        fn before(&self, request: &Request) -> Option<Response> {
+            if let Some(name) = request.header("X-User") {
+                request.extensions().insert(User(name.to_string()));
+            }
+            None
+        }
+    }
+    let request = testing::request("GET / HTTP/1.1\r\n\r\n");
+    assert_eq!(request.extensions().insert(7u32), None);
+    assert_eq!(request.extensions().insert(8u32), Some(7));
+    assert!(request.extensions().contains::<u32>());
+    assert_eq!(request.clone().extensions().get::<u32>(), Some(8));
+    assert_eq!(request.extensions().remove::<u32>(), Some(8));
+    assert_eq!(request.extensions().get::<u32>(), None);
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.middleware(Identify);
+    server.add_route(Method::Get, "/me", |req| {
+        match req.extensions().get::<User>() {
+            Some(User(name)) => Response::html(format!("hello {}", name)),
+            None => Response::new(401),
+        }
+    });
+    server.add_async_route(Method::Get, "/later", |req| {
+        let user = req.extensions().get::<User>();
+        async move {
+            tokio::task::yield_now().await;
+            Response::html(format!("{:?}", user))
+        }
+    });
+    server.add_handler(
+        Method::Get,
+        "/extracted",
+        |Extension(user): Extension<User>| Response::html(user.0),
+    );
+    let mut connection = testing::connect(server).await;
+    let response = connection
+        .request("GET /me HTTP/1.1\r\nX-User: ada\r\n\r\n")
+        .await;
+    assert!(response.ends_with("hello ada"));
+    let response = connection.request("GET /me HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 401 "));
+    let response = connection
+        .request("GET /later HTTP/1.1\r\nX-User: ada\r\n\r\n")
+        .await;
+    assert!(response.ends_with("Some(User(\"ada\"))"));
+    let response = connection
+        .request("GET /extracted HTTP/1.1\r\nX-User: ada\r\n\r\n")
+        .await;
+    assert!(response.ends_with("\r\n\r\nada"));
+    let response = connection.request("GET /extracted HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 500 "));
+}
+
+#[tokio::test]
+async fn test_options_asterisk() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/users", |_req| Response::html("users"));
+    server.add_route(Method::Post, "/users", |_req| Response::new(201));
+    server.add_route(Method::Delete, "/users/:id", |_req| Response::new(204));
+    server.add_route(Method::Get, "/*", |_req| Response::html("anything"));
+    let mut connection = testing::connect(server).await;
+    let response = connection.request("OPTIONS * HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    let allow = response
+        .lines()
+        .find_map(|line| line.strip_prefix("Allow: "))
+        .unwrap();
+    let mut methods: Vec<&str> = allow.split(", ").collect();
+    methods.sort_unstable();
+    assert_eq!(methods, ["DELETE", "GET", "HEAD", "OPTIONS", "POST"]);
+    // Only `OPTIONS` may ask about the whole server
+    let response = connection
+        .request("GET * HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await;
+    assert!(response.starts_with("HTTP/1.1 400 "));
+
+    let response = testing::send(
+        server::new("127.0.0.1:0".to_string()),
+        "OPTIONS * HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.contains("Allow: OPTIONS\r\n"));
+}
+
+#[tokio::test]
+async fn test_accepts_content_types() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server
+        .add_route(Method::Post, "/users", |req| {
+            Response::html(format!("{} bytes", req.body().len()))
+        })
+        .accepts_content_types(&["application/json", "text/*"]);
+    server
+        .add_route(Method::Delete, "/users", |_req| Response::new(204))
+        .accepts_content_types(&["application/json"]);
+    let mut connection = testing::connect(server).await;
+    let post = |content_type: &str, body: &str| {
+        let header = match content_type {
+            "" => String::new(),
+            content_type => format!("Content-Type: {}\r\n", content_type),
+        };
+        format!(
+            "POST /users HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+            header,
+            body.len(),
+            body
+        )
+    };
+    // Accepted types, ignoring case and parameters
+    for content_type in [
+        "application/json",
+        "Application/JSON; charset=utf-8",
+        "text/plain",
+    ] {
+        let response = connection.request(&post(content_type, "{}")).await;
+        assert!(response.ends_with("2 bytes"), "{}", content_type);
+    }
+    // Other types, or a body without one
+    for content_type in ["application/xml", "application/json-seq", "text", ""] {
+        let response = connection.request(&post(content_type, "{}")).await;
+        assert!(response.starts_with("HTTP/1.1 415 "), "{}", content_type);
+        assert!(response.contains("Accept: application/json, text/*\r\n"));
+    }
+    // No body and no type to check
+    let response = connection.request(&post("", "")).await;
+    assert!(response.ends_with("0 bytes"));
+    let response = connection.request("DELETE /users HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 204 "));
+}
+
+#[tokio::test]
+async fn test_max_in_flight() {
+    use request::Method;
+    use response::Response;
+    use server::{self, Overflow, ServerActions};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+    let running = |overflow: Overflow| {
+        let current = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&most);
+        let bound = server::ephemeral(move |server| {
+            server.max_in_flight(2, overflow);
+            server.add_async_route(Method::Get, "/work", move |_req| {
+                let (current, most) = (Arc::clone(&current), Arc::clone(&most));
+                async move {
+                    let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    current.fetch_sub(1, Ordering::SeqCst);
+                    Response::html("done")
+                }
+            });
+        })
+        .unwrap();
+        (bound, seen)
+    };
+    // Five requests at once, on as many connections
+    let spawn_requests = |url: String| -> Vec<tokio::task::JoinHandle<Response>> {
+        (0..5)
+            .map(|_| {
+                let url = url.clone();
+                tokio::spawn(async move { client::get(&url).await.unwrap() })
+            })
+            .collect()
+    };
+
+    let (bound, most) = running(Overflow::Wait);
+    let requests = spawn_requests(bound.url("/work"));
+    for request in requests {
+        assert_eq!(request.await.unwrap().status(), 200);
+    }
+    assert_eq!(most.load(Ordering::SeqCst), 2);
+    bound.shutdown().await.unwrap();
+
+    let retry_after = Duration::from_millis(1500);
+    let (bound, most) = running(Overflow::Reject { retry_after });
+    let requests = spawn_requests(bound.url("/work"));
+    let mut rejected = 0;
+    for request in requests {
+        let response = request.await.unwrap();
+        if response.status() == 503 {
+            assert_eq!(response.header("Retry-After"), Some("2"));
+            rejected += 1;
+        }
+    }
+    assert_eq!(rejected, 3);
+    assert_eq!(most.load(Ordering::SeqCst), 2);
+    bound.shutdown().await.unwrap();
+}
+
+#[test]
+fn test_check_preconditions() {
+    use std::time::{Duration, UNIX_EPOCH};
+    let check = |headers: &str, etag: Option<&str>, modified: Option<u64>| {
+        let request = testing::request(&format!("PUT /doc HTTP/1.1\r\n{}\r\n", headers));
+        let modified = modified.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        request
+            .check_preconditions(etag, modified)
+            .map(|response| response.status())
+    };
+    let current = Some("\"v3\"");
+    assert_eq!(check("", current, None), None);
+    assert_eq!(check("If-Match: \"v3\"\r\n", current, None), None);
+    assert_eq!(check("If-Match: \"v1\", \"v3\"\r\n", current, None), None);
+    assert_eq!(check("If-Match: \"v2\"\r\n", current, None), Some(412));
+    assert_eq!(check("If-Match: W/\"v3\"\r\n", current, None), Some(412));
+    // `*` only asks for the resource to exist
+    assert_eq!(check("If-Match: *\r\n", current, None), None);
+    assert_eq!(check("If-Match: *\r\n", None, None), Some(412));
+    assert_eq!(check("If-Match: \"v3\"\r\n", None, None), Some(412));
+
+    // 946684800 seconds after the epoch
+    let since = "If-Unmodified-Since: Sat, 01 Jan 2000 00:00:00 GMT\r\n";
+    assert_eq!(check(since, current, Some(946_684_800)), None);
+    assert_eq!(check(since, current, Some(946_684_799)), None);
+    assert_eq!(check(since, current, Some(946_684_801)), Some(412));
+    assert_eq!(check(since, current, None), None);
+    assert_eq!(
+        check("If-Unmodified-Since: soon\r\n", current, Some(1)),
+        None
+    );
+    // `If-Match` wins when both are sent
+    let both = format!("If-Match: \"v3\"\r\n{}", since);
+    assert_eq!(check(&both, current, Some(946_684_801)), None);
+}
+
+#[tokio::test]
+async fn test_server_timing() {
+    use middleware::Middleware;
+    use request::{Method, Request};
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    struct Auth;
+    impl Middleware for Auth {
+        fn before(&self, request: &Request) -> Option<Response> {
+            if request.path() == "/report" {
+                request.timing().record("auth", Duration::from_micros(1500));
+            }
+            None
+        }
+    }
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.middleware(Auth);
+    server.add_async_route(Method::Get, "/report", |req| {
+        let timing = req.timing();
+        async move {
+            tokio::task::yield_now().await;
+            timing.record("db", Duration::from_millis(53));
+            timing.record_described("render", Duration::from_micros(12_250), "Te\"mp\r\nlate");
+            Response::html("report")
+        }
+    });
+    server.add_route(Method::Get, "/plain", |_req| Response::html("plain"));
+    let mut connection = testing::connect(server).await;
+    let response = connection.request("GET /report HTTP/1.1\r\n\r\n").await;
+    assert!(response.contains(
+        "Server-Timing: auth;dur=1.5, db;dur=53, render;desc=\"Te\\\"mplate\";dur=12.25\r\n"
+    ));
+    let response = connection.request("GET /plain HTTP/1.1\r\n\r\n").await;
+    assert!(!response.contains("Server-Timing"));
+}
+
+#[tokio::test]
+async fn test_spa_fallback() {
+    use server::{self, ServerActions};
+    let dir = testing::temp_dir("spa_fallback");
+    std::fs::create_dir_all(dir.join("dist/assets")).unwrap();
+    std::fs::write(dir.join("dist/index.html"), "<div id=app></div>").unwrap();
+    std::fs::write(dir.join("dist/app.js"), "run()").unwrap();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.serve_dir("/plain", dir.join("dist"));
+    server.serve_spa("/", dir.join("dist"));
+    let mut connection = testing::connect(server).await;
+
+    for path in ["/", "/users/42", "/assets/"] {
+        let app = connection
+            .request(&format!("GET {} HTTP/1.1\r\n\r\n", path))
+            .await;
+        assert!(app.starts_with("HTTP/1.1 200 "), "{}", app);
+        assert!(app.ends_with("\r\n\r\n<div id=app></div>"), "{}", app);
+    }
+    let asset = connection.request("GET /app.js HTTP/1.1\r\n\r\n").await;
+    assert!(asset.ends_with("\r\n\r\nrun()"), "{}", asset);
+    let missing = connection.request("GET /missing.js HTTP/1.1\r\n\r\n").await;
+    assert!(missing.starts_with("HTTP/1.1 404 "), "{}", missing);
+    let plain = connection
+        .request("GET /plain/users/42 HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(plain.starts_with("HTTP/1.1 404 "), "{}", plain);
+}
+
+#[test]
+fn test_server_from_env() {
+    use error::ServerError;
+    use server::{self, ServerInfo};
+    // The environment of the process is shared with the other tests, it's left alone
+    let from = |vars: &[(&str, &str)]| {
+        server::from_vars(|name| {
+            vars.iter()
+                .find(|(variable, _)| *variable == name)
+                .map(|(_, value)| value.to_string())
+        })
+    };
+    let server = from(&[("PORT", "")]).unwrap();
+    assert_eq!((server.ip(), server.port()), ("0.0.0.0", 8080));
+
+    let server = from(&[("HOST", "::1"), ("PORT", "3000")]).unwrap();
+    assert_eq!((server.ip(), server.port()), ("::1", 3000));
+
+    let vars = [
+        ("BIND_ADDR", "127.0.0.1:9000"),
+        ("HOST", "::1"),
+        ("PORT", "3000"),
+    ];
+    let server = from(&vars).unwrap();
+    assert_eq!((server.ip(), server.port()), ("127.0.0.1", 9000));
+
+    let error = from(&[("HOST", "::1"), ("PORT", "http")]).err().unwrap();
+    assert!(
+        matches!(&error, ServerError::Env { variable, value } if variable == "PORT" && value == "http")
+    );
+    assert_eq!(error.to_string(), "invalid value \"http\" of PORT");
+}
+
+#[tokio::test]
+async fn test_max_per_ip() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    let bound = server::ephemeral(|server| {
+        server.add_route(Method::Get, "/", |_req| Response::html("hi"));
+        server.add_route(Method::Get, "/panic", |_req| -> Response { panic!("boom") });
+        server.max_per_ip(2);
+    })
+    .unwrap();
+    let open =
+        || async { testing::Connection::new(TcpStream::connect(bound.address()).await.unwrap()) };
+    let mut first = open().await;
+    let mut second = open().await;
+    let ok = first.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(ok.starts_with("HTTP/1.1 200 "), "{}", ok);
+    let failed = second.request("GET /panic HTTP/1.1\r\n\r\n").await;
+    assert!(failed.starts_with("HTTP/1.1 500 "), "{}", failed);
+    assert_eq!(open().await.read_to_end().await, "");
+
+    // The slot of the connection whose handler panicked is freed once it closes
+    drop(second);
+    let mut third = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let mut third = open().await;
+            // A rejected connection is closed right away, an accepted one waits for a request
+            let wait = Duration::from_millis(50);
+            if tokio::time::timeout(wait, third.read_to_end())
+                .await
+                .is_err()
+            {
+                return third;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    let served = third.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(served.starts_with("HTTP/1.1 200 "), "{}", served);
+    bound.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_wire_bytes() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| {
+        Response::html("hi").with_header("X-Id", "7")
+    });
+    server.add_route(Method::Delete, "/", |_req| Response::new(204));
+    server.add_route(Method::Get, "/stream", |_req| {
+        let (sender, chunks) = tokio::sync::mpsc::channel(2);
+        sender.try_send(b"ab".to_vec()).unwrap();
+        sender.try_send(b"cde".to_vec()).unwrap();
+        Response::chunked(chunks, "text/plain")
+    });
+
+    assert_eq!(
+        testing::wire(&server, "GET / HTTP/1.1\r\n\r\n").await,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nX-Id: 7\r\nContent-Length: 2\r\n\r\nhi"
+    );
+    assert_eq!(
+        testing::wire(&server, "DELETE / HTTP/1.1\r\n\r\n").await,
+        "HTTP/1.1 204 No Content\r\n\r\n"
+    );
+    assert_eq!(
+        testing::wire(&server, "GET /stream HTTP/1.1\r\n\r\n").await,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n\
+         2\r\nab\r\n3\r\ncde\r\n0\r\n\r\n"
+    );
+}
+
+#[tokio::test]
+async fn test_empty_lines_before_request() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    assert_eq!(testing::request("\r\nGET /a HTTP/1.1\r\n\r\n").path(), "/a");
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/", |_req| Response::html("pong"));
+    let mut connection = testing::connect(server).await;
+    let first = connection.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(first.ends_with("\r\n\r\npong"), "{}", first);
+    // A ping on its own is ignored, nothing is answered to it
+    connection.send("\r\n").await;
+    let pinged = connection
+        .request("\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await;
+    assert!(pinged.starts_with("HTTP/1.1 200 "), "{}", pinged);
+    assert!(pinged.ends_with("\r\n\r\npong"), "{}", pinged);
+    assert_eq!(connection.read_to_end().await, "");
+}
+
+#[tokio::test]
+async fn test_vhosts() {
+    use request::Method;
+    use response::Response;
+    use router::Router;
+    use server::{self, ServerActions};
+    let site = |name: &'static str| {
+        let mut router = Router::new();
+        router.add_route(Method::Get, "/", move |_req| Response::html(name));
+        router
+    };
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.vhost("API.example.com", site("api"));
+    server.vhost("[::1]", site("ipv6"));
+    server.add_route(Method::Get, "/", |_req| Response::html("default"));
+    let mut connection = testing::connect(server).await;
+    for (host, body) in [
+        ("api.example.com", "api"),
+        ("Api.Example.COM:8080", "api"),
+        ("[::1]:80", "ipv6"),
+        ("www.example.com", "default"),
+    ] {
+        let answer = connection
+            .request(&format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host))
+            .await;
+        assert!(answer.ends_with(&format!("\r\n\r\n{}", body)), "{}", answer);
+    }
+    let missing = connection
+        .request("GET /users HTTP/1.1\r\nHost: api.example.com\r\n\r\n")
+        .await;
+    assert!(missing.starts_with("HTTP/1.1 404 "), "{}", missing);
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.vhost("api.example.com", site("api"));
+    server.add_route(Method::Get, "/", |_req| Response::html("default"));
+    server.unknown_host(|_req| Response::new(421));
+    let mut connection = testing::connect(server).await;
+    let unknown = connection
+        .request("GET / HTTP/1.1\r\nHost: other.example.com\r\n\r\n")
+        .await;
+    assert!(unknown.starts_with("HTTP/1.1 421 "), "{}", unknown);
+    let known = connection
+        .request("GET / HTTP/1.1\r\nHost: api.example.com\r\n\r\n")
+        .await;
+    assert!(known.ends_with("\r\n\r\napi"), "{}", known);
+}
+
+#[tokio::test]
+async fn test_max_connection_lifetime() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    let new_server = || {
+        let mut server = server::new("127.0.0.1:0".to_string());
+        server.add_route(Method::Get, "/", |_req| Response::html("hi"));
+        server.add_async_route(Method::Get, "/slow", |_req| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Response::html("slow")
+        });
+        server.max_connection_lifetime(Duration::from_millis(200));
+        server
+    };
+
+    let mut connection = testing::connect(new_server()).await;
+    let fresh = connection.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(!fresh.contains("Connection: close"), "{}", fresh);
+    // Idle past its lifetime, the connection is closed without waiting for a request
+    let closed = tokio::time::timeout(Duration::from_secs(2), connection.read_to_end());
+    assert_eq!(closed.await.unwrap(), "");
+
+    let mut connection = testing::connect(new_server()).await;
+    let last = connection.request("GET /slow HTTP/1.1\r\n\r\n").await;
+    assert!(last.contains("Connection: close\r\n"), "{}", last);
+    assert!(last.ends_with("\r\n\r\nslow"), "{}", last);
+    assert_eq!(connection.read_to_end().await, "");
+}
+
+#[tokio::test]
+async fn test_log_level() {
+    use logging::Level;
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.log_level(Level::Info);
+    server.add_route(Method::Get, "/", |_req| {
+        Response::html(format!(
+            "{} {} {}",
+            logging::enabled(Level::Error),
+            logging::enabled(Level::Info),
+            logging::enabled(Level::Debug)
+        ))
+    });
+    // Only the requests of the server print less, nothing else is affected
+    assert!(logging::enabled(Level::Debug));
+    let mut connection = testing::connect(server).await;
+    let answer = connection.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(answer.ends_with("true true false"), "{}", answer);
+    assert!(logging::enabled(Level::Debug));
+
+    logging::scope(Level::Off, async {
+        assert!(!logging::enabled(Level::Error));
+        assert!(!logging::enabled(Level::Off));
+    })
+    .await;
+}
+
+#[test]
+fn test_forwarded_header() {
+    let forwarded = |value: &str| {
+        let mut req = testing::request(&format!(
+            "GET / HTTP/1.1\r\nForwarded: {}\r\nX-Forwarded-For: 198.51.100.1\r\n\r\n",
+            value
+        ));
+        req.remote_addr = Some("10.0.0.1:5000".parse().unwrap());
+        req.trust_proxy = true;
+        req
+    };
+    let req = forwarded("for=192.0.2.60;proto=HTTPS;host=example.com, for=10.0.0.2");
+    assert_eq!(req.client_ip(), Some("192.0.2.60".parse().unwrap()));
+    assert_eq!(req.forwarded_proto().as_deref(), Some("https"));
+    assert_eq!(req.forwarded_host().as_deref(), Some("example.com"));
+    assert!(req.is_https());
+
+    let req = forwarded("For=\"[2001:db8:cafe::17]:4711\"; host=\"a\\\"b;c\"");
+    assert_eq!(req.client_ip(), Some("2001:db8:cafe::17".parse().unwrap()));
+    assert_eq!(req.forwarded_host().as_deref(), Some("a\"b;c"));
+    assert_eq!(req.forwarded_proto(), None);
+    assert!(!req.is_https());
+
+    // Unknown or obfuscated nodes fall back to X-Forwarded-For
+    let req = forwarded("for=unknown;proto=http");
+    assert_eq!(req.client_ip(), Some("198.51.100.1".parse().unwrap()));
+    let mut req = forwarded("for=192.0.2.60;proto=https");
+    req.trust_proxy = false;
+    assert_eq!(req.client_ip(), Some("10.0.0.1".parse().unwrap()));
+    assert_eq!(req.forwarded_proto(), None);
+}
+
+#[tokio::test]
+async fn test_manual_clock() {
+    use clock::ManualClock;
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+    let clock = ManualClock::new();
+    let calls = Arc::new(AtomicU64::new(0));
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.set_clock(clock.clone());
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Get, "/report", move |_req| {
+            Response::html(format!(
+                "report {}",
+                counted.fetch_add(1, Ordering::Relaxed) + 1
+            ))
+        })
+        .clock(clock.clone())
+        .cache_for(Duration::from_secs(60));
+    let counted = Arc::clone(&calls);
+    server
+        .add_route(Method::Post, "/orders", move |_req| {
+            Response::html(format!(
+                "order {}",
+                counted.fetch_add(1, Ordering::Relaxed) + 1
+            ))
+        })
+        .clock(clock.clone())
+        .idempotent(Duration::from_secs(60));
+    let connections = server.connections();
+    let mut connection = testing::connect(server).await;
+
+    let report = "GET /report HTTP/1.1\r\n\r\n";
+    let order = "POST /orders HTTP/1.1\r\nIdempotency-Key: a\r\nContent-Length: 0\r\n\r\n";
+    assert!(connection.request(report).await.ends_with("report 1"));
+    assert!(connection.request(order).await.ends_with("order 2"));
+    clock.advance(Duration::from_secs(59));
+    assert!(connection.request(report).await.ends_with("report 1"));
+    assert!(connection.request(order).await.ends_with("order 2"));
+    assert_eq!(connections.list()[0].idle, Duration::ZERO);
+
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(connections.list()[0].idle, Duration::from_secs(61));
+    assert!(connection.request(report).await.ends_with("report 3"));
+    assert!(connection.request(order).await.ends_with("order 4"));
+}
+
+#[tokio::test]
+async fn test_overload_response() {
+    use request::Method;
+    use response::Response;
+    use server::{self, Overflow, ServerActions};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    let bound = server::ephemeral(|server| {
+        server.add_async_route(Method::Get, "/work", |_req| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Response::html("done")
+        });
+        server.max_in_flight(1, Overflow::Wait);
+        server.max_per_ip(2);
+        server.overload_response(Duration::from_millis(2500));
+    })
+    .unwrap();
+    let url = bound.url("/work");
+    let first = tokio::spawn(async move { client::get(&url).await.unwrap() });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let shed = client::get(&bound.url("/work")).await.unwrap();
+    assert_eq!(shed.status(), 503);
+    assert_eq!(shed.header("Retry-After"), Some("3"));
+    assert_eq!(first.await.unwrap().status(), 200);
+
+    // Past the connections allowed per IP the client is told why it's closed
+    let open =
+        || async { testing::Connection::new(TcpStream::connect(bound.address()).await.unwrap()) };
+    let mut kept = vec![open().await, open().await];
+    for connection in &mut kept {
+        connection.send("GET /missing HTTP/1.1\r\n\r\n").await;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let refused = open().await.read_to_end().await;
+    assert!(refused.starts_with("HTTP/1.1 503 "), "{}", refused);
+    assert!(refused.contains("Retry-After: 3\r\n"), "{}", refused);
+    assert!(refused.contains("Connection: close\r\n"), "{}", refused);
+    drop(kept);
+    bound.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_bare_lf_line_endings() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let req = testing::request("POST /a HTTP/1.1\nHost: x\nContent-Length: 2\n\nhi");
+    assert_eq!((req.path(), req.header("Host")), ("/a", Some("x")));
+    assert_eq!(req.body(), b"hi");
+    // Both endings may be mixed, a bare LF ends a line wherever it is
+    let req = testing::request("GET / HTTP/1.1\r\nX-A: a\nX-B: b\r\n\n");
+    assert_eq!(
+        (req.header("X-A"), req.header("X-B")),
+        (Some("a"), Some("b"))
+    );
+
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Post, "/echo", |req| {
+        Response::new(200).with_body(req.body().to_vec())
+    });
+    let mut connection = testing::connect(server).await;
+    let echoed = connection
+        .request("POST /echo HTTP/1.1\nContent-Length: 4\n\nbody")
+        .await;
+    assert!(echoed.ends_with("\r\n\r\nbody"), "{}", echoed);
+    let strict = connection
+        .request("POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nok")
+        .await;
+    assert!(strict.ends_with("\r\n\r\nok"), "{}", strict);
+}
+
+#[tokio::test]
+async fn test_scoped_listener_routes() {
+    use request::Method;
+    use response::Response;
+    use router::Router;
+    use server::{self, ServerActions};
+    use tokio::net::TcpStream;
+    let mut admin = Router::new();
+    admin.add_route(Method::Post, "/reload", |_req| Response::new(204));
+    admin.add_route(Method::Get, "/", |_req| Response::html("admin"));
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_listener("127.0.0.1:0").scoped_routes(admin);
+    server.add_route(Method::Get, "/", |_req| Response::html("public"));
+    server.add_route(Method::Get, "/health", |_req| Response::html("ok"));
+    let mut incoming = server.incoming().await.unwrap();
+    let addresses = incoming.local_addrs().unwrap();
+    assert_eq!(addresses.len(), 2);
+    tokio::spawn(async move {
+        while let Ok((socket, _, listener)) = incoming.accept_on().await {
+            tokio::spawn(incoming.serve_on(socket, listener));
+        }
+    });
+
+    let mut public = testing::Connection::new(TcpStream::connect(addresses[0]).await.unwrap());
+    let mut internal = testing::Connection::new(TcpStream::connect(addresses[1]).await.unwrap());
+    let reload = "POST /reload HTTP/1.1\r\nContent-Length: 0\r\n\r\n";
+    assert!(public.request(reload).await.starts_with("HTTP/1.1 404"));
+    assert!(internal.request(reload).await.starts_with("HTTP/1.1 204"));
+    // Scoped routes come first, shared ones answer the rest on every listener
+    let root = "GET / HTTP/1.1\r\n\r\n";
+    assert!(public.request(root).await.ends_with("\r\n\r\npublic"));
+    assert!(internal.request(root).await.ends_with("\r\n\r\nadmin"));
+    let health = "GET /health HTTP/1.1\r\n\r\n";
+    assert!(internal.request(health).await.ends_with("\r\n\r\nok"));
+}
+
+#[tokio::test]
+async fn test_unterminated_head_is_limited() {
+    use server::{self, ServerActions};
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.max_head_size(1024);
+    let mut connection = testing::connect(server).await;
+    connection.send("GET / HTTP/1.1\r\nX-Pad: ").await;
+    // The blank line ending the head never comes, the limit ends the wait for it
+    let padding = "a".repeat(64);
+    for _ in 0..16 {
+        connection.send(&padding).await;
+    }
+    let answer = connection.read_to_end().await;
+    assert!(answer.starts_with("HTTP/1.1 431"), "{}", answer);
+
+    let limits = request::Limits::default();
+    let mut head = format!("GET / HTTP/1.1\r\nX-Pad: {}", "a".repeat(16 * 1024)).into_bytes();
+    assert!(matches!(
+        request::take_request(&mut head, &limits),
+        Some(Err(request::ParseError::HeadTooLarge))
+    ));
+    let mut head = b"GET / HTTP/1.1\r\nX-Pad: a".to_vec();
+    assert!(request::take_request(&mut head, &limits).is_none());
+}
+
+#[tokio::test]
+async fn test_active_connections_after_force_close() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    let mut metrics = None;
+    let bound = server::ephemeral(|server| {
+        server.shutdown_grace(Duration::from_millis(50));
+        server.add_async_route(Method::Get, "/slow", |_req| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Response::html("late")
+        });
+        metrics = Some(server.metrics());
+    })
+    .unwrap();
+    let metrics = metrics.unwrap();
+    let mut connection =
+        testing::Connection::new(TcpStream::connect(bound.address()).await.unwrap());
+    connection.send("GET /slow HTTP/1.1\r\n\r\n").await;
+    while metrics.snapshot().active_connections == 0 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // The connection is aborted once the grace period is over, it's no longer counted
+    bound.shutdown().await.unwrap();
+    assert_eq!(metrics.snapshot().active_connections, 0);
+}
+
+#[tokio::test]
+async fn test_accept_filter_backoff() {
+    use server::{self, ServerActions};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+    use tokio::io::AsyncReadExt;
+    let seen = Arc::new(AtomicU64::new(0));
+    let counted = Arc::clone(&seen);
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.accept_filter(move |_addr| counted.fetch_add(1, Ordering::Relaxed) >= 40);
+    let mut incoming = server.incoming().await.unwrap();
+    let address = incoming.local_addr().unwrap();
+    let accepting = tokio::spawn(async move { incoming.accept().await.map(|_| ()) });
+
+    // Accepting pauses after runs of rejected connections, then lets the next one through
+    for _ in 0..40 {
+        let mut rejected = tokio::net::TcpStream::connect(address).await.unwrap();
+        let mut answer = vec![];
+        let _ = rejected.read_to_end(&mut answer).await;
+    }
+    let _allowed = tokio::net::TcpStream::connect(address).await.unwrap();
+    accepting.await.unwrap().unwrap();
+    assert_eq!(seen.load(Ordering::Relaxed), 41);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_systemd_fd() {
+    let vars = |pid: &'static str, fds: &'static str| {
+        move |name: &str| match name {
+            "LISTEN_PID" => Some(pid.to_string()),
+            "LISTEN_FDS" => Some(fds.to_string()),
+            _ => None,
+        }
+    };
+    assert_eq!(server::systemd_fd(vars("42", "1"), 42), Some(3));
+    // Variables meant for another process, like the parent of a child inheriting them
+    assert_eq!(server::systemd_fd(vars("42", "1"), 43), None);
+    assert_eq!(server::systemd_fd(vars("42", "0"), 42), None);
+    assert_eq!(server::systemd_fd(vars("x", "1"), 42), None);
+    assert_eq!(server::systemd_fd(|_| None, 42), None);
+    assert!(server::from_systemd().is_none());
+}
+
+#[tokio::test]
+async fn test_server_new_addr() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions, ServerInfo};
+    use std::net::SocketAddr;
+    let addr: SocketAddr = "[::1]:8080".parse().unwrap();
+    let server = server::new_addr(addr);
+    assert_eq!(
+        (server.address(), server.ip(), server.port()),
+        ("[::1]:8080", "::1", 8080)
+    );
+    let server = server::new_addr("10.0.0.1:443".parse().unwrap());
+    assert_eq!((server.ip(), server.port()), ("10.0.0.1", 443));
+
+    let mut server = server::new_addr("127.0.0.1:0".parse().unwrap());
+    server.add_route(Method::Get, "/", |_req| Response::html("parsed"));
+    let bound = server.bind().unwrap();
+    assert_ne!(bound.port(), 0);
+    assert_eq!(server.port(), i32::from(bound.port()));
+    tokio::spawn(server.try_start());
+    let response = client::get(&format!("http://{}/", bound)).await.unwrap();
+    assert_eq!(response.body(), b"parsed");
+}
+
+#[tokio::test]
+async fn test_connection_keep_alive() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let configure = || {
+        let mut server = server::new("127.0.0.1:0".to_string());
+        server.add_route(Method::Get, "/", |_req| Response::html("hi"));
+        server
+    };
+
+    // HTTP/1.1 defaults to keep-alive, nothing is said until the client closes
+    let mut connection = testing::connect(configure()).await;
+    let kept = connection.request("GET / HTTP/1.1\r\n\r\n").await;
+    assert!(!kept.contains("Connection:"), "{}", kept);
+    let closed = connection
+        .request("GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await;
+    assert!(closed.contains("Connection: close\r\n"));
+    assert_eq!(connection.read_to_end().await, "");
+
+    // HTTP/1.0 defaults to closing, `keep-alive` keeps the connection open
+    let mut connection = testing::connect(configure()).await;
+    let kept = connection
+        .request("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+        .await;
+    assert!(kept.contains("Connection: keep-alive\r\n"), "{}", kept);
+    // Responses are HTTP/1.1, closing is always said
+    let closed = connection.request("GET / HTTP/1.0\r\n\r\n").await;
+    assert!(closed.contains("Connection: close\r\n"), "{}", closed);
+    assert!(closed.ends_with("\r\n\r\nhi"));
+    assert_eq!(connection.read_to_end().await, "");
+}
+
+#[tokio::test]
+async fn test_proxy_pass() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let upstream = server::ephemeral(|server| {
+        server.add_route(Method::Get, "/api/x", |_req| Response::html("outside"));
+        server.add_route(Method::Get, "/base/api/x", |req| Response::html(req.path()));
+        server.add_route(Method::Post, "/base/api/echo", |req| {
+            Response::html(format!(
+                "{} {} {}",
+                req.path(),
+                req.header("X-Token").unwrap_or("-"),
+                String::from_utf8_lossy(req.body())
+            ))
+            .with_header("X-Upstream", "yes")
+        });
+    })
+    .unwrap();
+    // Nothing listens on the port of a listener that was dropped
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let down = format!("http://{}", closed.local_addr().unwrap());
+    drop(closed);
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_route(Method::Get, "/local", |_req| Response::html("local"));
+    server.proxy_pass("/api", &upstream.url("/base"));
+    server.proxy_pass("/down", &down);
+    let mut connection = testing::connect(server).await;
+
+    let forwarded = connection
+        .request("POST /api/echo?x=1 HTTP/1.1\r\nX-Token: abc\r\nContent-Length: 4\r\n\r\nping")
+        .await;
+    assert!(
+        forwarded.starts_with("HTTP/1.1 200 OK\r\n"),
+        "{}",
+        forwarded
+    );
+    assert!(forwarded.contains("X-Upstream: yes\r\n"));
+    assert!(
+        forwarded.ends_with("\r\n\r\n/base/api/echo?x=1 abc ping"),
+        "{}",
+        forwarded
+    );
+    // Dot segments are resolved before forwarding, they can't climb out of the base path
+    let climbing = connection
+        .request("GET /a/../../api/x HTTP/1.1\r\n\r\n")
+        .await;
+    assert!(climbing.ends_with("\r\n\r\n/base/api/x"), "{}", climbing);
+    let local = connection.request("GET /local HTTP/1.1\r\n\r\n").await;
+    assert!(local.ends_with("\r\n\r\nlocal"));
+    let unmatched = connection.request("GET /apis HTTP/1.1\r\n\r\n").await;
+    assert!(unmatched.starts_with("HTTP/1.1 404"));
+
+    let unreachable = connection.request("GET /down/x HTTP/1.1\r\n\r\n").await;
+    assert!(unreachable.starts_with("HTTP/1.1 502"), "{}", unreachable);
+    upstream.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_grace() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpStream;
+    let mut connections = None;
+    let bound = server::ephemeral(|server| {
+        server.shutdown_grace(Duration::from_millis(300));
+        server.add_async_route(Method::Get, "/slow", |_req| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Response::html("late")
+        });
+        server.add_async_route(Method::Get, "/quick", |_req| async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Response::html("in time")
+        });
+        connections = Some(server.connections());
+    })
+    .unwrap();
+    let connections = connections.unwrap();
+    let connect =
+        || async { testing::Connection::new(TcpStream::connect(bound.address()).await.unwrap()) };
+    let mut slow = connect().await;
+    slow.send("GET /slow HTTP/1.1\r\n\r\n").await;
+    let mut quick = connect().await;
+    quick.send("GET /quick HTTP/1.1\r\n\r\n").await;
+    while connections
+        .list()
+        .iter()
+        .filter(|connection| connection.busy)
+        .count()
+        < 2
+    {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    let started = Instant::now();
+    let shutting_down = tokio::spawn(bound.shutdown());
+    // A request finishing within the grace period is answered, the slow one is cut off
+    let answered = quick.read_to_end().await;
+    assert!(answered.ends_with("\r\n\r\nin time"), "{}", answered);
+    assert_eq!(slow.read_to_end().await, "");
+    shutting_down.await.unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+#[tokio::test]
+async fn test_response_from_reader() {
+    use request::Method;
+    use response::Response;
+    use server::{self, ServerActions};
+    let dir = testing::temp_dir("from_reader");
+    let path = dir.join("data.bin");
+    let content: Vec<u8> = (0..50_000u32).map(|i| b'a' + (i % 26) as u8).collect();
+    std::fs::write(&path, &content).unwrap();
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.add_async_route(Method::Get, "/data", move |_req| {
+        let path = path.clone();
+        async move {
+            let file = tokio::fs::File::open(&path).await.unwrap();
+            let length = file.metadata().await.unwrap().len();
+            Response::from_reader(file, length, "application/octet-stream")
+        }
     });
-    // Start listening
-    server.start().await;
+    let mut connection = testing::connect(server).await;
+
+    let head = connection.request("HEAD /data HTTP/1.1\r\n\r\n").await;
+    assert!(head.contains("Content-Length: 50000\r\n"), "{}", head);
+    assert!(head.ends_with("\r\n\r\n"));
+    // The connection stays usable after a streamed body, the whole file went through
+    connection
+        .send("GET /data HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await;
+    let answer = connection.read_to_end().await;
+    assert!(answer.contains("Content-Type: application/octet-stream\r\n"));
+    let (_, body) = answer.split_once("\r\n\r\n").unwrap();
+    assert_eq!(body.as_bytes(), &content[..]);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_on_error() {
+    use error::{ErrorAction, ServerError};
+    use server::ServerActions;
+    use std::sync::{Arc, Mutex};
+    let accept_error = || ServerError::Accept(std::io::Error::other("too many open files"));
+    // Without a callback the error is printed and the server keeps accepting
+    let server = server::new("127.0.0.1:0".to_string());
+    assert_eq!(server.report(&accept_error()), ErrorAction::Continue);
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    for action in [ErrorAction::Continue, ErrorAction::Shutdown] {
+        let mut server = server::new("127.0.0.1:0".to_string());
+        let seen = seen.clone();
+        server.on_error(move |error| {
+            seen.lock().unwrap().push(error.to_string());
+            action
+        });
+        assert_eq!(server.report(&accept_error()), action);
+    }
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert!(seen
+        .iter()
+        .all(|error| error.contains("too many open files")));
+}
+
+#[test]
+#[should_panic(expected = "The idle reaper needs a non-zero interval")]
+fn test_idle_reaper_zero_interval() {
+    use server::ServerActions;
+    use std::time::Duration;
+    let mut server = server::new("127.0.0.1:0".to_string());
+    server.idle_reaper(Duration::ZERO, Duration::from_secs(60));
 }
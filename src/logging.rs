@@ -0,0 +1,60 @@
+//! Messages the server prints, filtered by `ServerActions::log_level`.
+
+use std::{fmt, future::Future};
+
+/// How much the server prints, each level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Nothing is printed.
+    Off,
+    /// Failures, like errors of the listener, panics and unreachable upstreams.
+    Error,
+    /// Life of the server, like starting, shutting down and closing idle connections.
+    Info,
+    /// Every connection and the way it ended, the default.
+    Debug,
+}
+
+tokio::task_local! {
+    /// Level of the server running the current task, everything is printed outside of one.
+    static LEVEL: Level;
+}
+
+/// Runs `future` printing only the messages of `level` and the levels before it.
+pub(crate) async fn scope<F: Future>(level: Level, future: F) -> F::Output {
+    LEVEL.scope(level, future).await
+}
+
+/// Runs `f` printing only the messages of `level` and the levels before it.
+pub(crate) fn sync_scope<R>(level: Level, f: impl FnOnce() -> R) -> R {
+    LEVEL.sync_scope(level, f)
+}
+
+/// Returns whether messages of `level` are printed.
+pub(crate) fn enabled(level: Level) -> bool {
+    let current = LEVEL.try_with(|level| *level).unwrap_or(Level::Debug);
+    level != Level::Off && level <= current
+}
+
+/// Prints `message` if `level` is enabled, errors to stderr and the rest to stdout.
+pub(crate) fn log(level: Level, message: fmt::Arguments<'_>) {
+    if !enabled(level) {
+        return;
+    }
+    match level {
+        Level::Error => eprintln!("{}", message),
+        _ => println!("{}", message),
+    }
+}
+
+pub(crate) fn error(message: fmt::Arguments<'_>) {
+    log(Level::Error, message);
+}
+
+pub(crate) fn info(message: fmt::Arguments<'_>) {
+    log(Level::Info, message);
+}
+
+pub(crate) fn debug(message: fmt::Arguments<'_>) {
+    log(Level::Debug, message);
+}
@@ -0,0 +1,49 @@
+//! Macros to declare routes.
+
+/// Registers several routes at once on a server or a router.
+///
+/// Each route is a method, a path pattern and a handler. Methods are written in uppercase, like
+/// in the request line; an unknown method is a compile error.
+///
+/// # Example
+/// ```
+/// use mini_rest::{request::Request, response::Response, routes, server::{self, ServerActions}};
+/// fn index(_req: &Request) -> Response {
+///     Response::html("<h1>Hello, world!</h1>")
+/// }
+///
+/// fn user(req: &Request) -> Response {
+///     Response::html(format!("user {}", req.param("id").unwrap()))
+/// }
+///
+/// let mut server = server::new("127.0.0.1:8080".to_string());
+/// routes! { server =>
+///     GET "/" => index,
+///     GET "/users/:id" => user,
+///     DELETE "/users/:id" => |_req| Response::new(204),
+/// }
+/// ```
+///
+/// ```compile_fail
+/// use mini_rest::{response::Response, routes, router::Router};
+/// let mut router = Router::new();
+/// routes! { router => FETCH "/" => |_req| Response::new(200) }
+/// ```
+#[macro_export]
+macro_rules! routes {
+    ($target:expr => $($method:ident $path:literal => $handler:expr),* $(,)?) => {
+        $(
+            $target.add_route($crate::routes!(@method $method), $path, $handler);
+        )*
+    };
+    (@method GET) => { $crate::request::Method::Get };
+    (@method HEAD) => { $crate::request::Method::Head };
+    (@method POST) => { $crate::request::Method::Post };
+    (@method PUT) => { $crate::request::Method::Put };
+    (@method PATCH) => { $crate::request::Method::Patch };
+    (@method DELETE) => { $crate::request::Method::Delete };
+    (@method OPTIONS) => { $crate::request::Method::Options };
+    (@method $other:ident) => {
+        compile_error!(concat!("unknown HTTP method `", stringify!($other), "`"))
+    };
+}
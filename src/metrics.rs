@@ -0,0 +1,264 @@
+//! Counters describing the traffic of a server.
+
+use crate::request::Method;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Counters updated by the connections of a server.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) requests: AtomicU64,
+    pub(crate) active_connections: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    /// Read buffers allocated for connections, the ones reused from the buffer pool aside.
+    pub(crate) read_buffers: AtomicU64,
+    /// Statistics of every route that answered a request, by method and pattern.
+    routes: Mutex<Vec<(String, Arc<RouteStats>)>>,
+}
+
+/// Counts a connection in `active_connections` until it's dropped, an aborted task included.
+pub(crate) struct ActiveConnection<'a> {
+    counters: &'a Counters,
+}
+
+impl ActiveConnection<'_> {
+    pub(crate) fn new(counters: &Counters) -> ActiveConnection<'_> {
+        counters.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection { counters }
+    }
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.counters
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Upper bounds of the latency buckets of `RouteMetrics`, slower requests go in a last one.
+pub const LATENCY_BUCKETS: [Duration; 7] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+/// Statistics of a route, updated without locking by the requests it answers.
+#[derive(Debug, Default)]
+pub(crate) struct RouteStats {
+    /// Set once the route is listed in the counters of the server.
+    registered: AtomicBool,
+    total_micros: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl RouteStats {
+    /// Counts a request the route answered in `elapsed`.
+    ///
+    /// The first one lists the route in `counters`, under the name `key` returns.
+    pub(crate) fn record(
+        self: &Arc<Self>,
+        counters: &Counters,
+        key: impl FnOnce() -> String,
+        elapsed: Duration,
+    ) {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            counters
+                .routes
+                .lock()
+                .unwrap()
+                .push((key(), Arc::clone(self)));
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+/// Values of the counters of a server at some point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Requests answered since the server started.
+    pub requests: u64,
+    /// Connections currently open.
+    pub active_connections: u64,
+    /// Bytes read from clients, as sent on the wire.
+    pub bytes_received: u64,
+    /// Bytes written to clients, as sent on the wire.
+    pub bytes_sent: u64,
+}
+
+/// Requests answered by a route and how long its handler took.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RouteMetrics {
+    pub requests: u64,
+    /// Time spent in the handler over every request.
+    pub total_time: Duration,
+    /// Requests by latency: `buckets[i]` counts the ones answered within
+    /// `LATENCY_BUCKETS[i]` but not the previous bound, the last one the slower ones.
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+/// Handle to read the metrics of a server while it runs.
+#[derive(Debug, Clone)]
+pub struct MetricsHandle {
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl MetricsHandle {
+    /// Returns the current value of every counter.
+    pub fn snapshot(&self) -> Metrics {
+        let counters = &self.counters;
+        Metrics {
+            requests: counters.requests.load(Ordering::Relaxed),
+            active_connections: counters.active_connections.load(Ordering::Relaxed),
+            bytes_received: counters.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the statistics of the routes that answered a request, keyed by method and
+    /// pattern like `GET /users/:id`.
+    pub fn routes(&self) -> HashMap<String, RouteMetrics> {
+        let mut routes: HashMap<String, RouteMetrics> = HashMap::new();
+        // A replaced router lists its routes again, their numbers add up
+        for (key, stats) in self.counters.routes.lock().unwrap().iter() {
+            let metrics = routes.entry(key.clone()).or_default();
+            for (count, bucket) in metrics.buckets.iter_mut().zip(&stats.buckets) {
+                let requests = bucket.load(Ordering::Relaxed);
+                *count += requests;
+                metrics.requests += requests;
+            }
+            metrics.total_time += Duration::from_micros(stats.total_micros.load(Ordering::Relaxed));
+        }
+        routes
+    }
+}
+
+/// Last requests answered by a server, kept while `capacity` isn't zero.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    pub(crate) capacity: AtomicUsize,
+    entries: Mutex<VecDeque<RecordedRequest>>,
+}
+
+/// Request kept by `ServerActions::record_requests`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub method: Method,
+    /// Request target as sent by the client, query string included.
+    pub path: String,
+    /// Status of the response answering it.
+    pub status: u16,
+    /// When the response was ready.
+    pub time: SystemTime,
+}
+
+impl History {
+    /// Keeps a request, forgetting the oldest one when full.
+    pub(crate) fn record(&self, method: &Method, path: &str, status: u16) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let recorded = RecordedRequest {
+            method: method.clone(),
+            path: path.to_string(),
+            status,
+            time: SystemTime::now(),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        while entries.len() >= capacity {
+            entries.pop_front();
+        }
+        entries.push_back(recorded);
+    }
+
+    pub(crate) fn list(&self) -> Vec<RecordedRequest> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Handle to read the last requests answered by a server while it runs.
+#[derive(Debug, Clone)]
+pub struct RecentRequests {
+    pub(crate) history: Arc<History>,
+}
+
+impl RecentRequests {
+    /// Returns the recorded requests, oldest first.
+    pub fn list(&self) -> Vec<RecordedRequest> {
+        self.history.list()
+    }
+}
+
+/// Socket counting every byte read from and written to it.
+pub(crate) struct Metered<S> {
+    inner: S,
+    counters: Arc<Counters>,
+}
+
+impl<S> Metered<S> {
+    pub(crate) fn new(inner: S, counters: Arc<Counters>) -> Metered<S> {
+        Metered { inner, counters }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.counters
+            .bytes_received
+            .fetch_add(read, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.counters
+                .bytes_sent
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
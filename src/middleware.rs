@@ -0,0 +1,189 @@
+//! Code running around the routes of a server for every request.
+
+use crate::{request::Request, response::Response};
+
+/// Hooks called before and after a request is routed.
+///
+/// Both hooks do nothing by default, so a middleware only implements the ones it needs.
+///
+/// # Example
+/// ```
+/// use mini_rest::{middleware::Middleware, request::Request, response::Response};
+/// struct PoweredBy;
+///
+/// impl Middleware for PoweredBy {
+///     fn after(&self, _request: &Request, response: Response) -> Response {
+///         response.with_header("X-Powered-By", "mini_rest")
+///     }
+/// }
+/// ```
+pub trait Middleware: Send + Sync {
+    /// Answers the request right away, instead of routing it, by returning a response.
+    fn before(&self, _request: &Request) -> Option<Response> {
+        None
+    }
+
+    /// Changes the response sent for a request.
+    fn after(&self, _request: &Request, response: Response) -> Response {
+        response
+    }
+}
+
+/// Returns a middleware compressing responses with brotli or gzip, whichever the client's
+/// `Accept-Encoding` prefers.
+///
+/// # Example
+/// ```
+/// use mini_rest::{middleware, server::{self, ServerActions}};
+/// let mut server = server::new("127.0.0.1:8080".to_string());
+/// server.middleware(middleware::compression().min_size(1024));
+/// ```
+#[cfg(feature = "compression")]
+pub fn compression() -> crate::compression::Compression {
+    crate::compression::Compression::new()
+}
+
+/// Middleware sending every request to the same path over HTTPS.
+pub struct RedirectToHttps {
+    port: u16,
+}
+
+/// Returns a middleware answering every request with a `301 Moved Permanently` to the same
+/// path on `https://host:port`, the host being the one of the `Host` header.
+///
+/// It belongs on the plain HTTP server only: added to the one serving HTTPS every request would
+/// be redirected to itself. Requests without a `Host` header get a `400 Bad Request`.
+///
+/// # Example
+/// ```
+/// use mini_rest::{middleware, server::{self, ServerActions}};
+/// let mut server = server::new("0.0.0.0:80".to_string());
+/// server.middleware(middleware::redirect_to_https(443));
+/// ```
+pub fn redirect_to_https(port: u16) -> RedirectToHttps {
+    RedirectToHttps { port }
+}
+
+impl Middleware for RedirectToHttps {
+    fn before(&self, request: &Request) -> Option<Response> {
+        let host = match request.header("Host").map(strip_port) {
+            Some(host) if !host.is_empty() => host,
+            _ => return Some(Response::new(400)),
+        };
+        let location = match self.port {
+            443 => format!("https://{}{}", host, request.path()),
+            port => format!("https://{}:{}{}", host, port, request.path()),
+        };
+        Some(Response::new(301).with_header("Location", &location))
+    }
+}
+
+/// Middleware adding a baseline of security headers to every response.
+pub struct SecureHeaders {
+    headers: Vec<(&'static str, String)>,
+    /// Only sent over HTTPS, the header means nothing on plain HTTP.
+    strict_transport_security: Option<String>,
+}
+
+/// Returns a middleware adding common security headers to the responses that don't set them.
+///
+/// By default every response gets `X-Content-Type-Options: nosniff`, `X-Frame-Options: DENY`,
+/// `Referrer-Policy: strict-origin-when-cross-origin` and
+/// `Content-Security-Policy: default-src 'self'`. Responses to requests made over HTTPS, see
+/// `Request::is_https`, also get `Strict-Transport-Security: max-age=31536000;
+/// includeSubDomains`. Each value can be replaced or the header left out.
+///
+/// # Example
+/// ```
+/// use mini_rest::{middleware, server::{self, ServerActions}};
+/// let mut server = server::new("127.0.0.1:8080".to_string());
+/// server.middleware(
+///     middleware::secure_headers()
+///         .content_security_policy("default-src 'self'; img-src *")
+///         .without("X-Frame-Options"),
+/// );
+/// ```
+pub fn secure_headers() -> SecureHeaders {
+    SecureHeaders {
+        headers: vec![
+            ("X-Content-Type-Options", "nosniff".to_string()),
+            ("X-Frame-Options", "DENY".to_string()),
+            (
+                "Referrer-Policy",
+                "strict-origin-when-cross-origin".to_string(),
+            ),
+            ("Content-Security-Policy", "default-src 'self'".to_string()),
+        ],
+        strict_transport_security: Some("max-age=31536000; includeSubDomains".to_string()),
+    }
+}
+
+impl SecureHeaders {
+    /// Replaces the `X-Frame-Options` value, `DENY` by default.
+    pub fn frame_options(self, value: &str) -> SecureHeaders {
+        self.set("X-Frame-Options", value)
+    }
+
+    /// Replaces the `Referrer-Policy` value, `strict-origin-when-cross-origin` by default.
+    pub fn referrer_policy(self, value: &str) -> SecureHeaders {
+        self.set("Referrer-Policy", value)
+    }
+
+    /// Replaces the `Content-Security-Policy` value, `default-src 'self'` by default.
+    pub fn content_security_policy(self, value: &str) -> SecureHeaders {
+        self.set("Content-Security-Policy", value)
+    }
+
+    /// Replaces the `Strict-Transport-Security` value sent over HTTPS.
+    pub fn strict_transport_security(mut self, value: &str) -> SecureHeaders {
+        self.strict_transport_security = Some(value.to_string());
+        self
+    }
+
+    /// Stops sending the header `name`, ignoring case.
+    pub fn without(mut self, name: &str) -> SecureHeaders {
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+        if name.eq_ignore_ascii_case("Strict-Transport-Security") {
+            self.strict_transport_security = None;
+        }
+        self
+    }
+
+    fn set(mut self, name: &'static str, value: &str) -> SecureHeaders {
+        match self.headers.iter_mut().find(|(header, _)| *header == name) {
+            Some((_, current)) => *current = value.to_string(),
+            None => self.headers.push((name, value.to_string())),
+        }
+        self
+    }
+}
+
+impl Middleware for SecureHeaders {
+    fn after(&self, request: &Request, mut response: Response) -> Response {
+        let hsts = self
+            .strict_transport_security
+            .as_ref()
+            .filter(|_| request.is_https())
+            .map(|value| ("Strict-Transport-Security", value));
+        let headers = self.headers.iter().map(|(name, value)| (*name, value));
+        // Values set by the handler win over the defaults
+        for (name, value) in headers.chain(hsts) {
+            if response.header(name).is_none() {
+                response = response.with_header(name, value);
+            }
+        }
+        response
+    }
+}
+
+/// Returns the host of a `Host` header without its port, if it has one.
+pub(crate) fn strip_port(host: &str) -> &str {
+    let host = host.trim();
+    // IPv6 addresses are written inside brackets, their colons aren't port separators
+    let end = match host.rfind(']') {
+        Some(bracket) => bracket + 1,
+        None => host.find(':').unwrap_or(host.len()),
+    };
+    &host[..end]
+}
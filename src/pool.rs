@@ -0,0 +1,71 @@
+//! Read buffers reused across connections, enabled with `ServerActions::use_buffer_pool`.
+
+use crate::metrics::Counters;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{atomic::Ordering, Mutex},
+};
+
+/// Buffers of closed connections, kept for the next ones.
+pub(crate) struct BufferPool {
+    /// Most buffers kept while no connection uses them.
+    pub(crate) max_idle: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+/// Read buffer of a connection, given back to its pool when dropped.
+pub(crate) struct Buffer<'a> {
+    bytes: Vec<u8>,
+    pool: Option<&'a BufferPool>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(max_idle: usize) -> BufferPool {
+        BufferPool {
+            max_idle,
+            idle: Mutex::new(vec![]),
+        }
+    }
+}
+
+/// Returns a buffer of `size` bytes, taken from `pool` when it has one.
+///
+/// Buffers allocated are counted in `counters`, reused ones aren't.
+pub(crate) fn read_buffer<'a>(
+    pool: Option<&'a BufferPool>,
+    size: usize,
+    counters: &Counters,
+) -> Buffer<'a> {
+    let reused = pool.and_then(|pool| pool.idle.lock().unwrap().pop());
+    let bytes = reused.unwrap_or_else(|| {
+        counters.read_buffers.fetch_add(1, Ordering::Relaxed);
+        vec![0; size]
+    });
+    Buffer { bytes, pool }
+}
+
+impl Deref for Buffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DerefMut for Buffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl Drop for Buffer<'_> {
+    fn drop(&mut self) {
+        let Some(pool) = self.pool else {
+            return;
+        };
+        let mut idle = pool.idle.lock().unwrap();
+        if idle.len() < pool.max_idle {
+            idle.push(std::mem::take(&mut self.bytes));
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Forwarding of requests to upstream servers.
+
+use crate::{
+    client, files, logging,
+    request::{self, Request},
+    response::Response,
+};
+
+/// Headers that only describe the connection between two hops and must not be forwarded.
+const HOP_BY_HOP: [&str; 6] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Requests matching `prefix` forwarded to an upstream server.
+pub(crate) struct Proxy {
+    prefix: String,
+    host: String,
+    base_path: String,
+}
+
+impl Proxy {
+    /// Creates a proxy from an upstream url like `http://localhost:9000`.
+    ///
+    /// Returns `None` if the url isn't a plain `http` url.
+    pub(crate) fn new(prefix: &str, upstream: &str) -> Option<Proxy> {
+        let upstream = client::parse_url(upstream)?;
+        Some(Proxy {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            host: upstream.address,
+            base_path: upstream.path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Returns whether `path` is the prefix itself or is nested under it.
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || rest.starts_with('?'),
+            None => false,
+        }
+    }
+
+    /// Forwards `request` and relays the upstream answer, `502 Bad Gateway` if it failed.
+    ///
+    /// `path` is the one the request was routed with, forwarded under the base path of the
+    /// upstream with the query of the request. Dot segments were already resolved, so the
+    /// request can't reach paths of the upstream outside its base path.
+    pub(crate) async fn forward(&self, request: &Request, path: &str) -> Response {
+        match self.send(request, path).await {
+            Some(response) => response,
+            None => {
+                logging::error(format_args!(
+                    "Error forwarding request to upstream {}",
+                    self.host
+                ));
+                Response::new(502)
+            }
+        }
+    }
+
+    async fn send(&self, request: &Request, path: &str) -> Option<Response> {
+        // Headers listed in `Connection` are for this hop too, like `HTTP2-Settings` next to
+        // an `Upgrade: h2c` the server ignores
+        let listed: Vec<&str> = request
+            .header("Connection")
+            .map(|value| value.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+        let mut headers: Vec<(String, String)> = request
+            .headers()
+            .iter()
+            .filter(|(name, _)| {
+                !HOP_BY_HOP
+                    .iter()
+                    .chain(&listed)
+                    .any(|hop| name.eq_ignore_ascii_case(hop))
+            })
+            .cloned()
+            .collect();
+        // Upstream closes after answering, so the whole response is read until EOF
+        headers.push(("Connection".to_string(), "close".to_string()));
+        let segments: Vec<String> = path.split('/').map(files::encode_segment).collect();
+        let mut target = format!("{}{}", self.base_path, segments.join("/"));
+        if let Some((_, query)) = request.path().split_once('?') {
+            target.push('?');
+            target.push_str(query);
+        }
+        let bytes = request::serialize(request.method(), &target, &headers, request.body());
+
+        let mut response = client::send(&self.host, &bytes).await.ok()?;
+        response.remove_headers(&HOP_BY_HOP);
+        Some(response)
+    }
+}
@@ -0,0 +1,1334 @@
+//! Parsing of incoming HTTP requests.
+
+use crate::{
+    auth::{self, BasicCredentials},
+    error::BodyError,
+    extract::States,
+    files,
+    response::{Response, ResponseWriter},
+    timing::ServerTiming,
+};
+use futures_core::Stream;
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc,
+};
+
+type BodyChunks = mpsc::Receiver<Result<Vec<u8>, BodyError>>;
+
+/// HTTP request method.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+    /// Echoes the request back, rejected by the server since it can leak credentials.
+    Trace,
+    /// Asks for a tunnel to the target, not supported by the server.
+    Connect,
+    /// Any method not known by the server, kept as it was received.
+    Other(String),
+}
+
+impl Method {
+    fn parse(method: &str) -> Method {
+        match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "PATCH" => Method::Patch,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    /// Returns the method as it is written in a request line.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(method) => method,
+        }
+    }
+
+    /// Returns the position of the method in the canonical order of `Allow` headers.
+    pub(crate) fn rank(&self) -> usize {
+        match self {
+            Method::Get => 0,
+            Method::Head => 1,
+            Method::Post => 2,
+            Method::Put => 3,
+            Method::Patch => 4,
+            Method::Delete => 5,
+            Method::Options => 6,
+            Method::Trace => 7,
+            Method::Connect => 8,
+            Method::Other(_) => 9,
+        }
+    }
+}
+
+/// HTTP protocol version of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+/// An HTTP request received by the server.
+#[derive(Debug, Clone)]
+pub struct Request {
+    method: Method,
+    target: String,
+    version: Version,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    pub(crate) remote_addr: Option<SocketAddr>,
+    /// Whether headers set by proxies can be used to find the client.
+    pub(crate) trust_proxy: bool,
+    /// Segments captured by the pattern of the route answering the request.
+    pub(crate) params: Vec<(String, String)>,
+    /// Informational responses sent before the final one go through here.
+    pub(crate) informational: Option<mpsc::UnboundedSender<Response>>,
+    /// Values registered with `ServerActions::state`.
+    pub(crate) states: Option<Arc<States>>,
+    /// Session of the connection when it's served over TLS.
+    pub(crate) tls: Option<Arc<TlsInfo>>,
+    /// Index of the `ServerActions::add_listener` listener it arrived on, `None` for the main one.
+    pub(crate) listener: Option<usize>,
+    /// Chunks of a body streamed to the handler, taken by the first `body_stream` call.
+    pub(crate) body_chunks: Option<Arc<Mutex<Option<BodyChunks>>>>,
+    extensions: Extensions,
+}
+
+/// Values attached to a request by type, see `Request::extensions`.
+///
+/// Values are handed out as copies, the ones holding a resource like a connection go inside
+/// an `Arc`.
+#[derive(Default)]
+pub struct Extensions {
+    values: Mutex<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl Extensions {
+    /// Stores `value`, returning the value of the same type it replaces.
+    pub fn insert<T: Clone + Send + Sync + 'static>(&self, value: T) -> Option<T> {
+        let replaced = self
+            .values
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Arc::new(value))?;
+        replaced.downcast_ref::<T>().cloned()
+    }
+
+    /// Returns a copy of the value of type `T`, if one was stored.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        let values = self.values.lock().unwrap();
+        values.get(&TypeId::of::<T>())?.downcast_ref::<T>().cloned()
+    }
+
+    /// Removes the value of type `T`, returning it.
+    pub fn remove<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        let removed = self.values.lock().unwrap().remove(&TypeId::of::<T>())?;
+        removed.downcast_ref::<T>().cloned()
+    }
+
+    /// Returns whether a value of type `T` was stored.
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.values.lock().unwrap().contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl Clone for Extensions {
+    fn clone(&self) -> Extensions {
+        Extensions {
+            values: Mutex::new(self.values.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.values.lock().unwrap().len();
+        f.debug_struct("Extensions").field("len", &count).finish()
+    }
+}
+
+/// Session negotiated on a connection served over TLS, see `Request::tls_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Protocol agreed on with ALPN, like `http/1.1`, if the client offered any.
+    pub alpn: Option<String>,
+    /// Name of the cipher suite, like `TLS13_AES_128_GCM_SHA256`.
+    pub cipher_suite: String,
+    /// Version of the protocol, like `TLSv1_3`.
+    pub protocol_version: String,
+    /// DER encoded certificates the client authenticated with, its own first.
+    ///
+    /// Empty unless the server asks for client certificates. The subject of the first one is
+    /// the identity of the client for mutual TLS.
+    pub peer_certificates: Vec<Vec<u8>>,
+}
+
+impl Request {
+    /// Returns the method of the request.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Returns the request target as it appears in the request line.
+    pub fn path(&self) -> &str {
+        &self.target
+    }
+
+    /// Returns the segment captured as `:name` or `*name` by the pattern of the route.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the decoded `name=value` pairs of the query string, in the order they were sent.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        let query = match self.target.split_once('?') {
+            Some((_, query)) => query,
+            None => return vec![],
+        };
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                let decode = |part: &str| percent_decode(&part.replace('+', " "));
+                (decode(name), decode(value))
+            })
+            .collect()
+    }
+
+    /// Returns the decoded value of the first query parameter called `name`.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_pairs()
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Deserializes the query string, so `?page=2&limit=10` can fill a pagination struct.
+    ///
+    /// Missing or invalid parameters get a `400 Bad Request` the handler can return as is.
+    #[cfg(feature = "serde")]
+    pub fn query<T: serde::de::DeserializeOwned>(&self) -> Result<T, crate::response::Response> {
+        let query = self.target.split_once('?').map_or("", |(_, query)| query);
+        serde_urlencoded::from_str(query).map_err(|e| {
+            crate::response::Response::new(400)
+                .with_header("Content-Type", "text/plain")
+                .with_body(e.to_string())
+        })
+    }
+
+    /// Returns the HTTP version used by the client.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the value of the first header matching `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns every header in the order they were received.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Returns the raw body of the request.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Returns the token of an `Authorization: Bearer <token>` header, see `auth::bearer_token`.
+    pub fn bearer_token(&self) -> Option<&str> {
+        auth::bearer_token(self)
+    }
+
+    /// Returns the credentials of an `Authorization: Basic` header, see
+    /// `auth::basic_credentials`.
+    pub fn basic_credentials(&self) -> Option<BasicCredentials> {
+        auth::basic_credentials(self)
+    }
+
+    /// Checks the `If-Match` and `If-Unmodified-Since` preconditions of the request against
+    /// the current version of the resource, returning the `412 Precondition Failed` to answer
+    /// when they don't hold.
+    ///
+    /// `current_etag` is the quoted entity tag of the resource, like `"v3"`, and `None` if it
+    /// doesn't exist, so `If-Match: *` fails. Tags are compared strongly, a weak one never
+    /// matches. `If-Unmodified-Since` is only looked at without `If-Match`, and when the
+    /// resource has a `last_modified` date. Updates guarded with it don't overwrite the
+    /// changes of another client.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.add_route(Method::Put, "/document", |req| {
+    ///     if let Some(failed) = req.check_preconditions(Some("\"v3\""), None) {
+    ///         return failed;
+    ///     }
+    ///     Response::new(200).with_header("ETag", "\"v4\"")
+    /// });
+    /// ```
+    pub fn check_preconditions(
+        &self,
+        current_etag: Option<&str>,
+        last_modified: Option<SystemTime>,
+    ) -> Option<Response> {
+        let holds = match (self.header("If-Match"), self.header("If-Unmodified-Since")) {
+            (Some(tags), _) => tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| match current_etag {
+                    Some(_) if tag == "*" => true,
+                    Some(current) => !tag.starts_with("W/") && tag == current,
+                    None => false,
+                }),
+            (None, Some(since)) => {
+                let since = files::parse_date(since);
+                let modified = last_modified
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|modified| modified.as_secs());
+                match (since, modified) {
+                    (Some(since), Some(modified)) => modified <= since,
+                    // An invalid date or a resource without one aren't checked
+                    _ => true,
+                }
+            }
+            (None, None) => true,
+        };
+        (!holds).then(|| Response::new(412))
+    }
+
+    /// Returns the measurements sent back in the `Server-Timing` header of the response.
+    ///
+    /// Nothing is added to responses of requests that recorded none.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Instant;
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.add_route(Method::Get, "/report", |req| {
+    ///     let started = Instant::now();
+    ///     let rows = vec!["a", "b"];
+    ///     req.timing().record("db", started.elapsed());
+    ///     Response::html(rows.join(", "))
+    /// });
+    /// ```
+    pub fn timing(&self) -> ServerTiming {
+        if let Some(timing) = self.extensions.get::<ServerTiming>() {
+            return timing;
+        }
+        let timing = ServerTiming::default();
+        self.extensions.insert(timing.clone());
+        timing
+    }
+
+    /// Returns the body of the request as a stream of chunks.
+    ///
+    /// For routes set with `Route::stream_body` the chunks are yielded as they come off the
+    /// socket, and the stream ends with an error if the body exceeds the `max_body_size` of the
+    /// server or its framing is broken. It can only be taken once, later calls get an empty
+    /// stream. For other routes the buffered body is yielded in a single chunk.
+    pub fn body_stream(&self) -> BodyStream {
+        match &self.body_chunks {
+            Some(chunks) => BodyStream {
+                buffered: None,
+                chunks: chunks.lock().unwrap().take(),
+            },
+            None => BodyStream {
+                buffered: Some(self.body.clone()).filter(|body| !body.is_empty()),
+                chunks: None,
+            },
+        }
+    }
+
+    /// Returns a writer for the informational responses sent before the final one.
+    pub fn response_writer(&self) -> ResponseWriter {
+        ResponseWriter {
+            sender: self.informational.clone(),
+        }
+    }
+
+    /// Returns the address of the peer that opened the connection.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Returns the IP of the client that sent the request.
+    ///
+    /// When the server trusts proxies the `for` parameter of the first `Forwarded` element is
+    /// used, or else the leftmost address of `X-Forwarded-For`, so the client behind the proxy
+    /// is found. Otherwise, or if the headers are missing or malformed, it's the IP of the peer.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        let forwarded = self
+            .forwarded_param("for")
+            .and_then(|node| parse_forwarded_node(&node))
+            .or_else(|| {
+                self.header("X-Forwarded-For")
+                    .filter(|_| self.trust_proxy)
+                    .and_then(|value| parse_forwarded_ip(value.split(',').next()?))
+            });
+        forwarded.or(self.remote_addr.map(|addr| addr.ip()))
+    }
+
+    /// Returns the scheme the client used to reach a trusted proxy, in lowercase.
+    ///
+    /// Taken from the `proto` parameter of the first `Forwarded` element, or else from
+    /// `X-Forwarded-Proto`. `None` when the server doesn't trust proxies or neither is sent.
+    pub fn forwarded_proto(&self) -> Option<String> {
+        let proto = self.forwarded_param("proto").or_else(|| {
+            self.header("X-Forwarded-Proto")
+                .filter(|_| self.trust_proxy)
+                .map(|proto| proto.trim().to_string())
+        })?;
+        Some(proto.to_ascii_lowercase())
+    }
+
+    /// Returns the `Host` the client sent to a trusted proxy.
+    ///
+    /// Taken from the `host` parameter of the first `Forwarded` element, or else from
+    /// `X-Forwarded-Host`. `None` when the server doesn't trust proxies or neither is sent.
+    pub fn forwarded_host(&self) -> Option<String> {
+        self.forwarded_param("host").or_else(|| {
+            let value = self
+                .header("X-Forwarded-Host")
+                .filter(|_| self.trust_proxy)?;
+            Some(value.split(',').next()?.trim().to_string())
+        })
+    }
+
+    /// Returns the parameter `name` of the first element of `Forwarded`, added by the proxy
+    /// closest to the client, `None` when the server doesn't trust proxies.
+    fn forwarded_param(&self, name: &str) -> Option<String> {
+        let value = self.header("Forwarded").filter(|_| self.trust_proxy)?;
+        forwarded_element(value)
+            .into_iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Returns the TLS session of the connection, `None` for plain HTTP.
+    ///
+    /// Connections are only served over TLS with the `tls` feature, see `ServerActions::tls`.
+    pub fn tls_info(&self) -> Option<&TlsInfo> {
+        self.tls.as_deref()
+    }
+
+    /// Returns the values attached to the request, for middlewares to pass on to handlers.
+    ///
+    /// Values are stored by type, so a middleware and the handlers reading its values agree
+    /// on a type rather than a name. They're kept until the request is answered.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{middleware::Middleware, request::{Method, Request}, response::Response};
+    /// use mini_rest::server::{self, ServerActions};
+    /// #[derive(Clone)]
+    /// struct User(String);
+    /// struct Identify;
+    /// impl Middleware for Identify {
+    ///     fn before(&self, request: &Request) -> Option<Response> {
+    ///         let name = request.header("X-User")?;
+    ///         request.extensions().insert(User(name.to_string()));
+    ///         None
+    ///     }
+    /// }
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.middleware(Identify);
+    /// server.add_route(Method::Get, "/me", |req| match req.extensions().get::<User>() {
+    ///     Some(User(name)) => Response::html(format!("hello {}", name)),
+    ///     None => Response::new(401),
+    /// });
+    /// ```
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Returns whether the client reached the server over HTTPS.
+    ///
+    /// True when the connection is served over TLS, or behind a trusted proxy terminating TLS
+    /// whose `forwarded_proto` is `https`.
+    pub fn is_https(&self) -> bool {
+        self.tls.is_some() || self.forwarded_proto().as_deref() == Some("https")
+    }
+
+    /// Returns whether the connection should stay open after answering this request.
+    ///
+    /// HTTP/1.1 connections are persistent unless the client sends `Connection: close`,
+    /// HTTP/1.0 connections are closed unless the client sends `Connection: keep-alive`.
+    /// Other options don't matter: the server never switches protocols, so a request asking
+    /// for an `Upgrade`, like clients probing for `h2c` do, is answered in HTTP/1.1 and the
+    /// connection is reused as any other.
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.header("Connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|option| option.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        match self.version {
+            Version::Http11 => !has_token("close"),
+            Version::Http10 => has_token("keep-alive"),
+        }
+    }
+
+    /// Returns whether the client accepts a response of type `mime`, according to `Accept`.
+    pub fn accepts(&self, mime: &str) -> bool {
+        self.quality(mime) > 0.0
+    }
+
+    /// Returns the type the client prefers among `available`, according to `Accept`.
+    ///
+    /// Types with the same quality keep the order of `available`, types the client doesn't
+    /// accept are never returned.
+    pub fn preferred<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        let mut best: Option<(&str, f32)> = None;
+        for mime in available {
+            let quality = self.quality(mime);
+            if quality > best.map_or(0.0, |(_, best)| best) {
+                best = Some((mime, quality));
+            }
+        }
+        best.map(|(mime, _)| mime)
+    }
+
+    /// Returns the quality given to `mime` by the most specific matching `Accept` range.
+    fn quality(&self, mime: &str) -> f32 {
+        let (kind, subtype) = mime.split_once('/').unwrap_or((mime, ""));
+        let ranges = self
+            .header("Accept")
+            .and_then(parse_accept)
+            .unwrap_or_else(|| vec![("*".to_string(), "*".to_string(), 1.0)]);
+        ranges
+            .iter()
+            .filter_map(|(range_kind, range_subtype, quality)| {
+                let specificity = match (range_kind.as_str(), range_subtype.as_str()) {
+                    ("*", "*") => 0,
+                    (range_kind, "*") if range_kind.eq_ignore_ascii_case(kind) => 1,
+                    (range_kind, range_subtype)
+                        if range_kind.eq_ignore_ascii_case(kind)
+                            && range_subtype.eq_ignore_ascii_case(subtype) =>
+                    {
+                        2
+                    }
+                    _ => return None,
+                };
+                Some((specificity, *quality))
+            })
+            .max_by_key(|(specificity, _)| *specificity)
+            .map_or(0.0, |(_, quality)| quality)
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        self.header("Content-Length")
+            .and_then(|value| value.trim().parse::<usize>().ok())
+    }
+}
+
+/// Body of a request read chunk by chunk, returned by `Request::body_stream`.
+#[derive(Debug)]
+pub struct BodyStream {
+    buffered: Option<Vec<u8>>,
+    chunks: Option<BodyChunks>,
+}
+
+impl BodyStream {
+    /// Waits for the next chunk of the body, `None` once it has been fully read.
+    pub async fn next(&mut self) -> Option<Result<Vec<u8>, BodyError>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for BodyStream {
+    type Item = Result<Vec<u8>, BodyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(body) = self.buffered.take() {
+            return Poll::Ready(Some(Ok(body)));
+        }
+        match &mut self.chunks {
+            Some(chunks) => chunks.poll_recv(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Limits applied while parsing requests.
+pub(crate) struct Limits {
+    pub(crate) max_headers: usize,
+    /// Bytes the head of a request may take, counted while it's still arriving.
+    pub(crate) max_head_size: usize,
+    pub(crate) max_body_size: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_headers: 100,
+            max_head_size: 16 * 1024,
+            max_body_size: None,
+        }
+    }
+}
+
+/// Longest chunk size or trailer line accepted in a chunked body, without its CRLF.
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// Largest decompressed body accepted when no `max_body_size` is set.
+#[cfg(feature = "compression")]
+const MAX_DECODED_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Error produced when the bytes sent by a client are not an acceptable HTTP request.
+#[derive(Debug)]
+pub(crate) enum ParseError {
+    Malformed,
+    TooManyHeaders,
+    HeadTooLarge,
+    BodyTooLarge,
+    #[cfg(feature = "compression")]
+    UnsupportedEncoding,
+}
+
+impl ParseError {
+    /// Returns the status code answered to the client.
+    pub(crate) fn status(&self) -> u16 {
+        match self {
+            ParseError::Malformed => 400,
+            ParseError::TooManyHeaders | ParseError::HeadTooLarge => 431,
+            ParseError::BodyTooLarge => 413,
+            #[cfg(feature = "compression")]
+            ParseError::UnsupportedEncoding => 415,
+        }
+    }
+}
+
+/// Drops the empty lines at the start of `buffer`.
+///
+/// Some clients send a lone CRLF on a kept-alive connection as a ping, or after the body of a
+/// `POST`. RFC 9112 asks servers to ignore at least one empty line before a request line.
+pub(crate) fn skip_empty_lines(buffer: &mut Vec<u8>) {
+    let empty = buffer
+        .iter()
+        .take_while(|byte| matches!(byte, b'\r' | b'\n'))
+        .count();
+    buffer.drain(..empty);
+}
+
+/// Returns whether `buffer` holds the whole head of a request, its body may still be missing.
+pub(crate) fn has_head(buffer: &[u8]) -> bool {
+    head_end(buffer).is_some()
+}
+
+/// Returns the length of the head starting `buffer` and where its body starts, `None` until
+/// the empty line ending the head arrived.
+///
+/// Lines may end with a bare LF rather than CRLF, as minimal clients write them, so the head
+/// ends at the first LF followed by another one or by CRLF.
+fn head_end(buffer: &[u8]) -> Option<(usize, usize)> {
+    let mut start = 0;
+    while let Some(offset) = buffer[start..].iter().position(|byte| *byte == b'\n') {
+        let newline = start + offset;
+        match &buffer[newline + 1..] {
+            [b'\n', ..] => return Some((newline, newline + 2)),
+            [b'\r', b'\n', ..] => return Some((newline, newline + 3)),
+            _ => start = newline + 1,
+        }
+    }
+    None
+}
+
+/// How the body of a request is delimited on the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+/// Request taken out of a buffer by `take_request_or_head`.
+pub(crate) enum Taken {
+    Whole(Request),
+    /// Only the head was taken, the body is still to be read with the framing.
+    Head(Request, Framing),
+}
+
+/// Returns the request whose head starts `buffer` without taking it out, its body is empty.
+///
+/// `None` while the head is incomplete, or when it's invalid as taking it reports why then.
+pub(crate) fn peek_head(buffer: &[u8], limits: &Limits) -> Option<Request> {
+    let (head_end, _) = head_end(buffer)?;
+    parse_head(std::str::from_utf8(&buffer[..head_end]).ok()?, limits).ok()
+}
+
+/// Answer to a request waiting for `100 Continue` before sending its body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Expectation {
+    Continue,
+    /// The declared body is over `max_body_size`, it shouldn't be sent at all.
+    TooLarge,
+}
+
+/// Returns how to answer the request whose head starts `buffer` if it waits for `100 Continue`.
+///
+/// `None` unless it's an HTTP/1.1 request sent with `Expect: 100-continue`, with a body of
+/// which nothing arrived yet.
+pub(crate) fn expectation(buffer: &[u8], limits: &Limits) -> Option<Expectation> {
+    let (head_end, body_start) = head_end(buffer)?;
+    let request = parse_head(std::str::from_utf8(&buffer[..head_end]).ok()?, limits).ok()?;
+    let expects = request
+        .header("Expect")
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"));
+    if !expects || request.version() != Version::Http11 || buffer.len() > body_start {
+        return None;
+    }
+    match framing(&request, limits) {
+        Ok(Framing::Length(0)) => None,
+        Ok(_) => Some(Expectation::Continue),
+        Err(ParseError::BodyTooLarge) => Some(Expectation::TooLarge),
+        // Reported once the request is taken
+        Err(_) => None,
+    }
+}
+
+/// Takes the first complete request out of `buffer`.
+///
+/// Returns `None` while the head or the body are still incomplete so more data can be
+/// appended, the buffer is only left untouched for bodies sent with a length: the chunks
+/// already decoded are drained, see `take_request_or_head`. Once a request is returned its
+/// bytes are drained from the buffer, so anything left belongs to the next request on the
+/// connection.
+#[cfg(test)]
+pub(crate) fn take_request(
+    buffer: &mut Vec<u8>,
+    limits: &Limits,
+) -> Option<Result<Request, ParseError>> {
+    take_request_or_head(buffer, &mut None, limits, |_| false).map(|taken| {
+        taken.map(|taken| match taken {
+            Taken::Whole(request) => request,
+            Taken::Head(..) => unreachable!("no body is streamed"),
+        })
+    })
+}
+
+/// Chunked body of the request at the start of a buffer, decoded as its bytes arrive.
+///
+/// Kept between the calls to `take_request_or_head` so every byte is decoded once: the bytes
+/// decoded are drained from the buffer, which only keeps the head until the last chunk.
+pub(crate) struct PendingBody {
+    request: Request,
+    /// Where the body starts in the buffer, right after the head.
+    body_start: usize,
+    chunked: Chunked,
+    body: Vec<u8>,
+}
+
+/// Takes the first request out of `buffer` like `take_request`, but only its head when
+/// `streams` says its body is read as it arrives.
+///
+/// A chunked body still arriving is kept in `pending`, the next call carries on decoding it.
+pub(crate) fn take_request_or_head(
+    buffer: &mut Vec<u8>,
+    pending: &mut Option<PendingBody>,
+    limits: &Limits,
+    streams: impl Fn(&Request) -> bool,
+) -> Option<Result<Taken, ParseError>> {
+    if let Some(decoding) = pending.take() {
+        return take_chunked(buffer, decoding, pending, limits);
+    }
+    skip_empty_lines(buffer);
+    let Some((head_end, body_start)) = head_end(buffer) else {
+        // A head that never ends would grow the buffer for as long as the client keeps sending
+        return (buffer.len() > limits.max_head_size).then_some(Err(ParseError::HeadTooLarge));
+    };
+    if head_end > limits.max_head_size {
+        return Some(Err(ParseError::HeadTooLarge));
+    }
+    let mut request = match std::str::from_utf8(&buffer[..head_end]) {
+        Ok(head) => match parse_head(head, limits) {
+            Ok(request) => request,
+            Err(e) => return Some(Err(e)),
+        },
+        Err(_) => return Some(Err(ParseError::Malformed)),
+    };
+
+    let framing = match framing(&request, limits) {
+        Ok(framing) => framing,
+        Err(e) => return Some(Err(e)),
+    };
+    if streams(&request) {
+        buffer.drain(..body_start);
+        return Some(Ok(Taken::Head(request, framing)));
+    }
+    let length = match framing {
+        Framing::Length(length) => length,
+        Framing::Chunked => {
+            let decoding = PendingBody {
+                request,
+                body_start,
+                chunked: Chunked::default(),
+                body: vec![],
+            };
+            return take_chunked(buffer, decoding, pending, limits);
+        }
+    };
+    let body_end = match body_start.checked_add(length) {
+        Some(body_end) => body_end,
+        None => return Some(Err(ParseError::Malformed)),
+    };
+    if buffer.len() < body_end {
+        return None;
+    }
+    request.body = buffer[body_start..body_end].to_vec();
+    buffer.drain(..body_end);
+    #[cfg(feature = "compression")]
+    if let Err(e) = decode_body(&mut request, limits) {
+        return Some(Err(e));
+    }
+    Some(Ok(Taken::Whole(request)))
+}
+
+/// Decodes the chunks of `decoding` that arrived in `buffer`, returning the request once its
+/// last chunk did and keeping it in `pending` until then.
+fn take_chunked(
+    buffer: &mut Vec<u8>,
+    mut decoding: PendingBody,
+    pending: &mut Option<PendingBody>,
+    limits: &Limits,
+) -> Option<Result<Taken, ParseError>> {
+    let body_start = decoding.body_start;
+    let size = match decoding
+        .chunked
+        .decode(&buffer[body_start..], limits, &mut decoding.body)
+    {
+        Ok(size) => size,
+        Err(e) => return Some(Err(e)),
+    };
+    if !decoding.chunked.is_done() {
+        buffer.drain(body_start..body_start + size);
+        *pending = Some(decoding);
+        return None;
+    }
+    buffer.drain(..body_start + size);
+    let mut request = decoding.request;
+    request.body = decoding.body;
+    request
+        .headers
+        .retain(|(name, _)| !name.eq_ignore_ascii_case("Transfer-Encoding"));
+    let length = request.body.len().to_string();
+    request.headers.push(("Content-Length".to_string(), length));
+    #[cfg(feature = "compression")]
+    if let Err(e) = decode_body(&mut request, limits) {
+        return Some(Err(e));
+    }
+    Some(Ok(Taken::Whole(request)))
+}
+
+/// Returns how the body of `request` is delimited, rejecting ambiguous or oversized ones.
+fn framing(request: &Request, limits: &Limits) -> Result<Framing, ParseError> {
+    if let Some(codings) = request.header("Transfer-Encoding") {
+        // A length next to chunked framing is how requests get smuggled past proxies
+        let last = codings.rsplit(',').next().unwrap_or_default().trim();
+        if request.header("Content-Length").is_some() || !last.eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::Malformed);
+        }
+        return Ok(Framing::Chunked);
+    }
+    let length = match request.header("Content-Length") {
+        Some(_) => request.content_length().ok_or(ParseError::Malformed)?,
+        None => 0,
+    };
+    if limits.max_body_size.is_some_and(|max| length > max) {
+        return Err(ParseError::BodyTooLarge);
+    }
+    Ok(Framing::Length(length))
+}
+
+/// Reads the body of a request whose head was taken alone, sending its chunks to `chunks`.
+///
+/// `buffer` holds what was already received after the head, whatever follows the body is left
+/// in it for the next request. Returns whether the whole body was read, the connection can't
+/// be reused otherwise.
+pub(crate) async fn stream_body<R>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    framing: Framing,
+    limits: &Limits,
+    chunks: mpsc::Sender<Result<Vec<u8>, BodyError>>,
+    read_size: usize,
+) -> bool
+where
+    R: AsyncRead + Unpin,
+{
+    let mut remaining = match framing {
+        Framing::Length(length) => length,
+        Framing::Chunked => 0,
+    };
+    let mut chunked = Chunked::default();
+    let mut read = vec![0; read_size];
+    loop {
+        let (chunk, complete) = match framing {
+            Framing::Length(_) => {
+                let taken = remaining.min(buffer.len());
+                remaining -= taken;
+                (buffer.drain(..taken).collect(), remaining == 0)
+            }
+            Framing::Chunked => {
+                let mut chunk = vec![];
+                match chunked.decode(buffer, limits, &mut chunk) {
+                    Ok(size) => {
+                        buffer.drain(..size);
+                        (chunk, chunked.is_done())
+                    }
+                    Err(e) => {
+                        let e = match e {
+                            ParseError::BodyTooLarge => BodyError::TooLarge,
+                            _ => BodyError::Malformed,
+                        };
+                        let _ = chunks.send(Err(e)).await;
+                        return false;
+                    }
+                }
+            }
+        };
+        if !chunk.is_empty() && chunks.send(Ok(chunk)).await.is_err() {
+            // The handler dropped the stream, the rest of the body is left unread
+            return false;
+        }
+        if complete {
+            return true;
+        }
+        match reader.read(&mut read).await {
+            Ok(size) if size > 0 => buffer.extend_from_slice(&read[..size]),
+            _ => {
+                let _ = chunks.send(Err(BodyError::Incomplete)).await;
+                return false;
+            }
+        }
+    }
+}
+
+/// Decoder of a chunked body, fed its bytes as they arrive.
+///
+/// Chunk size and trailer lines are limited in length so a line that never ends can't grow
+/// the buffer forever, and the decoded size counts against `max_body_size`.
+#[derive(Default)]
+struct Chunked {
+    state: ChunkState,
+    decoded: usize,
+}
+
+#[derive(Default)]
+enum ChunkState {
+    #[default]
+    Size,
+    /// Bytes of the current chunk still to come.
+    Data(usize),
+    /// The CRLF ending a chunk.
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+impl Chunked {
+    /// Decodes what it can from the start of `data` into `body`, returning how many bytes it
+    /// took. The bytes after them are fed again once more data arrived.
+    fn decode(
+        &mut self,
+        data: &[u8],
+        limits: &Limits,
+        body: &mut Vec<u8>,
+    ) -> Result<usize, ParseError> {
+        let mut position = 0;
+        loop {
+            let rest = &data[position..];
+            match self.state {
+                ChunkState::Size => {
+                    let Some(line) = chunk_line(rest)? else {
+                        return Ok(position);
+                    };
+                    position += line.len() + 2;
+                    // Extensions after `;` are allowed and ignored
+                    let size = line.split(|byte| *byte == b';').next().unwrap_or_default();
+                    let size = std::str::from_utf8(size)
+                        .ok()
+                        .filter(|size| {
+                            !size.is_empty() && size.bytes().all(|byte| byte.is_ascii_hexdigit())
+                        })
+                        .and_then(|size| usize::from_str_radix(size, 16).ok())
+                        .ok_or(ParseError::Malformed)?;
+                    if size == 0 {
+                        self.state = ChunkState::Trailers;
+                        continue;
+                    }
+                    self.decoded = self
+                        .decoded
+                        .checked_add(size)
+                        .ok_or(ParseError::BodyTooLarge)?;
+                    if limits.max_body_size.is_some_and(|max| self.decoded > max) {
+                        return Err(ParseError::BodyTooLarge);
+                    }
+                    self.state = ChunkState::Data(size);
+                }
+                ChunkState::Data(remaining) => {
+                    if rest.is_empty() {
+                        return Ok(position);
+                    }
+                    let taken = remaining.min(rest.len());
+                    body.extend_from_slice(&rest[..taken]);
+                    position += taken;
+                    self.state = match remaining - taken {
+                        0 => ChunkState::DataEnd,
+                        remaining => ChunkState::Data(remaining),
+                    };
+                }
+                ChunkState::DataEnd => {
+                    if rest.len() < 2 {
+                        return Ok(position);
+                    }
+                    if &rest[..2] != b"\r\n" {
+                        return Err(ParseError::Malformed);
+                    }
+                    position += 2;
+                    self.state = ChunkState::Size;
+                }
+                ChunkState::Trailers => {
+                    // Trailers are read up to the empty line ending them, their values are dropped
+                    let Some(trailer) = chunk_line(rest)? else {
+                        return Ok(position);
+                    };
+                    position += trailer.len() + 2;
+                    if trailer.is_empty() {
+                        self.state = ChunkState::Done;
+                    }
+                }
+                ChunkState::Done => return Ok(position),
+            }
+        }
+    }
+
+    /// Returns whether the last chunk and the trailers were decoded.
+    fn is_done(&self) -> bool {
+        matches!(self.state, ChunkState::Done)
+    }
+}
+
+/// Returns the line at the start of `data` without its CRLF, `None` if it didn't fully arrive.
+fn chunk_line(data: &[u8]) -> Result<Option<&[u8]>, ParseError> {
+    let searched = &data[..data.len().min(MAX_CHUNK_LINE + 2)];
+    match searched.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => Ok(Some(&data[..end])),
+        None if data.len() > MAX_CHUNK_LINE => Err(ParseError::Malformed),
+        None => Ok(None),
+    }
+}
+
+/// Replaces a body sent with a `Content-Encoding` with its decoded bytes.
+///
+/// Codings are undone from the last applied to the first, the size limit is checked on the
+/// decoded body so small payloads can't expand into huge ones.
+#[cfg(feature = "compression")]
+fn decode_body(request: &mut Request, limits: &Limits) -> Result<(), ParseError> {
+    use crate::compression::{self, DecodeError};
+    let codings: Vec<String> = match request.header("Content-Encoding") {
+        Some(codings) => codings
+            .split(',')
+            .map(|coding| coding.trim().to_string())
+            .filter(|coding| !coding.is_empty())
+            .collect(),
+        None => return Ok(()),
+    };
+    let max = limits.max_body_size.unwrap_or(MAX_DECODED_BODY_SIZE);
+    for coding in codings.iter().rev() {
+        request.body = compression::decode(coding, &request.body, max).map_err(|e| match e {
+            DecodeError::Unsupported => ParseError::UnsupportedEncoding,
+            DecodeError::TooLarge => ParseError::BodyTooLarge,
+            DecodeError::Invalid => ParseError::Malformed,
+        })?;
+    }
+    request.headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("Content-Encoding")
+            && !name.eq_ignore_ascii_case("Content-Length")
+    });
+    let length = request.body.len().to_string();
+    request.headers.push(("Content-Length".to_string(), length));
+    Ok(())
+}
+
+/// Parses the request line and the headers of a request, their lines ending with CRLF or LF.
+fn parse_head(head: &str, limits: &Limits) -> Result<Request, ParseError> {
+    let mut lines = head
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+    let (method, target, version) = lines
+        .next()
+        .and_then(parse_request_line)
+        .ok_or(ParseError::Malformed)?;
+
+    Ok(Request {
+        method,
+        target,
+        version,
+        headers: parse_headers(lines, limits)?,
+        body: vec![],
+        remote_addr: None,
+        trust_proxy: false,
+        params: vec![],
+        informational: None,
+        states: None,
+        tls: None,
+        listener: None,
+        body_chunks: None,
+        extensions: Extensions::default(),
+    })
+}
+
+fn parse_request_line(line: &str) -> Option<(Method, String, Version)> {
+    let mut parts = line.split(' ');
+    let method = parts.next().filter(|method| is_token(method))?;
+    let target = parts.next().filter(|target| {
+        !target.is_empty() && !target.bytes().any(|byte| byte.is_ascii_control())
+    })?;
+    let version = match parts.next()? {
+        "HTTP/1.0" => Version::Http10,
+        "HTTP/1.1" => Version::Http11,
+        _ => return None,
+    };
+    let method = Method::parse(method);
+    // The `*` target only means the whole server for `OPTIONS`
+    if target == "*" && method != Method::Options {
+        return None;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some((method, target.to_string(), version)),
+    }
+}
+
+/// Splits every header line into its name and its trimmed value.
+///
+/// Stops as soon as there are more headers than `limits` allow, so a flood of header lines
+/// never gets stored.
+///
+/// Lines that could be read differently by another server are rejected instead of guessed:
+/// folded lines (starting with whitespace), names that are empty or have whitespace before the
+/// colon, and control characters like a bare CR or LF anywhere in the line.
+fn parse_headers<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    limits: &Limits,
+) -> Result<Vec<(String, String)>, ParseError> {
+    let mut headers = vec![];
+    for line in lines {
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        let (name, value) = line.split_once(':').ok_or(ParseError::Malformed)?;
+        let valid_value = value
+            .bytes()
+            .all(|byte| byte == b'\t' || !byte.is_ascii_control());
+        if !is_token(name) || !valid_value {
+            return Err(ParseError::Malformed);
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok(headers)
+}
+
+/// Returns whether `value` is a non empty token, the syntax of methods and header names.
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Parses the media ranges of an `Accept` header with their quality.
+///
+/// Returns `None` if any range is malformed, so the caller can fall back to accepting anything.
+fn parse_accept(accept: &str) -> Option<Vec<(String, String, f32)>> {
+    accept
+        .split(',')
+        .map(|range| {
+            let mut parameters = range.split(';');
+            let (kind, subtype) = parameters.next()?.trim().split_once('/')?;
+            let mut quality = 1.0;
+            for parameter in parameters {
+                if let Some(value) = parameter.trim().strip_prefix("q=") {
+                    quality = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+            Some((kind.to_string(), subtype.to_string(), quality))
+        })
+        .collect()
+}
+
+/// Parses an address written by a proxy, which may carry a port.
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Parses the first element of a `Forwarded` value into its parameters, RFC 7239.
+///
+/// Elements are separated by commas and parameters by semicolons, values may be quoted
+/// strings with backslash escapes. Parsing stops at the first malformed parameter.
+fn forwarded_element(value: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut chars = value.chars().peekable();
+    loop {
+        let mut key = String::new();
+        for c in chars.by_ref() {
+            match c {
+                '=' => break,
+                ',' | ';' | '"' => return params,
+                c => key.push(c),
+            }
+        }
+        let key = key.trim().to_string();
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        let mut separator = None;
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            separator = chars.find(|c| matches!(c, ',' | ';'));
+        } else {
+            for c in chars.by_ref() {
+                if matches!(c, ',' | ';') {
+                    separator = Some(c);
+                    break;
+                }
+                value.push(c);
+            }
+            value = value.trim().to_string();
+        }
+        if key.is_empty() {
+            return params;
+        }
+        params.push((key, value));
+        if separator != Some(';') {
+            return params;
+        }
+    }
+}
+
+/// Parses the `for` node of a `Forwarded` element, `None` for `unknown` or obfuscated ones.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    // IPv6 addresses are written inside brackets, with or without a port
+    match node.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?.parse().ok(),
+        None => parse_forwarded_ip(node),
+    }
+}
+
+/// Returns the path of a request target ready to be matched against routes.
+///
+/// The query is dropped, percent-encoded bytes are decoded, and empty or `.` segments are
+/// removed while `..` segments remove the previous one, so the result never escapes `/`.
+pub(crate) fn normalize_path(target: &str) -> String {
+    let path = target.split('?').next().unwrap_or_default();
+    let decoded = percent_decode(path);
+    let mut segments: Vec<&str> = vec![];
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// Decodes `%XX` sequences, leaving invalid ones as they are.
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = bytes
+            .get(index + 1..index + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[index], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Serializes a request to the bytes sent to an upstream server.
+pub(crate) fn serialize(
+    method: &Method,
+    target: &str,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", method.as_str(), target);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(body);
+    bytes
+}
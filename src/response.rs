@@ -0,0 +1,1041 @@
+//! Building and serializing HTTP responses.
+
+use crate::{files, logging};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+type Trailers = Box<dyn FnOnce() -> Vec<(String, String)> + Send>;
+pub(crate) type ErrorHandler = dyn Fn(Box<dyn Any>) -> Response + Send + Sync;
+pub(crate) type ErrorHandlers = HashMap<TypeId, Arc<ErrorHandler>>;
+
+tokio::task_local! {
+    /// Handlers of `ServerActions::error_handler`, set while the server runs a route.
+    pub(crate) static ERROR_HANDLERS: Arc<ErrorHandlers>;
+}
+
+/// Callback told how many bytes of the body were sent so far.
+struct Progress(Box<dyn FnMut(u64) + Send>);
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// An HTTP response sent back to a client.
+#[derive(Debug)]
+pub struct Response {
+    status: u16,
+    reason: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Body,
+    progress: Option<Progress>,
+    /// Set by `close_connection`, the connection is closed once the response is written.
+    close: bool,
+    /// Kind of the error the response was made from, see `ServerActions::set_io_error_status`.
+    io_error: Option<io::ErrorKind>,
+}
+
+/// Content sent after the head of a response.
+enum Body {
+    Bytes(Vec<u8>),
+    /// Copied straight to the socket without being buffered in memory.
+    Reader {
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+        length: u64,
+    },
+    /// Sent with `Transfer-Encoding: chunked` as chunks arrive, optionally followed by trailers.
+    Chunked {
+        chunks: mpsc::Receiver<Vec<u8>>,
+        trailers: Option<(Vec<String>, Trailers)>,
+    },
+}
+
+impl Body {
+    /// Returns the `Content-Length` of the body, `None` when it's only known once sent.
+    fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { length, .. } => Some(*length),
+            Body::Chunked { .. } => None,
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::Reader { length, .. } => {
+                f.debug_struct("Reader").field("length", length).finish()
+            }
+            Body::Chunked { trailers, .. } => f
+                .debug_struct("Chunked")
+                .field("trailers", &trailers.as_ref().map(|(names, _)| names))
+                .finish(),
+        }
+    }
+}
+
+impl Response {
+    /// Creates an empty response with the given status code.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// let response = Response::new(204);
+    /// assert_eq!(response.status(), 204);
+    /// ```
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            reason: None,
+            headers: vec![],
+            body: Body::Bytes(vec![]),
+            progress: None,
+            close: false,
+            io_error: None,
+        }
+    }
+
+    /// Creates a `200 OK` response streamed with `Transfer-Encoding: chunked`.
+    ///
+    /// Every vector received from `chunks` is sent as a chunk, the response ends when every
+    /// sender is dropped.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// let (sender, chunks) = tokio::sync::mpsc::channel(16);
+    /// let response = Response::chunked(chunks, "text/plain");
+    /// # drop(sender);
+    /// ```
+    pub fn chunked(chunks: mpsc::Receiver<Vec<u8>>, content_type: &str) -> Response {
+        let mut response = Response::new(200).with_header("Content-Type", content_type);
+        response.body = Body::Chunked {
+            chunks,
+            trailers: None,
+        };
+        response
+    }
+
+    /// Creates an empty response with a custom reason phrase in place of the standard one.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// let response = Response::with_reason(200, "Everything Fine");
+    /// assert_eq!(response.reason(), "Everything Fine");
+    /// ```
+    pub fn with_reason(status: u16, reason: &str) -> Response {
+        // Line breaks would let the phrase inject headers
+        let reason = reason.chars().filter(|c| !c.is_control()).collect();
+        Response {
+            reason: Some(reason),
+            ..Response::new(status)
+        }
+    }
+
+    /// Creates a `200 OK` response whose body is copied from `reader` as it is sent.
+    ///
+    /// The body is never fully loaded in memory, which makes this the way to send big files.
+    /// `content_length` must be the exact amount of bytes `reader` yields.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use mini_rest::response::Response;
+    /// # async fn example() -> std::io::Result<()> {
+    /// let file = tokio::fs::File::open("video.mp4").await?;
+    /// let length = file.metadata().await?.len();
+    /// let response = Response::from_reader(file, length, "video/mp4");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_reader<R>(reader: R, content_length: u64, content_type: &str) -> Response
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let mut response = Response::new(200).with_header("Content-Type", content_type);
+        response.body = Body::Reader {
+            reader: Box::new(reader),
+            length: content_length,
+        };
+        response
+    }
+
+    /// Creates a response sending the file at `path` as an attachment named `filename`.
+    ///
+    /// Browsers offer to save the file instead of displaying it. The `Content-Type` is guessed
+    /// from the extension of `filename`, and names that aren't plain ASCII are sent encoded in
+    /// `filename*`, with an ASCII fallback for older clients. Missing files answer `404`.
+    ///
+    /// # Example
+    /// ```rust,no_run
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.add_async_route(Method::Get, "/export", |_req| {
+    ///     Response::download("./reports/latest.csv", "report-2024.csv")
+    /// });
+    /// ```
+    pub async fn download(path: impl AsRef<Path>, filename: &str) -> Response {
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(_) => return Response::new(404),
+        };
+        let length = match file.metadata().await {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            _ => return Response::new(404),
+        };
+        let content_type = files::mime_type(Path::new(filename));
+        Response::from_reader(file, length, content_type)
+            .with_header("Content-Disposition", &content_disposition(filename))
+    }
+
+    /// Creates a `200 OK` response with an HTML body.
+    pub fn html(body: impl Into<String>) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "text/html")
+            .with_body(body.into())
+    }
+
+    /// Creates a `200 OK` HTML response from `template`, replacing every `{{ name }}` with the
+    /// HTML-escaped value of `name` in `vars`.
+    ///
+    /// Placeholders missing from `vars` are left empty, see `html_template_strict` to fail on
+    /// them instead.
+    ///
+    /// # Example
+    /// ```
+    /// use std::collections::HashMap;
+    /// use mini_rest::response::Response;
+    /// let vars = HashMap::from([("name", "<ana>".to_string())]);
+    /// let response = Response::html_template("<p>Hello {{ name }}</p>", &vars);
+    /// assert_eq!(response.body(), b"<p>Hello &lt;ana&gt;</p>");
+    /// ```
+    pub fn html_template(template: &str, vars: &HashMap<&str, String>) -> Response {
+        let page = render(template, vars, false).unwrap_or_default();
+        Response::html(page)
+    }
+
+    /// Same as `html_template`, but a placeholder missing from `vars` is a bug of the page, so
+    /// the handler gets a `500 Internal Server Error` to return instead.
+    pub fn html_template_strict(
+        template: &str,
+        vars: &HashMap<&str, String>,
+    ) -> Result<Response, Response> {
+        match render(template, vars, true) {
+            Ok(page) => Ok(Response::html(page)),
+            Err(name) => {
+                logging::error(format_args!("Template placeholder `{}` has no value", name));
+                Err(Response::new(500))
+            }
+        }
+    }
+
+    /// Adds a header to the response.
+    ///
+    /// Values never replace each other: a header added several times, like `Set-Cookie`, is sent
+    /// as one line per value, in the order they were added.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// let response = Response::new(204)
+    ///     .with_header("Set-Cookie", "session=abc; HttpOnly")
+    ///     .with_header("Set-Cookie", "theme=dark");
+    /// assert_eq!(response.headers().len(), 2);
+    /// ```
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the `Cache-Control` header, replacing any previous one.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::response::{CacheControl, Response};
+    /// let response = Response::new(200)
+    ///     .cache_control(CacheControl::new().public().max_age(Duration::from_secs(60)));
+    /// assert_eq!(response.header("Cache-Control"), Some("public, max-age=60"));
+    /// ```
+    pub fn cache_control(self, cache_control: CacheControl) -> Response {
+        self.with_unique_header("Cache-Control", &cache_control.to_string())
+    }
+
+    /// Forbids any cache from storing the response.
+    pub fn no_store(self) -> Response {
+        self.with_unique_header("Cache-Control", "no-store")
+    }
+
+    /// Closes the connection once the response is written, even if the client asked to keep it
+    /// alive.
+    ///
+    /// Meant for answers after which the rest of the connection can't be trusted, like a
+    /// request whose body was left unread. The response is sent with `Connection: close`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// let response = Response::new(400).close_connection();
+    /// assert!(response.closes_connection());
+    /// ```
+    pub fn close_connection(mut self) -> Response {
+        self.close = true;
+        self
+    }
+
+    /// Returns whether the connection is closed after the response, see `close_connection`.
+    pub fn closes_connection(&self) -> bool {
+        self.close
+    }
+
+    /// Returns the kind of the I/O error the response was made from, if any.
+    pub(crate) fn io_error(&self) -> Option<io::ErrorKind> {
+        self.io_error
+    }
+
+    /// Sets a header, replacing every previous value of it.
+    pub(crate) fn with_unique_header(mut self, name: &str, value: &str) -> Response {
+        self.remove_headers(&[name]);
+        self.with_header(name, value)
+    }
+
+    /// Adds `charset` to a textual `Content-Type` that doesn't name its charset.
+    ///
+    /// JSON is always UTF-8 and has no charset parameter, so only `text/*` and the XML and
+    /// JavaScript types get one.
+    pub(crate) fn with_default_charset(self, charset: &str) -> Response {
+        let content_type = match self.header("Content-Type") {
+            Some(content_type) => content_type,
+            None => return self,
+        };
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        let media_type = media_type.to_ascii_lowercase();
+        let textual = media_type.starts_with("text/")
+            || media_type.ends_with("+xml")
+            || matches!(
+                media_type.as_str(),
+                "application/javascript" | "application/xml"
+            );
+        if !textual || content_type.to_ascii_lowercase().contains("charset=") {
+            return self;
+        }
+        let content_type = format!("{}; charset={}", content_type, charset);
+        self.with_unique_header("Content-Type", &content_type)
+    }
+
+    /// Calls `callback` with the amount of body bytes sent so far after every write to the
+    /// socket, like every chunk of a streamed body.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// let (_sender, chunks) = tokio::sync::mpsc::channel(16);
+    /// let response = Response::chunked(chunks, "application/octet-stream")
+    ///     .on_progress(|sent| println!("{} bytes downloaded", sent));
+    /// ```
+    pub fn on_progress<F>(mut self, callback: F) -> Response
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        self.progress = Some(Progress(Box::new(callback)));
+        self
+    }
+
+    /// Replaces the body of the response.
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Sends trailer headers after the last chunk of a [`Response::chunked`] response.
+    ///
+    /// `trailers` is called once every chunk was sent, so it can compute values like checksums
+    /// that are only known at the end. `names` are advertised beforehand in the `Trailer` header
+    /// and only trailers listed there are sent. Other responses ignore the trailers.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// let (_sender, chunks) = tokio::sync::mpsc::channel(16);
+    /// let response = Response::chunked(chunks, "text/plain").with_trailers(&["Server-Timing"], || {
+    ///     vec![("Server-Timing".to_string(), "total;dur=12".to_string())]
+    /// });
+    /// ```
+    pub fn with_trailers<F>(mut self, names: &[&str], trailers: F) -> Response
+    where
+        F: FnOnce() -> Vec<(String, String)> + Send + 'static,
+    {
+        if let Body::Chunked { trailers: slot, .. } = &mut self.body {
+            let names = names.iter().map(|name| name.to_string()).collect();
+            *slot = Some((names, Box::new(trailers)));
+        }
+        self
+    }
+
+    /// Returns the status code of the response.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns the reason phrase sent in the status line.
+    pub fn reason(&self) -> &str {
+        self.reason
+            .as_deref()
+            .unwrap_or_else(|| reason(self.status))
+    }
+
+    /// Returns the value of the first header matching `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the body of the response.
+    ///
+    /// Bodies created with [`Response::from_reader`] or [`Response::chunked`] aren't in memory,
+    /// so they are empty here.
+    pub fn body(&self) -> &[u8] {
+        match &self.body {
+            Body::Bytes(bytes) => bytes,
+            Body::Reader { .. } | Body::Chunked { .. } => &[],
+        }
+    }
+
+    /// Returns the body when it's already in memory, `None` for streamed bodies.
+    pub(crate) fn buffered_body(&self) -> Option<&[u8]> {
+        match &self.body {
+            Body::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Copies a response whose body is in memory, `None` for streamed bodies.
+    ///
+    /// A progress callback isn't copied, it belongs to the original.
+    pub(crate) fn try_clone(&self) -> Option<Response> {
+        Some(Response {
+            status: self.status,
+            reason: self.reason.clone(),
+            headers: self.headers.clone(),
+            body: Body::Bytes(self.buffered_body()?.to_vec()),
+            progress: None,
+            close: self.close,
+            io_error: self.io_error,
+        })
+    }
+
+    /// Returns every header set so far, in the order they were added.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Returns the exact bytes the response is sent as, body and chunk framing included.
+    ///
+    /// Serialized as on a connection staying open without a `Connection` header, and
+    /// without the headers the server adds, like its `default_header`s. Streamed bodies are
+    /// read until they end, a failing reader returns its error.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let wire = Response::html("hi").to_wire().await.unwrap();
+    ///     assert!(wire.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    ///     assert!(wire.ends_with(b"Content-Length: 2\r\n\r\nhi"));
+    /// }
+    /// ```
+    pub async fn to_wire(self) -> io::Result<Vec<u8>> {
+        let mut wire = vec![];
+        self.write_to(&mut wire, None, false).await?;
+        Ok(wire)
+    }
+
+    /// Same as `to_wire`, as text for readable assertions.
+    ///
+    /// Bytes that aren't UTF-8 are replaced with `U+FFFD`, binary bodies are better compared
+    /// with `to_wire`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::response::Response;
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let wire = Response::new(404).with_header("X-Id", "7").to_wire_string().await.unwrap();
+    ///     assert_eq!(wire, "HTTP/1.1 404 Not Found\r\nX-Id: 7\r\nContent-Length: 0\r\n\r\n");
+    /// }
+    /// ```
+    pub async fn to_wire_string(self) -> io::Result<String> {
+        let wire = self.to_wire().await?;
+        Ok(String::from_utf8_lossy(&wire).into_owned())
+    }
+
+    /// Returns the length of the body, `None` when it's chunked and not known in advance.
+    pub(crate) fn body_length(&self) -> Option<u64> {
+        self.body.len()
+    }
+
+    /// Replaces the status code, keeping the rest of the response.
+    pub(crate) fn with_status(mut self, status: u16) -> Response {
+        self.status = status;
+        self
+    }
+
+    /// Removes every header whose name is in `names`, ignoring case.
+    pub(crate) fn remove_headers(&mut self, names: &[&str]) {
+        self.headers
+            .retain(|(key, _)| !names.iter().any(|name| key.eq_ignore_ascii_case(name)));
+    }
+
+    /// Writes the response to the socket.
+    ///
+    /// `Content-Length` is always computed from the body for statuses that have one, replacing
+    /// the one set by the handler with a warning when they differ. `connection` is sent as the
+    /// `Connection` header when present. With `head_only` the body is left out, as answers to
+    /// `HEAD` requests require, and a `Content-Length` set along an empty body is kept since
+    /// it describes the body a `GET` would get.
+    pub(crate) async fn write_to<W>(
+        self,
+        socket: &mut W,
+        connection: Option<&str>,
+        head_only: bool,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        // Informational, `204` and `304` responses never have a body, not even an empty one
+        let bodyless = self.status < 200 || self.status == 204 || self.status == 304;
+        let length = self.body.len();
+        let declared = self.header("Content-Length").is_some();
+        let keep_declared = bodyless || (head_only && declared && length == Some(0));
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason());
+        for (name, value) in &self.headers {
+            if !keep_declared && name.eq_ignore_ascii_case("Content-Length") {
+                // A wrong length would leave the client reading into the next response
+                if value.trim().parse::<u64>().ok() != length {
+                    logging::error(format_args!(
+                        "Content-Length {} doesn't match the body of the {} response, sending {}",
+                        value,
+                        self.status,
+                        length.map_or("it chunked".to_string(), |length| length.to_string())
+                    ));
+                }
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        match length {
+            _ if keep_declared => {}
+            Some(length) => head.push_str(&format!("Content-Length: {}\r\n", length)),
+            None => head.push_str("Transfer-Encoding: chunked\r\n"),
+        }
+        if let Body::Chunked {
+            trailers: Some((names, _)),
+            ..
+        } = &self.body
+        {
+            head.push_str(&format!("Trailer: {}\r\n", names.join(", ")));
+        }
+        if let Some(value) = connection {
+            head.push_str(&format!("Connection: {}\r\n", value));
+        }
+        head.push_str("\r\n");
+
+        if head_only || bodyless {
+            return socket.write_all(head.as_bytes()).await;
+        }
+        let mut progress = self.progress;
+        match self.body {
+            Body::Bytes(body) => {
+                let mut bytes = head.into_bytes();
+                bytes.extend_from_slice(&body);
+                socket.write_all(&bytes).await?;
+                if let Some(Progress(progress)) = &mut progress {
+                    progress(body.len() as u64);
+                }
+                Ok(())
+            }
+            Body::Reader { reader, length } => {
+                socket.write_all(head.as_bytes()).await?;
+                let mut body = ProgressWriter {
+                    inner: socket,
+                    sent: 0,
+                    progress: progress.as_mut(),
+                };
+                let copied = io::copy(&mut reader.take(length), &mut body).await?;
+                // A short body leaves the client waiting for bytes that never come
+                if copied < length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "response body ended before its Content-Length",
+                    ));
+                }
+                Ok(())
+            }
+            Body::Chunked {
+                mut chunks,
+                trailers,
+            } => {
+                socket.write_all(head.as_bytes()).await?;
+                let mut sent = 0;
+                while let Some(chunk) = chunks.recv().await {
+                    // An empty chunk would be read as the end of the body
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    let mut bytes = format!("{:x}\r\n", chunk.len()).into_bytes();
+                    bytes.extend_from_slice(&chunk);
+                    bytes.extend_from_slice(b"\r\n");
+                    socket.write_all(&bytes).await?;
+                    sent += chunk.len() as u64;
+                    if let Some(Progress(progress)) = &mut progress {
+                        progress(sent);
+                    }
+                }
+
+                let mut end = String::from("0\r\n");
+                if let Some((names, trailers)) = trailers {
+                    for (name, value) in trailers() {
+                        if names.iter().any(|known| known.eq_ignore_ascii_case(&name)) {
+                            end.push_str(&format!("{}: {}\r\n", name, value));
+                        }
+                    }
+                }
+                end.push_str("\r\n");
+                socket.write_all(end.as_bytes()).await
+            }
+        }
+    }
+}
+
+/// Socket reporting the bytes written through it to the progress callback of a response.
+struct ProgressWriter<'a, W> {
+    inner: &'a mut W,
+    sent: u64,
+    progress: Option<&'a mut Progress>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ProgressWriter<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.sent += written as u64;
+            if let Some(Progress(progress)) = &mut this.progress {
+                progress(this.sent);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Sends informational `1xx` responses, like `103 Early Hints`, ahead of the final response.
+///
+/// Asynchronous handlers get each one sent as soon as it's written, letting the client start
+/// loading resources while the body is computed. Clients speaking HTTP/1.0 don't understand
+/// them, so they are dropped for those.
+///
+/// # Example
+/// ```
+/// use mini_rest::{request::Method, response::Response, router::Router};
+/// let mut router = Router::new();
+/// router.add_async_route(Method::Get, "/", |req| {
+///     let writer = req.response_writer();
+///     async move {
+///         writer.send_informational(103, &[("Link", "</style.css>; rel=preload; as=style")]);
+///         Response::html("<link rel=\"stylesheet\" href=\"/style.css\">")
+///     }
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct ResponseWriter {
+    pub(crate) sender: Option<mpsc::UnboundedSender<Response>>,
+}
+
+impl ResponseWriter {
+    /// Sends an informational response with `headers`.
+    ///
+    /// Returns false if `status` isn't a `1xx` code other than `101 Switching Protocols`, or
+    /// if the final response was already sent.
+    pub fn send_informational(&self, status: u16, headers: &[(&str, &str)]) -> bool {
+        let sender = match &self.sender {
+            Some(sender) if (100..200).contains(&status) && status != 101 => sender,
+            _ => return false,
+        };
+        let mut response = Response::new(status);
+        for (name, value) in headers {
+            response = response.with_header(name, value);
+        }
+        sender.send(response).is_ok()
+    }
+}
+
+/// Values a handler can answer with.
+///
+/// `Result` answers with either side, so handlers can bail out early with `?` when their error
+/// is a response as well. Errors of a type given to `ServerActions::error_handler` are answered
+/// by it instead.
+///
+/// # Example
+/// ```
+/// use mini_rest::{request::Request, response::Response};
+/// fn user(req: &Request) -> Result<Response, Response> {
+///     let id: u32 = req.query_param("id").and_then(|id| id.parse().ok()).ok_or(Response::new(400))?;
+///     Ok(Response::html(format!("user {}", id)))
+/// }
+/// ```
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse + 'static> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(response) => response.into_response(),
+            Err(error) => error_response(error),
+        }
+    }
+}
+
+/// Answers `error` with the handler the server running the route has for its type, if any.
+fn error_response<E: IntoResponse + 'static>(error: E) -> Response {
+    let mut error = Some(error);
+    let handled = ERROR_HANDLERS.try_with(|handlers| {
+        let handler = handlers.get(&TypeId::of::<E>())?;
+        error.take().map(|error| handler(Box::new(error)))
+    });
+    match (handled, error) {
+        (Ok(Some(response)), _) => response,
+        (_, Some(error)) => error.into_response(),
+        (_, None) => unreachable!("the error is only taken by its handler"),
+    }
+}
+
+/// Answers with the status `io_error_status` gives the kind of the error.
+///
+/// Handlers reading files can then give up with `?`. The error itself isn't sent, it could
+/// reveal paths of the server.
+///
+/// # Example
+/// ```
+/// use mini_rest::{request::Request, response::Response};
+/// fn readme(_req: &Request) -> std::io::Result<Response> {
+///     Ok(Response::html(std::fs::read_to_string("README.md")?))
+/// }
+/// ```
+impl IntoResponse for io::Error {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(io_error_status(self.kind()));
+        response.io_error = Some(self.kind());
+        response
+    }
+}
+
+/// Returns the status answered for an I/O error of `kind`: `404 Not Found` for `NotFound`,
+/// `403 Forbidden` for `PermissionDenied` and `500 Internal Server Error` otherwise.
+///
+/// # Example
+/// ```
+/// use mini_rest::response::io_error_status;
+/// assert_eq!(io_error_status(std::io::ErrorKind::NotFound), 404);
+/// assert_eq!(io_error_status(std::io::ErrorKind::InvalidData), 500);
+/// ```
+pub fn io_error_status(kind: io::ErrorKind) -> u16 {
+    match kind {
+        io::ErrorKind::NotFound => 404,
+        io::ErrorKind::PermissionDenied => 403,
+        _ => 500,
+    }
+}
+
+/// Replaces the `{{ name }}` placeholders of a template, failing with the name of the first
+/// one without value when `strict`.
+fn render(template: &str, vars: &HashMap<&str, String>, strict: bool) -> Result<String, String> {
+    let mut page = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        page.push_str(&rest[..start]);
+        let name = rest[start + 2..start + end].trim();
+        match vars.get(name) {
+            Some(value) => page.push_str(&escape_html(value)),
+            None if strict => return Err(name.to_string()),
+            None => {}
+        }
+        rest = &rest[start + end + 2..];
+    }
+    page.push_str(rest);
+    Ok(page)
+}
+
+/// Returns the `Content-Disposition` of an attachment named `filename`.
+///
+/// The quoted `filename` only keeps printable ASCII, anything else is replaced with `_` and
+/// the exact name goes in `filename*`, percent-encoded as UTF-8 (RFC 5987).
+fn content_disposition(filename: &str) -> String {
+    let mut fallback = String::with_capacity(filename.len());
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                fallback.push('\\');
+                fallback.push(c);
+            }
+            ' '..='~' => fallback.push(c),
+            _ => fallback.push('_'),
+        }
+    }
+    let plain = filename.chars().all(|c| matches!(c, ' '..='~'));
+    if plain {
+        return format!("attachment; filename=\"{}\"", fallback);
+    }
+
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Escapes the characters with a meaning in HTML, so a value can't add markup to a page.
+pub(crate) fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Directives of a `Cache-Control` header.
+#[derive(Debug, Clone, Default)]
+pub struct CacheControl {
+    visibility: Option<&'static str>,
+    max_age: Option<Duration>,
+    no_cache: bool,
+    immutable: bool,
+}
+
+impl CacheControl {
+    pub fn new() -> CacheControl {
+        CacheControl::default()
+    }
+
+    /// Lets shared caches like CDNs store the response.
+    pub fn public(mut self) -> CacheControl {
+        self.visibility = Some("public");
+        self
+    }
+
+    /// Only lets the browser of the user store the response.
+    pub fn private(mut self) -> CacheControl {
+        self.visibility = Some("private");
+        self
+    }
+
+    /// Keeps the response fresh for `max_age`.
+    pub fn max_age(mut self, max_age: Duration) -> CacheControl {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Makes caches check with the server before reusing the response.
+    pub fn no_cache(mut self) -> CacheControl {
+        self.no_cache = true;
+        self
+    }
+
+    /// Tells the response never changes while fresh, so it's never revalidated.
+    pub fn immutable(mut self) -> CacheControl {
+        self.immutable = true;
+        self
+    }
+}
+
+impl fmt::Display for CacheControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut directives = vec![];
+        directives.extend(self.visibility.map(String::from));
+        if self.no_cache {
+            directives.push("no-cache".to_string());
+        }
+        if let Some(max_age) = self.max_age {
+            directives.push(format!("max-age={}", max_age.as_secs()));
+        }
+        if self.immutable {
+            directives.push("immutable".to_string());
+        }
+        write!(f, "{}", directives.join(", "))
+    }
+}
+
+/// Returns the standard reason phrase of a status code.
+///
+/// Codes without a registered phrase get a generic one describing their class.
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        203 => "Non-Authoritative Information",
+        204 => "No Content",
+        205 => "Reset Content",
+        206 => "Partial Content",
+        300 => "Multiple Choices",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        402 => "Payment Required",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        407 => "Proxy Authentication Required",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        425 => "Too Early",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => match status / 100 {
+            1 => "Informational",
+            2 => "Success",
+            3 => "Redirection",
+            4 => "Client Error",
+            5 => "Server Error",
+            _ => "Unknown",
+        },
+    }
+}
+
+/// Parses a complete response read from an upstream server.
+///
+/// Headers describing how the message was framed (`Content-Length`, `Transfer-Encoding`,
+/// `Connection`) are dropped since the body is stored already decoded.
+pub(crate) fn parse_response(bytes: &[u8]) -> Option<Response> {
+    let head_end = bytes.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&bytes[..head_end]).ok()?;
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse::<u16>().ok()?;
+    // Informational responses have no body, the final response starts right after them
+    if (100..200).contains(&status) {
+        return parse_response(&bytes[head_end + 4..]);
+    }
+
+    let mut response = Response::new(status);
+    let mut chunked = false;
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if !name.eq_ignore_ascii_case("Content-Length")
+            && !name.eq_ignore_ascii_case("Connection")
+        {
+            response.headers.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    let body = &bytes[head_end + 4..];
+    response.body = Body::Bytes(if chunked {
+        decode_chunked(body)?
+    } else {
+        body.to_vec()
+    });
+    Some(response)
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`.
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size_line = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size_hex = size_line.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_hex, 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(decoded);
+        }
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
@@ -0,0 +1,582 @@
+//! Matching of requests against the registered routes.
+
+use crate::{
+    cache::ResponseCache,
+    clock::{self, Clock},
+    extract::Handler,
+    idempotency::{Attempt, IdempotencyStore},
+    metrics::RouteStats,
+    request::{Method, Request},
+    response::{IntoResponse, Response},
+};
+use std::{cmp::Ordering, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+pub(crate) type HandlerFuture = Pin<Box<dyn Future<Output = Response> + Send>>;
+pub(crate) type BoxedHandler = Box<dyn Fn(&Request) -> HandlerFuture + Send + Sync>;
+
+/// Set of routes answering requests by method and path.
+///
+/// Paths are patterns: a `:name` segment matches any single segment and a final `*name`
+/// matches the rest of the path, both captured for `Request::param`. When several patterns
+/// match a path the most specific one wins, a literal segment beating a `:name` which beats
+/// a `*name`.
+#[derive(Default)]
+pub struct Router {
+    /// Kept sorted by match priority.
+    routes: Vec<Route>,
+}
+
+/// Handler registered for a method and a path.
+pub struct Route {
+    method: Method,
+    path: String,
+    segments: Vec<Segment>,
+    handler: BoxedHandler,
+    summary: Option<String>,
+    cache: Option<Arc<ResponseCache>>,
+    stream_body: bool,
+    idempotency: Option<Arc<IdempotencyStore>>,
+    stats: Arc<RouteStats>,
+    /// Media types of the bodies accepted, any when `None`.
+    content_types: Option<Vec<String>>,
+    /// Clock of the cache and the idempotency keys, set before them.
+    clock: Arc<dyn Clock>,
+}
+
+/// Description of a registered route.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteInfo {
+    pub method: Method,
+    /// Path pattern as it was registered.
+    pub pattern: String,
+    /// Whether the pattern has `:name` segments.
+    pub has_params: bool,
+    /// Whether the pattern ends with a `*name` segment.
+    pub has_wildcard: bool,
+    /// Short description set with `Route::summary`.
+    pub summary: Option<String>,
+}
+
+/// Piece of a path pattern between two slashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+/// Result of looking for the route of a request.
+pub(crate) enum Match<'a> {
+    /// The route with the segments captured by its pattern.
+    Found(&'a Route, Vec<(String, String)>),
+    /// The path exists but not for the method, with the methods it allows.
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+    /// The path has more segments than the router looks at.
+    TooManySegments,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Registers `handler` to answer the `method` requests sent to `path`.
+    ///
+    /// Routes registered for `GET` answer `HEAD` requests as well. Handlers return anything
+    /// implementing `IntoResponse`, like a `Result` whose error is a response too.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// let mut router = Router::new();
+    /// router.add_route(Method::Get, "/", |_req| Response::html("<h1>Hello, world!</h1>"));
+    /// router.add_route(Method::Get, "/users/:id", |req| {
+    ///     Response::html(format!("user {}", req.param("id").unwrap()))
+    /// });
+    /// ```
+    pub fn add_route<F, R>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(&Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.insert(Route {
+            method,
+            path: path.to_string(),
+            segments: parse_pattern(path),
+            handler: Box::new(move |request| {
+                let response = handler(request).into_response();
+                Box::pin(std::future::ready(response))
+            }),
+            summary: None,
+            cache: None,
+            stream_body: false,
+            idempotency: None,
+            stats: Arc::default(),
+            content_types: None,
+            clock: clock::system(),
+        })
+    }
+
+    /// Registers an asynchronous `handler` to answer the `method` requests sent to `path`.
+    ///
+    /// The handler takes what it needs from the request and returns a future computing the
+    /// response, so it can wait on I/O without blocking the connection.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// let mut router = Router::new();
+    /// router.add_async_route(Method::Get, "/users/:id", |req| {
+    ///     let id = req.param("id").unwrap_or_default().to_string();
+    ///     async move {
+    ///         tokio::time::sleep(Duration::from_millis(10)).await;
+    ///         Response::html(format!("user {}", id))
+    ///     }
+    /// });
+    /// ```
+    pub fn add_async_route<F, Fut, R>(
+        &mut self,
+        method: Method,
+        path: &str,
+        handler: F,
+    ) -> &mut Route
+    where
+        F: Fn(&Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoResponse,
+    {
+        self.insert(Route {
+            method,
+            path: path.to_string(),
+            segments: parse_pattern(path),
+            handler: Box::new(move |request| {
+                let handling = handler(request);
+                Box::pin(async move { handling.await.into_response() })
+            }),
+            summary: None,
+            cache: None,
+            stream_body: false,
+            idempotency: None,
+            stats: Arc::default(),
+            content_types: None,
+            clock: clock::system(),
+        })
+    }
+
+    /// Registers a `handler` taking extractors instead of the request.
+    ///
+    /// Arguments are extracted in order, the first one failing answers the request instead of
+    /// the handler.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{extract::{Path, State}, request::Method, response::Response, router::Router};
+    /// #[derive(Clone)]
+    /// struct Config {
+    ///     greeting: String,
+    /// }
+    ///
+    /// fn greet(State(config): State<Config>, Path(name): Path<String>) -> Response {
+    ///     Response::html(format!("{} {}", config.greeting, name))
+    /// }
+    ///
+    /// let mut router = Router::new();
+    /// router.add_handler(Method::Get, "/greet/:name", greet);
+    /// ```
+    pub fn add_handler<H, Args>(&mut self, method: Method, path: &str, handler: H) -> &mut Route
+    where
+        H: Handler<Args>,
+        Args: 'static,
+    {
+        self.add_route(method, path, move |request| handler.call(request))
+    }
+
+    fn insert(&mut self, route: Route) -> &mut Route {
+        // Routes as specific as the new one keep their precedence over it
+        let index = self
+            .routes
+            .iter()
+            .position(|other| priority(&route.segments, &other.segments) == Ordering::Less)
+            .unwrap_or(self.routes.len());
+        self.routes.insert(index, route);
+        &mut self.routes[index]
+    }
+
+    /// Returns the registered routes, in the order they are tried.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.routes.iter().map(Route::info).collect()
+    }
+
+    /// Returns every method some route answers, for an `Allow` header describing the server.
+    pub(crate) fn methods(&self) -> Vec<Method> {
+        let mut methods = allowed(self.routes.iter().map(|route| &route.method));
+        // `OPTIONS` is answered even without routes
+        if methods.is_empty() {
+            methods.push(Method::Options);
+        }
+        methods
+    }
+
+    /// Returns whether a route streams the body of its requests, see `Route::stream_body`.
+    pub(crate) fn streams_bodies(&self) -> bool {
+        self.routes.iter().any(Route::streams_body)
+    }
+
+    /// Looks for the route answering `method` on a normalized `path`.
+    ///
+    /// Paths with more than `max_segments` segments aren't matched against any route, which
+    /// keeps paths made of thousands of slashes from slowing down matching.
+    pub(crate) fn find(&self, method: &Method, path: &str, max_segments: usize) -> Match<'_> {
+        let segments = path.split('/').take(max_segments.saturating_add(2)).count();
+        // The path starts with a slash, the first split is empty
+        if segments > max_segments.saturating_add(1) {
+            return Match::TooManySegments;
+        }
+        let matching: Vec<(&Route, Vec<(String, String)>)> = self
+            .routes
+            .iter()
+            .filter_map(|route| Some((route, route.captures(path)?)))
+            .collect();
+        let found = matching.iter().position(|(route, _)| {
+            route.method == *method || (*method == Method::Head && route.method == Method::Get)
+        });
+        match found {
+            Some(index) => {
+                let (route, params) = matching.into_iter().nth(index).unwrap();
+                Match::Found(route, params)
+            }
+            None if matching.is_empty() => Match::NotFound,
+            None => {
+                Match::MethodNotAllowed(allowed(matching.iter().map(|(route, _)| &route.method)))
+            }
+        }
+    }
+
+    /// Returns the methods of the routes matching `path`, for the `Allow` header of a request
+    /// refused whatever its routes.
+    ///
+    /// `TRACE` is left out since it's never answered by a route.
+    pub(crate) fn allowed_methods(&self, path: &str, max_segments: usize) -> Vec<Method> {
+        let segments = path.split('/').take(max_segments.saturating_add(2)).count();
+        if segments > max_segments.saturating_add(1) {
+            return vec![];
+        }
+        let matching = self
+            .routes
+            .iter()
+            .filter(|route| route.method != Method::Trace && route.captures(path).is_some());
+        allowed(matching.map(|route| &route.method))
+    }
+}
+
+impl Route {
+    /// Answers a request matching the route.
+    ///
+    /// Synchronous handlers run right away, only asynchronous ones wait for the future.
+    pub(crate) fn handle(&self, request: &Request) -> HandlerFuture {
+        if let Some(response) = self.refuse_content_type(request) {
+            return Box::pin(std::future::ready(response));
+        }
+        let pending = match self
+            .idempotency
+            .as_ref()
+            .and_then(|store| store.begin(request))
+        {
+            None => return self.cached(request),
+            Some(Attempt::Fresh(pending)) => pending,
+            Some(Attempt::Replay(response)) => return Box::pin(std::future::ready(response)),
+            Some(Attempt::InProgress) => return Box::pin(std::future::ready(Response::new(409))),
+            Some(Attempt::Mismatch) => return Box::pin(std::future::ready(Response::new(422))),
+        };
+        let handling = self.cached(request);
+        Box::pin(async move {
+            let response = handling.await;
+            pending.finish(&response);
+            response
+        })
+    }
+
+    /// Answers `request` with the handler, or with a response of the cache if it's enabled.
+    fn cached(&self, request: &Request) -> HandlerFuture {
+        let cache = match &self.cache {
+            Some(cache) => Arc::clone(cache),
+            None => return (self.handler)(request),
+        };
+        if let Some(response) = cache.get(request) {
+            return Box::pin(std::future::ready(response));
+        }
+        let handling = (self.handler)(request);
+        let request = request.clone();
+        Box::pin(async move {
+            let response = handling.await;
+            cache.put(&request, &response);
+            response
+        })
+    }
+
+    /// Reuses the responses of the route for `ttl` instead of calling the handler again.
+    ///
+    /// Responses are stored by request target, and by the request headers listed in their
+    /// `Vary`. Only successful answers to `GET` and `HEAD` with a body in memory are stored,
+    /// handlers can opt a response out with `Cache-Control: no-store`. Answers to requests
+    /// sent with `Authorization` are only stored when marked `public`, `s-maxage` or
+    /// `must-revalidate`.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// let mut router = Router::new();
+    /// router
+    ///     .add_route(Method::Get, "/report", |_req| Response::html("expensive"))
+    ///     .cache_for(Duration::from_secs(30));
+    /// ```
+    pub fn cache_for(&mut self, ttl: Duration) -> &mut Route {
+        self.cache = Some(Arc::new(ResponseCache::new(ttl, Arc::clone(&self.clock))));
+        self
+    }
+
+    /// Hands the body of requests to the handler as it arrives instead of buffering it.
+    ///
+    /// The handler reads it with `Request::body_stream`, `Request::body` stays empty. The body
+    /// is streamed as sent, a `Content-Encoding` isn't undone. When the handler answers before
+    /// reading the whole body, the connection is closed after the response.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// let mut router = Router::new();
+    /// router
+    ///     .add_async_route(Method::Post, "/upload", |req| {
+    ///         let mut body = req.body_stream();
+    ///         async move {
+    ///             let mut size = 0;
+    ///             while let Some(chunk) = body.next().await {
+    ///                 match chunk {
+    ///                     Ok(chunk) => size += chunk.len(),
+    ///                     Err(e) => return Response::new(e.status()),
+    ///                 }
+    ///             }
+    ///             Response::html(format!("{} bytes", size))
+    ///         }
+    ///     })
+    ///     .stream_body();
+    /// ```
+    pub fn stream_body(&mut self) -> &mut Route {
+        self.stream_body = true;
+        self
+    }
+
+    /// Returns whether the body of requests is handed to the handler as it arrives.
+    pub(crate) fn streams_body(&self) -> bool {
+        self.stream_body
+    }
+
+    /// Replays the response to a request sent with an `Idempotency-Key` when the same key
+    /// comes again to the same path within `ttl`, without calling the handler.
+    ///
+    /// Clients retrying a request they got no answer for can't do its side effects twice. A
+    /// key reused while its first request is being answered gets a `409 Conflict`, and one
+    /// reused with another method or body a `422 Unprocessable Content`. Replayed responses
+    /// have an `Idempotent-Replayed: true` header. Server errors aren't stored, so a retry
+    /// runs the handler again, and only the last 1024 keys are kept.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// let mut router = Router::new();
+    /// router
+    ///     .add_route(Method::Post, "/payments", |_req| Response::new(201))
+    ///     .idempotent(Duration::from_secs(24 * 60 * 60));
+    /// ```
+    pub fn idempotent(&mut self, ttl: Duration) -> &mut Route {
+        let clock = Arc::clone(&self.clock);
+        self.idempotency = Some(Arc::new(IdempotencyStore::new(ttl, clock)));
+        self
+    }
+
+    /// Makes the cache and the idempotency keys set afterwards expire by `clock`.
+    #[cfg(test)]
+    pub(crate) fn clock(&mut self, clock: Arc<dyn Clock>) -> &mut Route {
+        self.clock = clock;
+        self
+    }
+
+    /// Answers requests whose body isn't of one of the `types` with `415 Unsupported Media
+    /// Type`, before the handler runs.
+    ///
+    /// Types are matched without case or parameters, so `application/json` accepts
+    /// `application/json; charset=utf-8`, and `text/*` accepts any text. Requests without a
+    /// body nor `Content-Type` go through, a body without type is refused. The response lists
+    /// the accepted types in an `Accept` header.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// let mut router = Router::new();
+    /// router
+    ///     .add_route(Method::Post, "/users", |_req| Response::new(201))
+    ///     .accepts_content_types(&["application/json"]);
+    /// ```
+    pub fn accepts_content_types(&mut self, types: &[&str]) -> &mut Route {
+        self.content_types = Some(types.iter().map(|kind| kind.to_string()).collect());
+        self
+    }
+
+    /// Returns the `415` answered to `request` if its body isn't of an accepted type.
+    fn refuse_content_type(&self, request: &Request) -> Option<Response> {
+        let accepted = self.content_types.as_ref()?;
+        let has_body = !request.body().is_empty()
+            || request.header("Transfer-Encoding").is_some()
+            || request
+                .header("Content-Length")
+                .is_some_and(|length| length.trim() != "0");
+        let mime = match request.header("Content-Type") {
+            Some(value) => value.split(';').next().unwrap_or_default().trim(),
+            None if !has_body => return None,
+            None => "",
+        };
+        let (kind, subtype) = mime.split_once('/').unwrap_or((mime, ""));
+        let matches = accepted.iter().any(|accepted| {
+            match accepted.split_once('/').unwrap_or((accepted, "")) {
+                (accepted_kind, "*") => accepted_kind.eq_ignore_ascii_case(kind),
+                _ => accepted.eq_ignore_ascii_case(mime),
+            }
+        });
+        match matches && !subtype.is_empty() {
+            true => None,
+            false => Some(Response::new(415).with_header("Accept", &accepted.join(", "))),
+        }
+    }
+
+    /// Returns the path pattern the route was registered with.
+    pub(crate) fn pattern(&self) -> &str {
+        &self.path
+    }
+
+    /// Describes what the route does in a sentence, shown in the API docs.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// let mut router = Router::new();
+    /// router
+    ///     .add_route(Method::Get, "/users/:id", |_req| Response::new(200))
+    ///     .summary("Returns a user");
+    /// ```
+    pub fn summary(&mut self, summary: &str) -> &mut Route {
+        self.summary = Some(summary.to_string());
+        self
+    }
+
+    /// Returns the latency statistics of the route.
+    pub(crate) fn stats(&self) -> &Arc<RouteStats> {
+        &self.stats
+    }
+
+    /// Returns the description of the route.
+    pub fn info(&self) -> RouteInfo {
+        RouteInfo {
+            method: self.method.clone(),
+            pattern: self.path.clone(),
+            has_params: self
+                .segments
+                .iter()
+                .any(|segment| matches!(segment, Segment::Param(_))),
+            has_wildcard: self
+                .segments
+                .iter()
+                .any(|segment| matches!(segment, Segment::Wildcard(_))),
+            summary: self.summary.clone(),
+        }
+    }
+
+    /// Returns the segments captured from `path`, `None` if the pattern doesn't match it.
+    fn captures(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut parts = path.strip_prefix('/').unwrap_or(path).split('/');
+        let mut params = vec![];
+        for segment in &self.segments {
+            match segment {
+                Segment::Wildcard(name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    params.push((name.clone(), rest.join("/")));
+                }
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|part| !part.is_empty())?;
+                    params.push((name.clone(), part.to_string()));
+                }
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+/// Splits a path pattern into its segments.
+///
+/// A `*name` segment only counts as a wildcard at the end of the pattern.
+fn parse_pattern(path: &str) -> Vec<Segment> {
+    let parts: Vec<&str> = path.strip_prefix('/').unwrap_or(path).split('/').collect();
+    let last = parts.len() - 1;
+    parts
+        .iter()
+        .enumerate()
+        .map(
+            |(index, part)| match (part.strip_prefix(':'), part.strip_prefix('*')) {
+                (Some(name), _) if !name.is_empty() => Segment::Param(name.to_string()),
+                (_, Some(name)) if index == last => Segment::Wildcard(name.to_string()),
+                _ => Segment::Literal(part.to_string()),
+            },
+        )
+        .collect()
+}
+
+/// Compares how specific two patterns are, `Less` meaning `a` is tried before `b`.
+fn priority(a: &[Segment], b: &[Segment]) -> Ordering {
+    let rank = |segment: &Segment| match segment {
+        Segment::Literal(_) => 0,
+        Segment::Param(_) => 1,
+        Segment::Wildcard(_) => 2,
+    };
+    a.iter().map(rank).cmp(b.iter().map(rank))
+}
+
+/// Returns the `Allow` list of a path from the methods of its routes.
+///
+/// Methods are deduplicated and sorted in a canonical order. `HEAD` comes with `GET` and
+/// `OPTIONS` is always answered by the server; an empty list means there were no routes.
+fn allowed<'a>(methods: impl Iterator<Item = &'a Method>) -> Vec<Method> {
+    let mut allowed: Vec<Method> = vec![];
+    for method in methods {
+        allowed.push(method.clone());
+        if *method == Method::Get {
+            allowed.push(Method::Head);
+        }
+    }
+    if allowed.is_empty() {
+        return allowed;
+    }
+    allowed.push(Method::Options);
+    allowed.sort_by(|a, b| a.rank().cmp(&b.rank()).then(a.as_str().cmp(b.as_str())));
+    allowed.dedup();
+    allowed
+}
+
+/// Returns the value of an `Allow` header.
+pub(crate) fn allow_header(methods: &[Method]) -> String {
+    let methods: Vec<&str> = methods.iter().map(|method| method.as_str()).collect();
+    methods.join(", ")
+}
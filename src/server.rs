@@ -1,187 +1,3093 @@
-use std::{future::Future, pin::Pin};
+#[cfg(feature = "tls")]
+use crate::tls::{self, rustls};
+use crate::{
+    connections::{Connections, PerIp, Tracked},
+    dev, docs,
+    error::{ErrorAction, HandlerPanic, ServerError},
+    extract::{Handler, States},
+    files::StaticFiles,
+    logging::{self, Level},
+    metrics::{ActiveConnection, Counters, History, Metered, MetricsHandle, RecentRequests},
+    middleware::{self, Middleware},
+    pool::{self, BufferPool},
+    proxy::Proxy,
+    request::{self, Expectation, Framing, Limits, Method, Request, Taken, TlsInfo, Version},
+    response::{ErrorHandlers, IntoResponse, Response, ERROR_HANDLERS},
+    router::{self, Match, Route, RouteInfo, Router},
+    timing::ServerTiming,
+};
+use futures_core::Stream;
+use std::{
+    any::{Any, TypeId},
+    future::Future,
+    io,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    path::Path,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::{mpsc, watch, Semaphore},
+    task::JoinSet,
+    time::Instant,
 };
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+type PathRewrite = Box<dyn Fn(&str) -> String + Send + Sync>;
+type ErrorCallback = Box<dyn Fn(&ServerError) -> ErrorAction + Send + Sync>;
+type PanicCallback = Box<dyn Fn(&HandlerPanic) + Send + Sync>;
+type AcceptFilter = Box<dyn Fn(&SocketAddr) -> bool + Send + Sync>;
+type MethodNotAllowed = Box<dyn Fn(&Request, &[Method]) -> Response + Send + Sync>;
+type IoErrorStatus = Box<dyn Fn(io::ErrorKind) -> u16 + Send + Sync>;
+type HeadCheck = Box<dyn Fn(&Request) -> Option<Response> + Send + Sync>;
+type UnknownHost = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// Bounds of the wait after an accept error, doubled on every error in a row.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// Connections turned away by the accept filter in a row before accepting backs off.
+const REJECTIONS_BEFORE_BACKOFF: u32 = 16;
+
+/// Bounds of the unread body dropped before closing a connection, see `linger`.
+const LINGER_TIME: Duration = Duration::from_secs(1);
+/// Longest TLS handshake when no `header_timeout` is set.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_LINGER_BYTES: usize = 1024 * 1024;
+/// Idle read buffers kept by the buffer pool unless `ServerActions::buffer_pool_size` is set.
+const DEFAULT_BUFFER_POOL_SIZE: usize = 64;
+
+/// Path of the endpoint enabled with `status_endpoint`.
+const STATUS_PATH: &str = "/__mini_rest/status";
+/// Path listing the requests kept by `record_requests`, enabled with `status_endpoint` too.
+const REQUESTS_PATH: &str = "/__mini_rest/requests";
+
+pub struct Server {
+    address: String,
+    /// Parsed form of `address`, known when it was given parsed or once the server is bound.
+    socket_addr: Option<SocketAddr>,
+    router: Router,
+    /// Routers set with `vhost`, by lowercase host name.
+    vhosts: Vec<(String, Router)>,
+    /// Listeners added with `add_listener`, next to the one at `address`.
+    listeners: Vec<Listener>,
+    unknown_host: Option<UnknownHost>,
+    middlewares: Vec<Box<dyn Middleware>>,
+    proxies: Vec<Proxy>,
+    statics: Vec<StaticFiles>,
+    shutdown: watch::Sender<bool>,
+    shutdown_grace: Option<Duration>,
+    path_rewrite: Option<PathRewrite>,
+    idle_reaper: Option<(Duration, Duration)>,
+    connections: Arc<Connections>,
+    limits: Limits,
+    max_path_segments: usize,
+    on_error: Option<ErrorCallback>,
+    on_panic: Option<PanicCallback>,
+    trust_proxy: bool,
+    metrics: Arc<Counters>,
+    listener: Option<std::net::TcpListener>,
+    read_buffer_size: usize,
+    use_buffer_pool: bool,
+    buffer_pool: BufferPool,
+    handler_timeout: Option<Duration>,
+    header_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_connection_lifetime: Option<Duration>,
+    /// Set by `max_in_flight`, every request being dispatched holds a permit.
+    in_flight: Option<(Semaphore, Overflow)>,
+    /// Set by `overload_response`, shedding what the limits have no room for.
+    overload_retry_after: Option<Duration>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+    /// Protocols advertised with ALPN on TLS connections.
+    #[cfg(feature = "tls")]
+    alpn: Vec<String>,
+    readiness_delay: Option<Duration>,
+    /// Set as soon as the shutdown is triggered, `shutdown` follows once `readiness_delay` ran.
+    stop: watch::Sender<bool>,
+    accept_filter: Option<AcceptFilter>,
+    per_ip: Option<PerIp>,
+    states: Arc<States>,
+    default_charset: Option<String>,
+    default_headers: Vec<DefaultHeader>,
+    api_docs: Option<String>,
+    status_endpoint: bool,
+    dev_mode: bool,
+    log_level: Level,
+    method_not_allowed: Option<MethodNotAllowed>,
+    io_error_status: Option<IoErrorStatus>,
+    error_handlers: Arc<ErrorHandlers>,
+    reject_early: Option<HeadCheck>,
+    history: Arc<History>,
+    /// Set once `RouterHandle::replace_router` swaps `router` out.
+    replaced_router: Arc<RwLock<Option<Arc<Router>>>>,
+    created: std::time::Instant,
+}
+
+/// Header set with `default_header` or `append_default_header`.
+struct DefaultHeader {
+    name: String,
+    value: String,
+    /// Added even when the response has the header already.
+    append: bool,
+}
+
+/// Additional address a server listens on, added with `ServerActions::add_listener`.
+pub struct Listener {
+    address: String,
+    /// Routes only answering the requests arriving on this listener.
+    routes: Option<Router>,
+}
+
+impl Listener {
+    /// Answers the requests arriving on this listener with the routes of `router` first, then
+    /// with the ones shared by every listener.
+    ///
+    /// Other listeners never reach these routes.
+    pub fn scoped_routes(&mut self, router: Router) -> &mut Listener {
+        self.routes = Some(router);
+        self
+    }
+}
+
+/// Handle used to stop a running server.
+///
+/// Ctrl-C stops the server as well, the handle is for applications that decide when to stop on
+/// their own.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: watch::Sender<bool>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting new connections and starts draining the open ones.
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+/// Handle to list the open connections of a running server and abort them.
+#[derive(Clone)]
+pub struct ConnectionsHandle {
+    connections: Arc<Connections>,
+}
+
+/// Description of an open connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Id of the connection, printed in the logs when it's accepted.
+    pub id: u64,
+    pub remote_addr: Option<SocketAddr>,
+    /// Whether a request is being answered.
+    pub busy: bool,
+    /// Time since the connection last did something.
+    pub idle: Duration,
+}
+
+impl ConnectionsHandle {
+    /// Returns the open connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.connections.list()
+    }
+
+    /// Closes the connection `id` right away, even in the middle of a request.
+    ///
+    /// Returns false if no connection with that id is open.
+    pub fn close_connection(&self, id: u64) -> bool {
+        self.connections.abort(id)
+    }
+}
+
+/// Handle to replace the routes of a running server.
+#[derive(Clone)]
+pub struct RouterHandle {
+    replaced: Arc<RwLock<Option<Arc<Router>>>>,
+}
+
+impl RouterHandle {
+    /// Answers the next requests with the routes of `router` instead of the current ones.
+    ///
+    /// Every request routed after this returns uses the new routes, requests already routed
+    /// finish with the old ones, which are dropped once the last of them is done. Routes
+    /// registered through the server, like health checks, are replaced as well.
+    pub fn replace_router(&self, router: Router) {
+        *self.replaced.write().unwrap() = Some(Arc::new(router));
+    }
+}
+
+/// Lets handlers notice the server is shutting down, to stop long-running work like streams.
+#[derive(Clone)]
+pub struct ShutdownToken {
+    receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownToken {
+    /// Returns whether the shutdown already started.
+    pub fn is_shutting_down(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once the shutdown starts.
+    pub async fn cancelled(&mut self) {
+        // The sender lives as long as the server, a dropped server is shut down anyway
+        let _ = self.receiver.wait_for(|stopping| *stopping).await;
+    }
+}
+
+/// What happens to requests arriving while `ServerActions::max_in_flight` handlers run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait until a handler finishes, in the order they arrived.
+    Wait,
+    /// Answer with `503 Service Unavailable`, telling the client to retry after the delay.
+    Reject { retry_after: Duration },
+}
+
+pub trait ServerActions {
+    fn start(self) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+    /// Same as `start`, but errors that stop the server are returned instead of printed.
+    ///
+    /// ```rust,no_run
+    /// use mini_rest::server::{self, ServerActions};
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let server = server::new("127.0.0.1:8080".to_string());
+    ///     if let Err(e) = server.try_start().await {
+    ///         eprintln!("{}", e);
+    ///         std::process::exit(1);
+    ///     }
+    /// }
+    /// ```
+    fn try_start(self) -> Pin<Box<dyn Future<Output = Result<(), ServerError>> + Send>>;
+    /// Binds the server and returns its stream of accepted connections, to drive a custom
+    /// accept loop instead of `start`.
+    ///
+    /// Connections can be filtered or supervised before being handed back with
+    /// `Incoming::serve`. Shutdown draining is part of `start`, a custom loop decides on its own
+    /// when to stop.
+    ///
+    /// ```rust,no_run
+    /// use mini_rest::server::{self, ServerActions};
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let server = server::new("127.0.0.1:8080".to_string());
+    ///     let mut incoming = server.incoming().await.unwrap();
+    ///     while let Ok((socket, addr)) = incoming.accept().await {
+    ///         if addr.ip().is_loopback() {
+    ///             tokio::spawn(incoming.serve(socket));
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    fn incoming(self) -> Pin<Box<dyn Future<Output = Result<Incoming, ServerError>> + Send>>;
+    /// Binds the listening socket right away instead of when the server starts.
+    ///
+    /// Startup errors like a port already in use are reported here, before anything else
+    /// runs, and the server only goes live once started. Calling it again does nothing, and
+    /// the server keeps the bound address, so port `0` gets resolved to the one picked.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions, ServerInfo};
+    /// let mut server = server::new("127.0.0.1:0".to_string());
+    /// let address = server.bind().unwrap();
+    /// assert_ne!(address.port(), 0);
+    /// assert_eq!(server.port(), i32::from(address.port()));
+    /// ```
+    fn bind(&mut self) -> Result<SocketAddr, ServerError>;
+    fn add_route<F, R>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(&Request) -> R + Send + Sync + 'static,
+        R: IntoResponse;
+    /// Registers a handler taking extractors, see `Router::add_handler`.
+    fn add_handler<H, Args>(&mut self, method: Method, path: &str, handler: H) -> &mut Route
+    where
+        H: Handler<Args>,
+        Args: 'static;
+    /// Shares `state` with every handler, which get a copy through the `State<T>` extractor.
+    ///
+    /// States are stored by type, registering a second value of the same type replaces the
+    /// first one. Values shared across requests go inside an `Arc`.
+    ///
+    /// # Example
+    /// ```
+    /// use std::sync::{atomic::AtomicU64, Arc};
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.state(Arc::new(AtomicU64::new(0)));
+    /// ```
+    fn state<T: Clone + Send + Sync + 'static>(&mut self, state: T);
+    /// Registers an asynchronous handler, see `Router::add_async_route`.
+    fn add_async_route<F, Fut, R>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(&Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoResponse;
+    /// Answers the requests for `host` with the routes of `router`.
+    ///
+    /// The `Host` header of requests is matched without case and without its port, so one
+    /// listener can serve several sites. Requests for other hosts are answered with the routes
+    /// added to the server itself, see `unknown_host` to answer them differently. Middlewares,
+    /// served directories and proxies are shared by every host.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut api = Router::new();
+    /// api.add_route(Method::Get, "/users", |_req| Response::html("[]"));
+    /// let mut server = server::new("0.0.0.0:80".to_string());
+    /// server.vhost("api.example.com", api);
+    /// server.add_route(Method::Get, "/", |_req| Response::html("<h1>www</h1>"));
+    /// ```
+    fn vhost(&mut self, host: &str, router: Router);
+    /// Answers with `handler` the requests whose `Host` matches no `vhost`, instead of the
+    /// routes added to the server.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("0.0.0.0:80".to_string());
+    /// server.vhost("www.example.com", Router::new());
+    /// server.unknown_host(|_req| Response::new(421));
+    /// ```
+    fn unknown_host<F, R>(&mut self, handler: F)
+    where
+        F: Fn(&Request) -> R + Send + Sync + 'static,
+        R: IntoResponse;
+    /// Listens on `address` too, next to the address the server was created with.
+    ///
+    /// Requests arriving there are answered like the others, plus by the routes set with
+    /// `Listener::scoped_routes`, which no other listener reaches. That keeps an admin API off
+    /// a public address. Listeners are bound when the server starts, the addresses they got are
+    /// returned by `Incoming::local_addrs`. Custom accept loops pass the listener returned by
+    /// `Incoming::accept_on` to `Incoming::serve_on`, scoped routes aren't reached otherwise.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, router::Router};
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut admin = Router::new();
+    /// admin.add_route(Method::Post, "/reload", |_req| Response::new(204));
+    /// let mut server = server::new("0.0.0.0:80".to_string());
+    /// server.add_listener("127.0.0.1:9000").scoped_routes(admin);
+    /// server.add_route(Method::Get, "/", |_req| Response::html("<h1>public</h1>"));
+    /// ```
+    fn add_listener(&mut self, address: &str) -> &mut Listener;
+    /// Runs `middleware` around the routing of every request.
+    ///
+    /// Middlewares see requests in the order they were added, the first one answering in
+    /// `before` skips the rest and the routes. Responses go back through the `after` hook of
+    /// the middlewares that ran, the last added first.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{middleware, server::{self, ServerActions}};
+    /// let mut server = server::new("0.0.0.0:80".to_string());
+    /// server.middleware(middleware::redirect_to_https(443));
+    /// ```
+    fn middleware<M: Middleware + 'static>(&mut self, middleware: M);
+    /// Forwards every request under `prefix` that doesn't match a route to an upstream server.
+    ///
+    /// Method, path, headers and body are copied to the upstream request, its answer is
+    /// relayed back to the client. The path is the one routes are matched with, normalized
+    /// and rewritten by `path_rewrite`, under the path of `upstream`. If the upstream can't be
+    /// reached the client receives a `502 Bad Gateway`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.proxy_pass("/api", "http://localhost:9000");
+    /// ```
+    /// # Panics
+    /// If `upstream` isn't a `http://host[:port][/path]` url.
+    fn proxy_pass(&mut self, prefix: &str, upstream: &str);
+    /// Serves the files inside `dir` under `route`, so `/static/app.js` is `dir/app.js`.
+    ///
+    /// Files are streamed from disk with a `Content-Type` guessed from their extension and a
+    /// default `Cache-Control` that the returned `StaticFiles` can change.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.serve_dir("/static", "./public");
+    /// ```
+    fn serve_dir(&mut self, route: &str, dir: impl AsRef<Path>) -> &mut StaticFiles;
+    /// Serves a single-page app built into `dir` under `route`.
+    ///
+    /// Files are served as with `serve_dir`, but paths without a file and without extension
+    /// are answered with the `index.html` of `dir`, so the app routes deep links itself.
+    /// Missing files with an extension, like `/app.js`, still get a `404 Not Found`. Routes
+    /// are tried first, an API can be served next to the app, but directories are tried in
+    /// the order they are served, so other ones under `route` go before.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.add_route(Method::Get, "/api/users", |_req| Response::html("[]"));
+    /// server.serve_spa("/", "./dist");
+    /// ```
+    fn serve_spa(&mut self, route: &str, dir: impl AsRef<Path>) -> &mut StaticFiles;
+    /// Serves a single file under `route`.
+    fn serve_file(&mut self, route: &str, file: impl AsRef<Path>) -> &mut StaticFiles;
+    /// Answers `GET path` with `200 OK` while `ready` returns true, `503 Service Unavailable`
+    /// otherwise, for load balancers probing whether to send traffic.
+    ///
+    /// The check fails as soon as the shutdown is triggered, whatever `ready` says. With a
+    /// `readiness_delay` the server keeps serving normally while probes get the `503`, so load
+    /// balancers stop sending traffic before connections are refused.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.health_check("/ready", || true);
+    /// ```
+    fn health_check<F>(&mut self, path: &str, ready: F) -> &mut Route
+    where
+        F: Fn() -> bool + Send + Sync + 'static;
+    /// Keeps serving for `delay` once shutdown is triggered, only failing the health checks.
+    ///
+    /// Shutdown then happens in order: health checks answer `503` for `delay`, the server stops
+    /// accepting connections, and in-flight connections are waited for up to `shutdown_grace`.
+    /// The delay should be longer than the interval between the probes of the load balancer.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.health_check("/ready", || true);
+    /// server.readiness_delay(Duration::from_secs(5));
+    /// server.shutdown_grace(Duration::from_secs(10));
+    /// ```
+    fn readiness_delay(&mut self, delay: Duration);
+    /// Limits how long in-flight connections are waited for once shutdown is triggered.
+    ///
+    /// Connections still open after `grace` are forcibly closed so the server can exit even if a
+    /// handler is stuck. Without a grace period the server waits for every connection.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.shutdown_grace(Duration::from_secs(10));
+    /// ```
+    fn shutdown_grace(&mut self, grace: Duration);
+    /// Rewrites the path of every request before it's matched against the routes.
+    ///
+    /// `rewrite` receives the path already percent-decoded and normalized (without query), so it
+    /// can lowercase paths or remap legacy urls in one place.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.path_rewrite(|path| path.trim_start_matches("/v1").to_lowercase());
+    /// ```
+    fn path_rewrite<F>(&mut self, rewrite: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static;
+    /// Closes connections that stayed idle for longer than `max_idle`, checking every `interval`.
+    ///
+    /// Connections answering a request are never considered idle. This protects long-running
+    /// servers from clients that keep connections half-open.
+    ///
+    /// # Panics
+    /// If `interval` is zero.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.idle_reaper(Duration::from_secs(5), Duration::from_secs(60));
+    /// ```
+    fn idle_reaper(&mut self, interval: Duration, max_idle: Duration);
+    /// Limits how many header lines a request may have, `100` by default.
+    ///
+    /// Requests with more headers are answered with `431 Request Header Fields Too Large`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.max_headers(50);
+    /// ```
+    fn max_headers(&mut self, max: usize);
+    /// Limits the size of request heads in bytes, `16384` by default.
+    ///
+    /// The size is checked as the head arrives, a client that never ends its head is answered
+    /// with `431 Request Header Fields Too Large` once it sent more.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.max_head_size(8 * 1024);
+    /// ```
+    fn max_head_size(&mut self, max: usize);
+    /// Limits how many segments the path of a request may have, `128` by default.
+    ///
+    /// Longer paths are answered with `400 Bad Request` without being matched against the
+    /// routes, the ones of every `vhost`, listener and replaced router included.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.max_path_segments(32);
+    /// ```
+    fn max_path_segments(&mut self, max: usize);
+    /// Limits the size of request bodies in bytes, unlimited by default.
+    ///
+    /// Requests with bigger bodies are answered with `413 Content Too Large` before their body
+    /// is read. With the `compression` feature, bodies sent with a `Content-Encoding` are
+    /// decoded before reaching handlers and the limit applies to the decoded size, which is
+    /// never more than 16 MiB without a limit. Unknown codings get `415 Unsupported Media Type`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.max_body_size(1024 * 1024);
+    /// ```
+    fn max_body_size(&mut self, max: usize);
+    /// Sets how many bytes each connection reads from its socket at once, `8192` by default.
+    ///
+    /// Bigger buffers take less reads to receive large uploads at the cost of memory for every
+    /// open connection.
+    ///
+    /// # Panics
+    /// If `size` is `0`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.read_buffer_size(64 * 1024);
+    /// ```
+    fn read_buffer_size(&mut self, size: usize);
+    /// Reuses the read buffers of closed connections for new ones when `enabled`, off by
+    /// default.
+    ///
+    /// This saves an allocation of `read_buffer_size` bytes for most connections when many are
+    /// opened and closed. Up to `buffer_pool_size` idle buffers are kept, their memory is only
+    /// freed with the server.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.use_buffer_pool(true);
+    /// server.buffer_pool_size(256);
+    /// ```
+    fn use_buffer_pool(&mut self, enabled: bool);
+    /// Sets how many idle read buffers the buffer pool keeps, `64` by default.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.buffer_pool_size(16);
+    /// ```
+    fn buffer_pool_size(&mut self, size: usize);
+    /// Answers with `503 Service Unavailable` when a handler takes longer than `timeout`.
+    ///
+    /// Only the future of asynchronous handlers can be cut short, synchronous ones always run
+    /// to the end.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.handler_timeout(Duration::from_secs(10));
+    /// ```
+    fn handler_timeout(&mut self, timeout: Duration);
+    /// Answers with `408 Request Timeout` and closes the connection when the head of a request
+    /// isn't fully received `timeout` after its first byte.
+    ///
+    /// This stops clients sending their headers a byte at a time to hold connections open.
+    /// Only the head is limited, a body may take longer to arrive.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.header_timeout(Duration::from_secs(10));
+    /// ```
+    fn header_timeout(&mut self, timeout: Duration);
+    /// Closes the connection when writing a response makes no progress for `timeout`.
+    ///
+    /// This stops clients that never read their responses from holding connections and the
+    /// tasks answering them. The response is abandoned mid-way, the client can't tell it from
+    /// a complete one but by its length. Each write is limited, not the whole response, so a
+    /// long stream keeps going as long as the client reads it.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.write_timeout(Duration::from_secs(30));
+    /// ```
+    fn write_timeout(&mut self, timeout: Duration);
+    /// Closes connections once they have been open for `lifetime`, however busy they are.
+    ///
+    /// A request being answered when the lifetime ends gets its response with
+    /// `Connection: close`, idle connections are closed right away. Clients then reconnect,
+    /// which spreads them again across the instances behind a load balancer.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.max_connection_lifetime(Duration::from_secs(300));
+    /// ```
+    fn max_connection_lifetime(&mut self, lifetime: Duration);
+    /// Runs at most `max` requests at once, across every connection, doing `overflow` with
+    /// the others.
+    ///
+    /// Connections stay open past the limit, it's what their requests do that is limited, so
+    /// a pool of database connections behind the handlers isn't overwhelmed. Middlewares run
+    /// within the limit too.
+    ///
+    /// # Panics
+    /// If `max` is `0`.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, Overflow, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.max_in_flight(64, Overflow::Reject { retry_after: Duration::from_secs(1) });
+    /// ```
+    fn max_in_flight(&mut self, max: usize, overflow: Overflow);
+    /// Sheds the load the limits of the server have no room for, answering it with
+    /// `503 Service Unavailable` and a `Retry-After` of `retry_after` instead of queuing it.
+    ///
+    /// Requests over `max_in_flight` are rejected even with `Overflow::Wait`, the delay of
+    /// `Overflow::Reject` is kept. Connections over `max_per_ip` get the response before being
+    /// closed rather than nothing, except on TLS servers since no handshake was made yet.
+    ///
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// use mini_rest::server::{self, Overflow, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.max_in_flight(256, Overflow::Wait);
+    /// server.max_per_ip(32);
+    /// server.overload_response(Duration::from_secs(5));
+    /// ```
+    fn overload_response(&mut self, retry_after: Duration);
+    /// Serves every connection over TLS with `config`, available with the `tls` feature.
+    ///
+    /// Certificates and client authentication are set up on the `rustls` configuration. The
+    /// session of a connection is then given to its requests, see `Request::tls_info`. The
+    /// handshake is limited by the header timeout, or to 10 seconds without one.
+    ///
+    /// The protocols of the configuration are replaced with the ones set by `alpn`, so only
+    /// `http/1.1` is advertised by default.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use std::sync::Arc;
+    /// use mini_rest::{server::{self, ServerActions}, tls::rustls::{self, pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}}};
+    /// let certificates = CertificateDer::pem_file_iter("cert.pem")
+    ///     .unwrap()
+    ///     .collect::<Result<Vec<_>, _>>()
+    ///     .unwrap();
+    /// let key = PrivateKeyDer::from_pem_file("key.pem").unwrap();
+    /// let config = rustls::ServerConfig::builder()
+    ///     .with_no_client_auth()
+    ///     .with_single_cert(certificates, key)
+    ///     .unwrap();
+    /// let mut server = server::new("127.0.0.1:8443".to_string());
+    /// server.tls(Arc::new(config));
+    /// ```
+    #[cfg(feature = "tls")]
+    fn tls(&mut self, config: Arc<rustls::ServerConfig>);
+    /// Replaces the protocols advertised with ALPN on TLS connections, `http/1.1` by default.
+    ///
+    /// Clients offering protocols but none of these fail the handshake. A connection
+    /// negotiating a protocol other than HTTP/1 is closed right after its handshake, so other
+    /// protocols like `acme-tls/1` can be answered by the handshake alone. HTTP/2 isn't
+    /// supported, `h2` is left out with an error printed.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions, ServerInfo};
+    /// let mut server = server::new("127.0.0.1:8443".to_string());
+    /// server.alpn(&["http/1.1", "http/1.0"]);
+    /// assert_eq!(server.alpn_protocols(), ["http/1.1", "http/1.0"]);
+    /// ```
+    #[cfg(feature = "tls")]
+    fn alpn(&mut self, protocols: &[&str]);
+    /// Adds `; charset=<charset>` to the `Content-Type` of textual responses that don't name
+    /// their charset, so browsers don't have to guess it.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.default_charset("utf-8");
+    /// ```
+    fn default_charset(&mut self, charset: &str);
+    /// Sends `name: value` with every response whose handler didn't set `name` itself.
+    ///
+    /// Setting the same name again replaces its value. See `append_default_header` for
+    /// headers added even when the response has them already.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.default_header("X-Powered-By", "mini_rest");
+    /// ```
+    fn default_header(&mut self, name: &str, value: &str);
+    /// Adds `name: value` to every response, next to the values of `name` it already has.
+    ///
+    /// Meant for headers holding lists, like `Vary`. Each call adds one more value.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.append_default_header("Vary", "Origin");
+    /// ```
+    fn append_default_header(&mut self, name: &str, value: &str);
+    /// Closes the connections whose peer address `filter` rejects right after accepting them.
+    ///
+    /// Nothing is read from rejected connections, which makes it cheaper than a middleware to
+    /// turn away IP addresses. Accepting pauses after every 16 rejected connections in a row,
+    /// for a wait doubled each time from 10ms up to a second, so a flood doesn't keep the
+    /// server spinning.
+    ///
+    /// # Example
+    /// ```
+    /// use std::net::SocketAddr;
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("0.0.0.0:8080".to_string());
+    /// server.accept_filter(|addr: &SocketAddr| addr.ip().is_loopback());
+    /// ```
+    fn accept_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&SocketAddr) -> bool + Send + Sync + 'static;
+    /// Caps the connections open at once from a single IP address to `max`.
+    ///
+    /// A connection from an address at its limit is closed right after being accepted,
+    /// without reading anything. Its slot is freed once a connection closes, however it ends.
+    /// Behind a proxy every client shares the address of the proxy, so leave it unset there.
+    ///
+    /// # Panics
+    /// If `max` is 0, no connection could ever be served.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("0.0.0.0:8080".to_string());
+    /// server.max_per_ip(16);
+    /// ```
+    fn max_per_ip(&mut self, max: usize);
+    /// Reports the errors of the listener to `callback` instead of printing them.
+    ///
+    /// After an accept error the returned `ErrorAction` decides whether the server keeps
+    /// accepting or shuts down, in which case `try_start` returns the error once connections
+    /// are drained. Bind errors don't reach `callback`, they always stop the server and are
+    /// returned by `bind`, `try_start` and `incoming`, or printed by `start`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{error::ErrorAction, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.on_error(|err| {
+    ///     eprintln!("Listener failed: {}", err);
+    ///     ErrorAction::Continue
+    /// });
+    /// ```
+    fn on_error<F>(&mut self, callback: F)
+    where
+        F: Fn(&ServerError) -> ErrorAction + Send + Sync + 'static;
+    /// Calls `callback` when the handling of a request panics, in place of printing it.
+    ///
+    /// Panics of handlers and middlewares are caught: the request is answered with a
+    /// `500 Internal Server Error` and the connection keeps serving the next ones. The panic
+    /// hook of the process still runs first, this only replaces the line the server logs.
+    /// Nothing can be caught when the crate is built with `panic = "abort"`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.on_panic(|panic| eprintln!("Bug in {}: {:?}", panic.path, panic.message));
+    /// ```
+    fn on_panic<F>(&mut self, callback: F)
+    where
+        F: Fn(&HandlerPanic) + Send + Sync + 'static;
+    /// Trusts the headers set by proxies to find the client of a request, off by default.
+    ///
+    /// Only enable it when the server is reachable through a proxy alone, since clients can send
+    /// those headers themselves to spoof their IP. `Forwarded` is read first, then the
+    /// `X-Forwarded-*` headers, see `Request::client_ip` and `Request::forwarded_proto`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.trust_proxy(true);
+    /// ```
+    fn trust_proxy(&mut self, trust: bool);
+    /// Answers `GET /__mini_rest/status` with the state of the server, off by default.
+    ///
+    /// The JSON body has the version of the crate, the seconds since the server was created,
+    /// the open connections and the requests answered. Anyone can read it, so only enable it
+    /// when debugging an instance.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.status_endpoint(std::env::var("DEBUG").is_ok());
+    /// ```
+    fn status_endpoint(&mut self, enabled: bool);
+    /// Keeps the method, path, status and time of the last `capacity` requests answered.
+    ///
+    /// Nothing is recorded by default. Recorded requests are read through `recent_requests`,
+    /// or as JSON at `/__mini_rest/requests` when the `status_endpoint` is enabled.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.record_requests(100);
+    /// let recent = server.recent_requests();
+    /// assert!(recent.list().is_empty());
+    /// ```
+    fn record_requests(&mut self, capacity: usize);
+    /// Returns a handle to read the requests kept by `record_requests` while the server runs.
+    fn recent_requests(&self) -> RecentRequests;
+    /// Prints every request with the response answering it, off by default.
+    ///
+    /// Request lines, status lines, headers and the start of text bodies are printed, binary
+    /// and streamed bodies only with their size. Headers like `Authorization` are printed as
+    /// well, so it's meant for development only, for example enabled in debug builds alone.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.dev_mode(cfg!(debug_assertions));
+    /// ```
+    fn dev_mode(&mut self, enabled: bool);
+    /// Prints only the messages of `level` and the levels before it, everything by default.
+    ///
+    /// Errors go to stderr and the rest to stdout. `Level::Info` keeps the life of the server
+    /// without a line per connection, `Level::Off` prints nothing, callbacks like `on_error`
+    /// still run. The level only applies to this server, messages printed by tasks spawned
+    /// from handlers are all kept.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{logging::Level, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.log_level(Level::Info);
+    /// ```
+    fn log_level(&mut self, level: Level);
+    /// Answers requests whose path has routes but not for their method with `handler`.
+    ///
+    /// The handler gets the methods the path allows, the response is sent with the status
+    /// it sets and an `Allow` header is added unless it has one. `OPTIONS` requests and paths
+    /// without routes never reach it.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.set_method_not_allowed(|_req, allowed: &[Method]| {
+    ///     let allowed: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+    ///     Response::new(405)
+    ///         .with_header("Content-Type", "application/json")
+    ///         .with_body(format!("{{\"error\":\"use {}\"}}", allowed.join(" or ")))
+    /// });
+    /// ```
+    fn set_method_not_allowed<F, R>(&mut self, handler: F)
+    where
+        F: Fn(&Request, &[Method]) -> R + Send + Sync + 'static,
+        R: IntoResponse;
+    /// Answers with `handler` the errors of type `E` route handlers return, in place of
+    /// `E::into_response`.
+    ///
+    /// Applications can then map their own error type to responses in one place, and handlers
+    /// return `Result<R, E>` bailing out with `?` throughout. Registering a second handler for
+    /// the same type replaces the first one.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::{Method, Request}, response::{IntoResponse, Response}};
+    /// use mini_rest::server::{self, ServerActions};
+    /// enum AppError {
+    ///     NotFound,
+    ///     Database(String),
+    /// }
+    /// impl IntoResponse for AppError {
+    ///     fn into_response(self) -> Response {
+    ///         Response::new(500)
+    ///     }
+    /// }
+    /// fn user(req: &Request) -> Result<Response, AppError> {
+    ///     let id = req.param("id").ok_or(AppError::NotFound)?;
+    ///     Ok(Response::html(format!("user {}", id)))
+    /// }
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.add_route(Method::Get, "/users/:id", user);
+    /// server.error_handler(|error: AppError| match error {
+    ///     AppError::NotFound => Response::new(404),
+    ///     AppError::Database(message) => {
+    ///         eprintln!("Database error: {}", message);
+    ///         Response::new(503)
+    ///     }
+    /// });
+    /// ```
+    fn error_handler<E, F, R>(&mut self, handler: F)
+    where
+        E: 'static,
+        F: Fn(E) -> R + Send + Sync + 'static,
+        R: IntoResponse;
+    /// Picks the status of the responses handlers make from an `io::Error`, in place of
+    /// `response::io_error_status`.
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::ErrorKind;
+    /// use mini_rest::{response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// // Hide which files exist but can't be read
+    /// server.set_io_error_status(|kind| match kind {
+    ///     ErrorKind::PermissionDenied => 404,
+    ///     kind => response::io_error_status(kind),
+    /// });
+    /// ```
+    fn set_io_error_status<F>(&mut self, status: F)
+    where
+        F: Fn(io::ErrorKind) -> u16 + Send + Sync + 'static;
+    /// Runs `check` on the head of every request as soon as it arrives, answering with the
+    /// response it returns instead of reading the body and calling the handler.
+    ///
+    /// Requests that can be refused from their headers alone, like uploads without valid
+    /// credentials, fail fast without the server waiting for a large body. When the body
+    /// hadn't fully arrived it's never read and the connection is closed after the response.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.reject_early(|req| match req.bearer_token() {
+    ///     Some("secret") => None,
+    ///     _ => Some(Response::new(401).with_header("WWW-Authenticate", "Bearer")),
+    /// });
+    /// ```
+    fn reject_early<F, R>(&mut self, check: F)
+    where
+        F: Fn(&Request) -> Option<R> + Send + Sync + 'static,
+        R: IntoResponse;
+    /// Returns a handle that triggers the shutdown of the server once it's started.
+    fn shutdown_handle(&self) -> ShutdownHandle;
+    /// Returns a token handlers can keep to notice when the server starts shutting down.
+    ///
+    /// Streaming handlers should stop once the token is cancelled, otherwise their connection
+    /// keeps the shutdown waiting until the grace period runs out.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// let token = server.shutdown_token();
+    /// server.add_route(Method::Get, "/events", move |_req| {
+    ///     let (sender, chunks) = tokio::sync::mpsc::channel(16);
+    ///     let mut token = token.clone();
+    ///     tokio::spawn(async move {
+    ///         loop {
+    ///             tokio::select! {
+    ///                 _ = token.cancelled() => break,
+    ///                 _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {
+    ///                     if sender.send(b"data: tick\n\n".to_vec()).await.is_err() {
+    ///                         break;
+    ///                     }
+    ///                 }
+    ///             }
+    ///         }
+    ///     });
+    ///     Response::chunked(chunks, "text/event-stream")
+    /// });
+    /// ```
+    fn shutdown_token(&self) -> ShutdownToken;
+    /// Returns a handle to read the traffic counters of the server while it runs.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerActions};
+    /// let server = server::new("127.0.0.1:8080".to_string());
+    /// let metrics = server.metrics();
+    /// assert_eq!(metrics.snapshot().bytes_sent, 0);
+    /// ```
+    fn metrics(&self) -> MetricsHandle;
+    /// Returns a handle to list and abort the open connections while the server runs.
+    ///
+    /// Aborting a connection drops the request it's answering, the client sees the connection
+    /// closed without a response.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// let connections = server.connections();
+    /// server.add_route(Method::Delete, "/admin/connections/:id", move |req| {
+    ///     let id = req.param("id").and_then(|id| id.parse().ok());
+    ///     match id.map(|id| connections.close_connection(id)) {
+    ///         Some(true) => Response::new(204),
+    ///         Some(false) => Response::new(404),
+    ///         None => Response::new(400),
+    ///     }
+    /// });
+    /// ```
+    fn connections(&self) -> ConnectionsHandle;
+    /// Returns a handle to swap the routes of the server while it runs.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, router::Router, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.add_route(Method::Get, "/", |_req| Response::html("old home"));
+    /// let routes = server.router_handle();
+    /// // Later, when a feature flag changes
+    /// let mut router = Router::new();
+    /// router.add_route(Method::Get, "/", |_req| Response::html("new home"));
+    /// routes.replace_router(router);
+    /// ```
+    fn router_handle(&self) -> RouterHandle;
+    /// Answers `GET` requests to `path` with an OpenAPI document describing the routes.
+    ///
+    /// The document lists every path with its methods, path parameters and the summaries set
+    /// with `Route::summary`. Schemas of bodies aren't known, so none are described.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server
+    ///     .add_route(Method::Get, "/users/:id", |_req| Response::new(200))
+    ///     .summary("Returns a user");
+    /// server.enable_api_docs("/openapi.json");
+    /// ```
+    fn enable_api_docs(&mut self, path: &str);
+}
+
+pub trait ServerInfo {
+    /// Returns full address of the server.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerInfo};
+    /// let server = server::new("127.0.0.1:8080".to_string());
+    /// assert_eq!(server.address(), "127.0.0.1:8080");
+    /// ```
+    fn address(&self) -> &str;
+    /// Returns the IP address of the server.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerInfo};
+    /// let server = server::new("127.0.0.1:8080".to_string()); assert_eq!(server.ip(), "127.0.0.1");
+    /// ```
+    fn ip(&self) -> &str;
+    /// Returns the port where server is listening.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerInfo};
+    /// let server = server::new("127.0.0.1:8080".to_string());
+    /// assert_eq!(server.port(), 8080);
+    fn port(&self) -> i32;
+    /// Returns the registered routes, in the order they are tried.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions, ServerInfo}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.add_route(Method::Get, "/users/:id", |_req| Response::new(200));
+    /// for route in server.routes() {
+    ///     println!("{} {}", route.method.as_str(), route.pattern);
+    /// }
+    /// ```
+    fn routes(&self) -> Vec<RouteInfo>;
+    /// Returns the protocols advertised with ALPN on TLS connections, see `ServerActions::alpn`.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::server::{self, ServerInfo};
+    /// let server = server::new("127.0.0.1:8443".to_string());
+    /// assert_eq!(server.alpn_protocols(), ["http/1.1"]);
+    /// ```
+    #[cfg(feature = "tls")]
+    fn alpn_protocols(&self) -> &[String];
+}
+
+impl ServerActions for Server {
+    /// To use the `start` function, you need to initialize an async runtime, such as Tokio. Here's an example:
+    /// ```rust,no_run
+    /// use mini_rest::server::{self, ServerActions};
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut server = server::new("127.0.0.1:8080".to_string());
+    ///     server.start().await;
+    /// }
+    /// ```
+    fn start(self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let level = self.log_level;
+        Box::pin(logging::scope(level, async move {
+            if let Err(e) = start_server(self).await {
+                logging::error(format_args!("Error: {}", e));
+            }
+        }))
+    }
+
+    fn try_start(self) -> Pin<Box<dyn Future<Output = Result<(), ServerError>> + Send>> {
+        Box::pin(logging::scope(self.log_level, start_server(self)))
+    }
+
+    fn incoming(self) -> Pin<Box<dyn Future<Output = Result<Incoming, ServerError>> + Send>> {
+        Box::pin(logging::scope(self.log_level, Incoming::bind(self)))
+    }
+
+    fn bind(&mut self) -> Result<SocketAddr, ServerError> {
+        let bound = match &self.listener {
+            Some(listener) => listener.local_addr(),
+            None => {
+                let listener = match self.socket_addr {
+                    Some(addr) => std::net::TcpListener::bind(addr),
+                    None => std::net::TcpListener::bind(&self.address),
+                };
+                listener.and_then(|listener| {
+                    let address = listener.local_addr()?;
+                    self.listener = Some(listener);
+                    Ok(address)
+                })
+            }
+        };
+        match bound {
+            Ok(address) => {
+                self.address = address.to_string();
+                self.socket_addr = Some(address);
+                Ok(address)
+            }
+            Err(source) => Err(bind_error(&self.address, source)),
+        }
+    }
+
+    /// Registers `handler` to answer the `method` requests sent to `path`.
+    ///
+    /// Routes registered for `GET` answer `HEAD` requests as well. Requests with a method no
+    /// route accepts on their path get a `405 Method Not Allowed`, and `OPTIONS` requests are
+    /// answered automatically, both with the `Allow` header of the path.
+    ///
+    /// # Example
+    /// ```
+    /// use mini_rest::{request::Method, response::Response, server::{self, ServerActions}};
+    /// let mut server = server::new("127.0.0.1:8080".to_string());
+    /// server.add_route(Method::Get, "/", |_req| Response::html("<h1>Hello, world!</h1>"));
+    /// ```
+    fn add_route<F, R>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(&Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.router.add_route(method, path, handler)
+    }
+
+    fn add_handler<H, Args>(&mut self, method: Method, path: &str, handler: H) -> &mut Route
+    where
+        H: Handler<Args>,
+        Args: 'static,
+    {
+        self.router.add_handler(method, path, handler)
+    }
+
+    fn state<T: Clone + Send + Sync + 'static>(&mut self, state: T) {
+        Arc::make_mut(&mut self.states).insert(TypeId::of::<T>(), Arc::new(state));
+    }
+
+    fn add_async_route<F, Fut, R>(&mut self, method: Method, path: &str, handler: F) -> &mut Route
+    where
+        F: Fn(&Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoResponse,
+    {
+        self.router.add_async_route(method, path, handler)
+    }
+
+    fn vhost(&mut self, host: &str, router: Router) {
+        let host = host.to_ascii_lowercase();
+        self.vhosts.retain(|(name, _)| *name != host);
+        self.vhosts.push((host, router));
+    }
+
+    fn unknown_host<F, R>(&mut self, handler: F)
+    where
+        F: Fn(&Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.unknown_host = Some(Box::new(move |request| handler(request).into_response()));
+    }
+
+    fn add_listener(&mut self, address: &str) -> &mut Listener {
+        self.listeners.push(Listener {
+            address: address.to_string(),
+            routes: None,
+        });
+        self.listeners.last_mut().unwrap()
+    }
+
+    fn middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    fn proxy_pass(&mut self, prefix: &str, upstream: &str) {
+        let proxy = Proxy::new(prefix, upstream)
+            .unwrap_or_else(|| panic!("Invalid upstream url: {}", upstream));
+        self.proxies.push(proxy);
+    }
+
+    fn health_check<F>(&mut self, path: &str, ready: F) -> &mut Route
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        let stop = self.stop.subscribe();
+        self.add_route(Method::Get, path, move |_req| {
+            let (status, body) = match !*stop.borrow() && ready() {
+                true => (200, "ready"),
+                false => (503, "not ready"),
+            };
+            Response::new(status)
+                .with_header("Content-Type", "text/plain")
+                .no_store()
+                .with_body(body)
+        })
+    }
+
+    fn serve_dir(&mut self, route: &str, dir: impl AsRef<Path>) -> &mut StaticFiles {
+        self.statics.push(StaticFiles::dir(route, dir.as_ref()));
+        self.statics.last_mut().unwrap()
+    }
+
+    fn serve_spa(&mut self, route: &str, dir: impl AsRef<Path>) -> &mut StaticFiles {
+        self.statics.push(StaticFiles::spa(route, dir.as_ref()));
+        self.statics.last_mut().unwrap()
+    }
+
+    fn serve_file(&mut self, route: &str, file: impl AsRef<Path>) -> &mut StaticFiles {
+        self.statics.push(StaticFiles::file(route, file.as_ref()));
+        self.statics.last_mut().unwrap()
+    }
+
+    fn readiness_delay(&mut self, delay: Duration) {
+        self.readiness_delay = Some(delay);
+    }
+
+    fn shutdown_grace(&mut self, grace: Duration) {
+        self.shutdown_grace = Some(grace);
+    }
+
+    fn path_rewrite<F>(&mut self, rewrite: F)
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.path_rewrite = Some(Box::new(rewrite));
+    }
+
+    fn idle_reaper(&mut self, interval: Duration, max_idle: Duration) {
+        assert!(
+            !interval.is_zero(),
+            "The idle reaper needs a non-zero interval"
+        );
+        self.idle_reaper = Some((interval, max_idle));
+    }
+
+    fn max_headers(&mut self, max: usize) {
+        self.limits.max_headers = max;
+    }
+
+    fn max_head_size(&mut self, max: usize) {
+        self.limits.max_head_size = max;
+    }
+
+    fn max_path_segments(&mut self, max: usize) {
+        self.max_path_segments = max;
+    }
+
+    fn max_body_size(&mut self, max: usize) {
+        self.limits.max_body_size = Some(max);
+    }
+
+    fn handler_timeout(&mut self, timeout: Duration) {
+        self.handler_timeout = Some(timeout);
+    }
+
+    fn header_timeout(&mut self, timeout: Duration) {
+        self.header_timeout = Some(timeout);
+    }
+
+    fn write_timeout(&mut self, timeout: Duration) {
+        self.write_timeout = Some(timeout);
+    }
+
+    fn max_connection_lifetime(&mut self, lifetime: Duration) {
+        self.max_connection_lifetime = Some(lifetime);
+    }
+
+    fn max_in_flight(&mut self, max: usize, overflow: Overflow) {
+        assert!(max > 0, "At least one request must be able to run");
+        self.in_flight = Some((Semaphore::new(max), overflow));
+    }
+
+    fn overload_response(&mut self, retry_after: Duration) {
+        self.overload_retry_after = Some(retry_after);
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls(&mut self, config: Arc<rustls::ServerConfig>) {
+        self.tls = Some(with_alpn(&config, &self.alpn));
+    }
+
+    #[cfg(feature = "tls")]
+    fn alpn(&mut self, protocols: &[&str]) {
+        if protocols.contains(&"h2") {
+            logging::sync_scope(self.log_level, || {
+                logging::error(format_args!("HTTP/2 isn't supported, h2 isn't advertised"))
+            });
+        }
+        self.alpn = protocols
+            .iter()
+            .filter(|protocol| **protocol != "h2")
+            .map(|protocol| protocol.to_string())
+            .collect();
+        if let Some(acceptor) = &self.tls {
+            self.tls = Some(with_alpn(acceptor.config(), &self.alpn));
+        }
+    }
+
+    fn read_buffer_size(&mut self, size: usize) {
+        assert!(size > 0, "The read buffer can't be empty");
+        self.read_buffer_size = size;
+    }
+
+    fn use_buffer_pool(&mut self, enabled: bool) {
+        self.use_buffer_pool = enabled;
+    }
+
+    fn buffer_pool_size(&mut self, size: usize) {
+        self.buffer_pool.max_idle = size;
+    }
+
+    fn default_charset(&mut self, charset: &str) {
+        self.default_charset = Some(charset.to_string());
+    }
+
+    fn default_header(&mut self, name: &str, value: &str) {
+        self.default_headers
+            .retain(|header| header.append || !header.name.eq_ignore_ascii_case(name));
+        self.default_headers.push(DefaultHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+            append: false,
+        });
+    }
+
+    fn append_default_header(&mut self, name: &str, value: &str) {
+        self.default_headers.push(DefaultHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+            append: true,
+        });
+    }
+
+    fn accept_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.accept_filter = Some(Box::new(filter));
+    }
+
+    fn max_per_ip(&mut self, max: usize) {
+        assert!(max > 0, "max_per_ip must allow at least one connection");
+        self.per_ip = Some(PerIp::new(max));
+    }
+
+    fn on_error<F>(&mut self, callback: F)
+    where
+        F: Fn(&ServerError) -> ErrorAction + Send + Sync + 'static,
+    {
+        self.on_error = Some(Box::new(callback));
+    }
+
+    fn on_panic<F>(&mut self, callback: F)
+    where
+        F: Fn(&HandlerPanic) + Send + Sync + 'static,
+    {
+        self.on_panic = Some(Box::new(callback));
+    }
+
+    fn trust_proxy(&mut self, trust: bool) {
+        self.trust_proxy = trust;
+    }
+
+    fn status_endpoint(&mut self, enabled: bool) {
+        self.status_endpoint = enabled;
+    }
+
+    fn record_requests(&mut self, capacity: usize) {
+        self.history.capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    fn recent_requests(&self) -> RecentRequests {
+        RecentRequests {
+            history: Arc::clone(&self.history),
+        }
+    }
+
+    fn dev_mode(&mut self, enabled: bool) {
+        self.dev_mode = enabled;
+    }
+
+    fn log_level(&mut self, level: Level) {
+        self.log_level = level;
+    }
+
+    fn set_method_not_allowed<F, R>(&mut self, handler: F)
+    where
+        F: Fn(&Request, &[Method]) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.method_not_allowed = Some(Box::new(move |request, allowed| {
+            handler(request, allowed).into_response()
+        }));
+    }
+
+    fn error_handler<E, F, R>(&mut self, handler: F)
+    where
+        E: 'static,
+        F: Fn(E) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        let handler = move |error: Box<dyn Any>| match error.downcast::<E>() {
+            Ok(error) => handler(*error).into_response(),
+            Err(_) => unreachable!("handlers are registered by the type of their error"),
+        };
+        Arc::make_mut(&mut self.error_handlers).insert(TypeId::of::<E>(), Arc::new(handler));
+    }
+
+    fn set_io_error_status<F>(&mut self, status: F)
+    where
+        F: Fn(io::ErrorKind) -> u16 + Send + Sync + 'static,
+    {
+        self.io_error_status = Some(Box::new(status));
+    }
+
+    fn reject_early<F, R>(&mut self, check: F)
+    where
+        F: Fn(&Request) -> Option<R> + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.reject_early = Some(Box::new(move |request| {
+            check(request).map(IntoResponse::into_response)
+        }));
+    }
+
+    fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            sender: self.stop.clone(),
+        }
+    }
+
+    fn shutdown_token(&self) -> ShutdownToken {
+        ShutdownToken {
+            receiver: self.shutdown.subscribe(),
+        }
+    }
+
+    fn metrics(&self) -> MetricsHandle {
+        MetricsHandle {
+            counters: self.metrics.clone(),
+        }
+    }
+
+    fn connections(&self) -> ConnectionsHandle {
+        ConnectionsHandle {
+            connections: Arc::clone(&self.connections),
+        }
+    }
+
+    fn router_handle(&self) -> RouterHandle {
+        RouterHandle {
+            replaced: Arc::clone(&self.replaced_router),
+        }
+    }
+
+    fn enable_api_docs(&mut self, path: &str) {
+        self.api_docs = Some(request::normalize_path(path));
+    }
+}
+
+impl ServerInfo for Server {
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn ip(&self) -> &str {
+        // The port goes after the last colon, IPv6 addresses are written inside brackets
+        let ip = match self.socket_addr {
+            // Written by `SocketAddr` itself, the port is the only thing after the colon
+            Some(addr) => {
+                let port = addr.port().to_string();
+                &self.address[..self.address.len() - port.len() - 1]
+            }
+            None => self.address.rsplit_once(':').unwrap().0,
+        };
+        ip.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    fn port(&self) -> i32 {
+        if let Some(addr) = self.socket_addr {
+            return i32::from(addr.port());
+        }
+        let (_, port) = self.address.rsplit_once(':').unwrap();
+        port.parse().unwrap()
+    }
+
+    fn routes(&self) -> Vec<RouteInfo> {
+        self.current_router().routes()
+    }
+
+    #[cfg(feature = "tls")]
+    fn alpn_protocols(&self) -> &[String] {
+        &self.alpn
+    }
+}
+
+/// Returns an acceptor for `config` advertising `protocols` with ALPN.
+#[cfg(feature = "tls")]
+fn with_alpn(config: &Arc<rustls::ServerConfig>, protocols: &[String]) -> TlsAcceptor {
+    let mut config = rustls::ServerConfig::clone(config);
+    config.alpn_protocols = protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Start a new HTTP server
+/// # Example
+/// ```
+/// // Create a server with a specific address
+/// use mini_rest::server::{self, ServerInfo};
+/// let server = server::new("192.168.1.253:8080".to_string());
+/// ```
+/// # Paramrests
+/// - `address`: An optional address for the server.
+/// # Returns
+/// A `Server` instance that supports main actions like starting the server or retrieving its details.
+pub fn new(addr: String) -> Server {
+    with_address(addr, None)
+}
+
+/// Returns a server with the defaults, listening at `address` which `socket_addr` is when known.
+fn with_address(address: String, socket_addr: Option<SocketAddr>) -> Server {
+    Server {
+        address,
+        socket_addr,
+        router: Router::new(),
+        vhosts: vec![],
+        listeners: vec![],
+        unknown_host: None,
+        middlewares: vec![],
+        proxies: vec![],
+        statics: vec![],
+        shutdown: watch::Sender::new(false),
+        shutdown_grace: None,
+        path_rewrite: None,
+        idle_reaper: None,
+        connections: Arc::default(),
+        limits: Limits::default(),
+        max_path_segments: 128,
+        on_error: None,
+        on_panic: None,
+        trust_proxy: false,
+        metrics: Arc::default(),
+        listener: None,
+        read_buffer_size: 8 * 1024,
+        use_buffer_pool: false,
+        buffer_pool: BufferPool::new(DEFAULT_BUFFER_POOL_SIZE),
+        handler_timeout: None,
+        header_timeout: None,
+        write_timeout: None,
+        max_connection_lifetime: None,
+        in_flight: None,
+        overload_retry_after: None,
+        #[cfg(feature = "tls")]
+        tls: None,
+        #[cfg(feature = "tls")]
+        alpn: vec!["http/1.1".to_string()],
+        readiness_delay: None,
+        stop: watch::Sender::new(false),
+        accept_filter: None,
+        per_ip: None,
+        states: Arc::default(),
+        default_charset: None,
+        default_headers: vec![],
+        api_docs: None,
+        status_endpoint: false,
+        dev_mode: false,
+        log_level: Level::Debug,
+        method_not_allowed: None,
+        io_error_status: None,
+        error_handlers: Arc::default(),
+        reject_early: None,
+        history: Arc::default(),
+        replaced_router: Arc::default(),
+        created: std::time::Instant::now(),
+    }
+}
+
+/// Start a new HTTP server from an already parsed address
+///
+/// The address is kept parsed: it's bound as is and `ip` and `port` read it without parsing.
+/// # Example
+/// ```
+/// use std::net::SocketAddr;
+/// use mini_rest::server::{self, ServerInfo};
+/// let addr: SocketAddr = "[::1]:8080".parse().unwrap();
+/// let server = server::new_addr(addr);
+/// assert_eq!(server.ip(), "::1");
+/// assert_eq!(server.port(), 8080);
+/// ```
+/// # Returns
+/// A `Server` instance, same as the one returned by `new`.
+pub fn new_addr(addr: SocketAddr) -> Server {
+    with_address(addr.to_string(), Some(addr))
+}
+
+/// Start a new HTTP server on the address given by environment variables
+///
+/// `BIND_ADDR` is the whole address, like `0.0.0.0:8080`. Without it, the address is made of
+/// `HOST` and `PORT`, each falling back to `0.0.0.0` and `8080` when unset or empty, so a port
+/// injected by a container platform is enough and reachable from outside the container.
+/// # Example
+/// ```
+/// use mini_rest::server::{self, ServerInfo};
+/// std::env::set_var("PORT", "3000");
+/// let server = server::from_env().unwrap();
+/// assert_eq!(server.port(), 3000);
+/// ```
+/// # Returns
+/// A `Server` instance, or `ServerError::Env` naming the variable that isn't an address,
+/// an IP or a port.
+pub fn from_env() -> Result<Server, ServerError> {
+    from_vars(|name| std::env::var(name).ok())
+}
+
+/// Same as `from_env`, reading the variables through `lookup`.
+pub(crate) fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Server, ServerError> {
+    let var = |name: &str| lookup(name).filter(|value| !value.is_empty());
+    let invalid = |variable: &str, value: String| ServerError::Env {
+        variable: variable.to_string(),
+        value,
+    };
+    if let Some(bind) = var("BIND_ADDR") {
+        let addr = bind.parse().map_err(|_| invalid("BIND_ADDR", bind))?;
+        return Ok(new_addr(addr));
+    }
+    let ip = match var("HOST") {
+        Some(host) => host.parse().map_err(|_| invalid("HOST", host))?,
+        None => std::net::IpAddr::from([0, 0, 0, 0]),
+    };
+    let port = match var("PORT") {
+        Some(port) => port.parse().map_err(|_| invalid("PORT", port))?,
+        None => 8080,
+    };
+    Ok(new_addr(SocketAddr::new(ip, port)))
+}
+
+/// Start a new HTTP server on a listener that is already bound
+///
+/// Useful when the socket is created by someone else, like a supervisor that keeps it open
+/// across restarts of the process so no connection is refused while the new one starts.
+/// # Example
+/// ```
+/// use mini_rest::server::{self, ServerInfo};
+/// let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+/// let server = server::from_listener(listener).unwrap();
+/// assert_eq!(server.ip(), "127.0.0.1");
+/// ```
+/// # Returns
+/// A `Server` instance accepting connections on `listener`, or the error of reading its address.
+pub fn from_listener(listener: std::net::TcpListener) -> io::Result<Server> {
+    let mut server = new_addr(listener.local_addr()?);
+    server.listener = Some(listener);
+    Ok(server)
+}
+
+/// Start a new HTTP server on a random loopback port, configured by `configure`
+///
+/// The server is bound to `127.0.0.1:0` and already running when this returns, which makes it
+/// handy for examples and tests: nothing depends on a fixed port being free. It must be called
+/// from within a Tokio runtime.
+/// # Example
+/// ```
+/// use mini_rest::{client, request::Method, response::Response, server::{self, ServerActions}};
+/// #[tokio::main]
+/// async fn main() {
+///     let bound = server::ephemeral(|server| {
+///         server.add_route(Method::Get, "/", |_req| Response::html("hello"));
+///     })
+///     .unwrap();
+///     let response = client::get(&bound.url("/")).await.unwrap();
+///     assert_eq!(response.body(), b"hello");
+///     bound.shutdown().await.unwrap();
+/// }
+/// ```
+/// # Returns
+/// The running server with its address, or the error of binding it.
+pub fn ephemeral<F>(configure: F) -> Result<BoundServer, ServerError>
+where
+    F: FnOnce(&mut Server),
+{
+    let mut server = new("127.0.0.1:0".to_string());
+    configure(&mut server);
+    let address = server.bind()?;
+    let shutdown = server.shutdown_handle();
+    let running = tokio::spawn(server.try_start());
+    Ok(BoundServer {
+        address,
+        shutdown,
+        running,
+    })
+}
+
+/// Server started by `ephemeral`, running until `shutdown` is called.
+pub struct BoundServer {
+    address: SocketAddr,
+    shutdown: ShutdownHandle,
+    running: tokio::task::JoinHandle<Result<(), ServerError>>,
+}
+
+impl BoundServer {
+    /// Returns the address the server listens on.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Returns the `http://` url of `path` on the server.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    /// Stops the server and waits for it to finish, returning the error that stopped it if any.
+    pub async fn shutdown(self) -> Result<(), ServerError> {
+        self.shutdown.shutdown();
+        match self.running.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // Only cancelled when the runtime itself shuts down
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Start a new HTTP server on the socket passed by systemd socket activation
+///
+/// systemd binds the socket described by the `.socket` unit and starts the service with it as
+/// file descriptor 3, setting `LISTEN_PID` and `LISTEN_FDS`. The variables are only read, so
+/// it's safe to call from a running runtime: child processes inheriting them have another pid
+/// and ignore them, and the descriptor is only taken by the first call. Only TCP sockets are
+/// supported, the server doesn't serve Unix sockets.
+/// # Example
+/// ```rust,no_run
+/// use mini_rest::server::{self, ServerActions};
+/// #[tokio::main]
+/// async fn main() {
+///     let server = server::from_systemd()
+///         .unwrap_or_else(|| server::new("127.0.0.1:8080".to_string()));
+///     server.start().await;
+/// }
+/// ```
+/// # Returns
+/// `None` when the process wasn't started with a socket.
+#[cfg(unix)]
+pub fn from_systemd() -> Option<Server> {
+    use std::os::unix::io::FromRawFd;
+    // Owned by the first listener made from it, a second one would close it twice
+    static TAKEN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    let fd = systemd_fd(|name| std::env::var(name).ok(), std::process::id())?;
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    // Safety: systemd hands the descriptor over to this process, nothing else owns it
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    from_listener(listener).ok()
+}
+
+/// Returns the descriptor systemd passed to the process `pid`, reading the variables through
+/// `var`.
+#[cfg(unix)]
+pub(crate) fn systemd_fd(var: impl Fn(&str) -> Option<String>, pid: u32) -> Option<i32> {
+    // The first passed descriptor always comes right after stdin, stdout and stderr
+    const FIRST_FD: i32 = 3;
+
+    let listen_pid: u32 = var("LISTEN_PID")?.parse().ok()?;
+    let fds: u32 = var("LISTEN_FDS")?.parse().ok()?;
+    (listen_pid == pid && fds > 0).then_some(FIRST_FD)
+}
+
+/// Starts the HTTP server asynchronously.
+///
+/// This function performs the actual logic for starting the server.
+/// It is separate from the trait `ServerActions` to avoid conflicts and allow more flexibility.
+async fn start_server(server: Server) -> Result<(), ServerError> {
+    let mut incoming = Incoming::bind(server).await?;
+    let server = Arc::clone(&incoming.server);
+    let mut connections = JoinSet::new();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut interrupted = false;
+    let mut shutdown = server.shutdown.subscribe();
+    let mut fatal = None;
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        tokio::select! {
+            // Wait until accept a new petition from a new client
+            accepted = incoming.accept_on() => match accepted {
+                Ok((socket, _, listener)) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    // Proccess connections concurrently
+                    connections.spawn(incoming.serve_on(socket, listener));
+                }
+                Err(e) => {
+                    let error = ServerError::Accept(e);
+                    if server.report(&error) == ErrorAction::Shutdown {
+                        fatal = Some(error);
+                        break;
+                    }
+                    // Errors like running out of file descriptors come back right away, waiting
+                    // avoids spinning on them
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                }
+            },
+            // Forget connections as they finish so the set only holds the open ones
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            // Ctrl-C triggers the shutdown like a `ShutdownHandle` would
+            _ = &mut ctrl_c, if !interrupted => {
+                interrupted = true;
+                server.stop.send_replace(true);
+            }
+            _ = async { shutdown.wait_for(|stopping| *stopping).await.is_ok() } => break,
+        }
+    }
+
+    // Idle connections notice the shutdown and close, busy ones finish their current request
+    server.shutdown.send_replace(true);
+    logging::info(format_args!(
+        "Shutting down, waiting for {} connections to finish...",
+        connections.len()
+    ));
+    let drain = async { while connections.join_next().await.is_some() {} };
+    let drained = match server.shutdown_grace {
+        Some(grace) => tokio::time::timeout(grace, drain).await.is_ok(),
+        None => {
+            drain.await;
+            true
+        }
+    };
+    if !drained {
+        logging::info(format_args!(
+            "Force-closing {} connections",
+            connections.len()
+        ));
+        connections.shutdown().await;
+    }
+    match fatal {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Router of a server, the one it was configured with until it's replaced.
+enum CurrentRouter<'a> {
+    Original(&'a Router),
+    Replaced(Arc<Router>),
+}
+
+impl std::ops::Deref for CurrentRouter<'_> {
+    type Target = Router;
+
+    fn deref(&self) -> &Router {
+        match self {
+            CurrentRouter::Original(router) => router,
+            CurrentRouter::Replaced(router) => router,
+        }
+    }
+}
+
+/// Stream of the connections accepted by a bound server.
+pub struct Incoming {
+    listener: TcpListener,
+    /// Bound `add_listener` listeners, in the order they were added.
+    added: Vec<TcpListener>,
+    /// Listener polled first by the next accept, rotated so a busy one can't starve the others.
+    polled_first: usize,
+    /// Connections turned away by the accept filter in a row since the last pause.
+    rejected: u32,
+    /// Wait after the next run of rejected connections, doubled on every run.
+    backoff: Duration,
+    /// Set while accepting backs off after rejected connections.
+    paused: Option<Pin<Box<tokio::time::Sleep>>>,
+    server: Arc<Server>,
+}
+
+/// Returns the error of binding `address`.
+fn bind_error(address: &str, source: io::Error) -> ServerError {
+    ServerError::Bind {
+        address: address.to_string(),
+        source,
+    }
+}
+
+impl Incoming {
+    async fn bind(mut server: Server) -> Result<Incoming, ServerError> {
+        let bound = match server.listener.take() {
+            Some(listener) => listener
+                .set_nonblocking(true)
+                .and_then(|()| TcpListener::from_std(listener)),
+            None => match server.socket_addr {
+                Some(addr) => TcpListener::bind(addr).await,
+                None => TcpListener::bind(server.address.clone()).await,
+            },
+        };
+        let listener = bound.map_err(|source| bind_error(&server.address, source))?;
+        logging::info(format_args!("Starting listening at {}...", server.address));
+        let mut added = vec![];
+        for added_listener in &server.listeners {
+            let address = &added_listener.address;
+            let listener = TcpListener::bind(address)
+                .await
+                .map_err(|source| bind_error(address, source))?;
+            logging::info(format_args!("Starting listening at {}...", address));
+            added.push(listener);
+        }
+        let server = Arc::new(server);
+        let level = server.log_level;
+        tokio::spawn(logging::scope(
+            level,
+            shut_down_when_stopped(Arc::clone(&server)),
+        ));
+        if let Some((interval, max_idle)) = server.idle_reaper {
+            let reaping = reap_idle_connections(Arc::clone(&server), interval, max_idle);
+            tokio::spawn(logging::scope(level, reaping));
+        }
+        Ok(Incoming {
+            listener,
+            added,
+            polled_first: 0,
+            rejected: 0,
+            backoff: MIN_ACCEPT_BACKOFF,
+            paused: None,
+            server,
+        })
+    }
+
+    /// Returns the address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns the addresses of every listener, the main one first and then the ones added
+    /// with `ServerActions::add_listener` in order.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners().map(TcpListener::local_addr).collect()
+    }
+
+    /// Waits for the next connection the accept filter of the server lets through, on any of
+    /// the listeners.
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (socket, addr, _) = self.accept_on().await?;
+        Ok((socket, addr))
+    }
+
+    /// Waits for the next connection like `accept`, also returning the listener it arrived on:
+    /// `None` for the main one, the index of an `add_listener` listener in the order they were
+    /// added otherwise.
+    pub async fn accept_on(&mut self) -> io::Result<(TcpStream, SocketAddr, Option<usize>)> {
+        std::future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    fn listeners(&self) -> impl Iterator<Item = &TcpListener> {
+        std::iter::once(&self.listener).chain(&self.added)
+    }
+
+    fn poll_accept(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(TcpStream, SocketAddr, Option<usize>)>> {
+        if let Some(paused) = &mut self.paused {
+            if paused.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.paused = None;
+        }
+        let count = self.added.len() + 1;
+        for offset in 0..count {
+            let index = (self.polled_first + offset) % count;
+            let listener = match index {
+                0 => &self.listener,
+                _ => &self.added[index - 1],
+            };
+            loop {
+                match listener.poll_accept(cx) {
+                    Poll::Ready(Ok((_, addr))) if !self.server.allows(&addr) => {
+                        self.rejected += 1;
+                        if self.rejected < REJECTIONS_BEFORE_BACKOFF {
+                            continue;
+                        }
+                        self.rejected = 0;
+                        // Denied clients connecting over and over would keep the task spinning
+                        let mut paused = Box::pin(tokio::time::sleep(self.backoff));
+                        self.backoff = (self.backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        if paused.as_mut().poll(cx).is_pending() {
+                            self.paused = Some(paused);
+                            return Poll::Pending;
+                        }
+                    }
+                    Poll::Pending => break,
+                    poll => {
+                        self.rejected = 0;
+                        self.backoff = MIN_ACCEPT_BACKOFF;
+                        self.polled_first = (index + 1) % count;
+                        let added = index.checked_sub(1);
+                        return poll.map_ok(|(socket, addr)| (socket, addr, added));
+                    }
+                }
+            }
+        }
+        Poll::Pending
+    }
 
-pub struct Server {
-    address: String,
-}
+    /// Returns a future answering every request of `socket` with the routes of the server.
+    ///
+    /// The future owns everything it needs, so it can be spawned as is. The socket is served
+    /// as one of the main listener, see `serve_on` for the ones of added listeners.
+    pub fn serve(&self, socket: TcpStream) -> impl Future<Output = ()> + Send + 'static {
+        self.serve_on(socket, None)
+    }
 
-pub trait ServerActions {
-    fn start(self) -> Pin<Box<dyn Future<Output = ()> + Send>>;
-    fn add_route<F>(&mut self, path: &str, handler: F)
-    where
-        F: Fn() + Send + Sync + 'static;
+    /// Returns a future answering every request of `socket` like `serve`, with the routes
+    /// scoped to `listener` as returned by `accept_on` too.
+    pub fn serve_on(
+        &self,
+        socket: TcpStream,
+        listener: Option<usize>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        handle_client(socket, Arc::clone(&self.server), listener)
+    }
 }
 
-pub trait ServerInfo {
-    /// Returns full address of the server.
-    ///
-    /// # Example
-    /// ```
-    /// use mini_rest::server::{self, ServerInfo};
-    /// let server = server::new("127.0.0.1:8080".to_string());
-    /// assert_eq!(server.address(), "127.0.0.1:8080");
-    /// ```
-    fn address(&self) -> &str;
-    /// Returns the IP address of the server.
-    ///
-    /// # Example
-    /// ```
-    /// use mini_rest::server::{self, ServerInfo};
-    /// let server = server::new("127.0.0.1:8080".to_string()); assert_eq!(server.ip(), "127.0.0.1");
-    /// ```
-    fn ip(&self) -> &str;
-    /// Returns the port where server is listening.
-    ///
-    /// # Example
-    /// ```
-    /// use mini_rest::server::{self, ServerInfo};
-    /// let server = server::new("127.0.0.1:8080".to_string());
-    /// assert_eq!(server.port(), 8080);
-    fn port(&self) -> i32;
+impl Stream for Incoming {
+    type Item = io::Result<(TcpStream, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut()
+            .poll_accept(cx)
+            .map_ok(|(socket, addr, _)| (socket, addr))
+            .map(Some)
+    }
 }
 
-impl ServerActions for Server {
-    /// To use the `start` function, you need to initialize an async runtime, such as Tokio. Here's an example:
-    /// ```rust,no_run
-    /// use mini_rest::server::{self, ServerActions};
-    /// #[tokio::main]
-    /// async fn main() {
-    ///     let mut server = server::new("127.0.0.1:8080".to_string());
-    ///     server.start().await;
-    /// }
-    /// ```
-    fn start(self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
-        Box::pin(async move {
-            if let Err(e) = start_server(self).await {
-                eprintln!("Error: {}", e);
-            }
-        })
+impl Server {
+    /// Makes the open connections idle for as long as `clock` says.
+    #[cfg(test)]
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn crate::clock::Clock>) {
+        self.connections = Arc::new(Connections::with_clock(clock));
     }
-    // TODO: Document this
-    fn add_route<F>(&mut self, path: &str, _handler: F)
-    where
-        F: Fn() + Send + Sync + 'static,
-    {
-        // #TOREMOVE
-        println!("Test path: {}", path);
+
+    /// Returns whether a connection from `addr` passes the accept filter, if there's one.
+    fn allows(&self, addr: &SocketAddr) -> bool {
+        let allowed = self
+            .accept_filter
+            .as_ref()
+            .is_none_or(|filter| filter(addr));
+        if !allowed {
+            // Custom accept loops call this from their own tasks
+            logging::sync_scope(self.log_level, || {
+                logging::debug(format_args!("Rejected connection from {}", addr))
+            });
+        }
+        allowed
     }
-}
 
-impl ServerInfo for Server {
-    fn address(&self) -> &str {
-        &self.address
+    /// Returns the routes scoped to the listener `request` arrived on, if it has some.
+    fn scoped_router(&self, request: &Request) -> Option<&Router> {
+        let index = request.listener?;
+        self.listeners.get(index)?.routes.as_ref()
     }
 
-    fn ip(&self) -> &str {
-        let (ip, _) = self.address.split_once(':').unwrap();
-        ip
+    /// Returns the router answering requests right now.
+    fn current_router(&self) -> CurrentRouter<'_> {
+        match self.replaced_router.read().unwrap().clone() {
+            Some(replaced) => CurrentRouter::Replaced(replaced),
+            None => CurrentRouter::Original(&self.router),
+        }
     }
 
-    fn port(&self) -> i32 {
-        let (_, port) = self.address.split_once(':').unwrap();
-        port.parse().unwrap()
+    /// Returns the router answering `request` by its `Host`, or the `unknown_host` handler.
+    fn router_for(&self, request: &Request) -> Result<CurrentRouter<'_>, &UnknownHost> {
+        let host = request.header("Host").map_or("", middleware::strip_port);
+        let vhost = self
+            .vhosts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(host));
+        match (vhost, &self.unknown_host) {
+            (Some((_, router)), _) => Ok(CurrentRouter::Original(router)),
+            (None, Some(handler)) => Err(handler),
+            (None, None) => Ok(self.current_router()),
+        }
+    }
+
+    /// Hands an error to the `on_error` callback, printing it when there's none.
+    pub(crate) fn report(&self, error: &ServerError) -> ErrorAction {
+        match &self.on_error {
+            Some(on_error) => on_error(error),
+            None => {
+                logging::error(format_args!("Error: {}", error));
+                ErrorAction::Continue
+            }
+        }
     }
 }
 
-/// Start a new HTTP server
-/// # Example
-/// ```
-/// // Create a server with a specific address
-/// use mini_rest::server::{self, ServerInfo};
-/// let server = server::new("192.168.1.253:8080".to_string());
-/// ```
-/// # Paramrests
-/// - `address`: An optional address for the server.
-/// # Returns
-/// A `Server` instance that supports main actions like starting the server or retrieving its details.
-pub fn new(addr: String) -> Server {
-    Server { address: addr }
+/// Shuts the connections down once the shutdown is triggered, after the `readiness_delay`
+/// during which only health checks know about it.
+pub(crate) async fn shut_down_when_stopped(server: Arc<Server>) {
+    let mut stop = server.stop.subscribe();
+    // The server lives as long as this task, so the sender is never dropped
+    let _ = stop.wait_for(|stopping| *stopping).await;
+    if let Some(delay) = server.readiness_delay {
+        logging::info(format_args!(
+            "Failing health checks for {:?} before shutting down",
+            delay
+        ));
+        tokio::time::sleep(delay).await;
+    }
+    server.shutdown.send_replace(true);
 }
 
-/// Starts the HTTP server asynchronously.
-///
-/// This function performs the actual logic for starting the server.
-/// It is separate from the trait `ServerActions` to avoid conflicts and allow more flexibility.
-async fn start_server(server: Server) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(server.address.clone()).await.unwrap();
-    println!("Starting listening at {}...", server.address);
+/// Periodically closes the idle connections until the server shuts down.
+async fn reap_idle_connections(server: Arc<Server>, interval: Duration, max_idle: Duration) {
+    let mut shutdown = server.shutdown.subscribe();
+    let mut ticks = tokio::time::interval(interval);
     loop {
-        // Wait until accept a new petition from a new client
-        match listener.accept().await {
-            Ok((socket, _)) => {
-                // Proccess connections concurrently
-                tokio::spawn(async move {
-                    handle_client(socket).await;
-                });
+        tokio::select! {
+            _ = ticks.tick() => {
+                let closed = server.connections.close_idle(max_idle);
+                if closed > 0 {
+                    logging::info(format_args!("Closing {} idle connections", closed));
+                }
             }
-            Err(e) => println!("Error in acception: {}", e),
+            _ = shutdown.wait_for(|stopping| *stopping) => break,
         }
     }
 }
 
-// TODO: Document this
-async fn handle_client(mut socket: TcpStream) {
-    println!(
-        "New client connected, Remote addr {:?}",
-        socket.peer_addr().unwrap()
-    );
-    let mut buffer = [0; 1024];
+/// Serves every request sent through a client connection.
+///
+/// Requests are answered one after another while the connection is kept alive, the connection
+/// is closed once a request asks for it (explicitly or by using HTTP/1.0 defaults) or when the
+/// server is shutting down.
+pub(crate) async fn handle_client(socket: TcpStream, server: Arc<Server>, listener: Option<usize>) {
+    let level = server.log_level;
+    logging::scope(level, serve_socket(socket, server, listener)).await
+}
+
+/// Serves the connection of `handle_client`, with the log level of the server set.
+async fn serve_socket(socket: TcpStream, server: Arc<Server>, listener: Option<usize>) {
+    let remote_addr = socket.peer_addr().ok();
+    // Held until the connection is done with, the socket is closed right away without one
+    let _slot = match (&server.per_ip, remote_addr) {
+        (Some(per_ip), Some(addr)) => match per_ip.acquire(addr.ip()) {
+            Some(slot) => Some(slot),
+            None => {
+                logging::debug(format_args!(
+                    "Rejected connection from {}, too many open",
+                    addr
+                ));
+                shed_connection(&server, socket).await;
+                return;
+            }
+        },
+        _ => None,
+    };
+    // Registered before the handshake, so closing and shutting down reach it meanwhile
+    let tracked = server.connections.register(remote_addr);
+    let _active = ActiveConnection::new(&server.metrics);
+    logging::debug(format_args!(
+        "New client connected (id {}), Remote addr {:?}",
+        tracked.id(),
+        remote_addr
+    ));
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = &server.tls {
+        let timeout = server.header_timeout.unwrap_or(TLS_HANDSHAKE_TIMEOUT);
+        let handshake = tokio::time::timeout(timeout, acceptor.accept(socket));
+        let mut shutdown = server.shutdown.subscribe();
+        let accepted = tokio::select! {
+            accepted = handshake => {
+                accepted.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+            }
+            _ = shutdown.wait_for(|stopping| *stopping) => Err(io::ErrorKind::Interrupted.into()),
+            _ = tracked.closed() => Err(io::ErrorKind::ConnectionAborted.into()),
+            _ = tracked.aborted() => Err(io::ErrorKind::ConnectionAborted.into()),
+        };
+        match accepted {
+            Ok(mut stream) => {
+                let info = Arc::new(tls::info(stream.get_ref().1));
+                match info.alpn.as_deref() {
+                    None | Some("http/1.1" | "http/1.0") => {
+                        serve_client(stream, remote_addr, listener, &server, &tracked, Some(info))
+                            .await
+                    }
+                    // Nothing else is spoken here, answering in HTTP/1 would confuse the client
+                    Some(protocol) => {
+                        logging::debug(format_args!(
+                            "Closing {:?}, negotiated {}",
+                            remote_addr, protocol
+                        ));
+                        let _ = stream.shutdown().await;
+                    }
+                }
+            }
+            Err(e) => logging::debug(format_args!(
+                "TLS handshake with {:?} failed: {}",
+                remote_addr, e
+            )),
+        }
+        return;
+    }
+    serve_client(socket, remote_addr, listener, &server, &tracked, None).await
+}
+
+/// Answers the requests of an accepted connection, registered with the open ones as `tracked`.
+async fn serve_client<S>(
+    socket: S,
+    remote_addr: Option<SocketAddr>,
+    listener: Option<usize>,
+    server: &Server,
+    tracked: &Tracked<'_>,
+    tls: Option<Arc<TlsInfo>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    // Counting on the socket itself covers heads, chunk framing and streamed bodies alike
+    let socket = WriteTimeout::new(socket, server.write_timeout);
+    let mut socket = Metered::new(socket, server.metrics.clone());
+    let served = tokio::select! {
+        _ = serve_connection(&mut socket, server, tracked, remote_addr, listener, tls) => true,
+        // Dropping the future drops the request being answered, no lock is held across awaits
+        _ = tracked.aborted() => {
+            logging::debug(format_args!("Aborted connection {}", tracked.id()));
+            false
+        }
+    };
+    if served {
+        // TLS clients expect a `close_notify` before the connection ends
+        let _ = socket.shutdown().await;
+    }
+}
+
+/// Reads and answers the requests of a connection until it should be closed.
+async fn serve_connection<S>(
+    socket: &mut Metered<S>,
+    server: &Server,
+    tracked: &Tracked<'_>,
+    remote_addr: Option<SocketAddr>,
+    listener: Option<usize>,
+    tls: Option<Arc<TlsInfo>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let metrics = &server.metrics;
+    let pool = server.use_buffer_pool.then_some(&server.buffer_pool);
+    let mut buffer = pool::read_buffer(pool, server.read_buffer_size, metrics);
     let mut content: Vec<u8> = vec![];
-    let mut content_length: usize = 0;
+    // Chunked body of the request at the start of `content`, decoded as it arrives
+    let mut pending = None;
+    let mut shutdown = server.shutdown.subscribe();
+    // Set while the head of a request is being received
+    let mut head_deadline: Option<Instant> = None;
+    // Whether the head at the start of `content` went through `reject_early` already
+    let mut head_checked = false;
+    // Whether the head at the start of `content` was looked at for `Expect: 100-continue`
+    let mut expectation_checked = false;
+    let expires = server
+        .max_connection_lifetime
+        .map(|lifetime| Instant::now() + lifetime);
+    let expired = || expires.is_some_and(|expires| Instant::now() >= expires);
+    let prepare = |request: &mut Request| {
+        request.remote_addr = remote_addr;
+        request.listener = listener;
+        request.trust_proxy = server.trust_proxy;
+        request.states = Some(Arc::clone(&server.states));
+        request.tls = tls.clone();
+    };
 
     loop {
-        match socket.read(&mut buffer).await {
+        // A ping between requests leaves the connection idle, not receiving a request
+        request::skip_empty_lines(&mut content);
+        if let (Some(check), false) = (&server.reject_early, head_checked) {
+            if let Some(mut head) = request::peek_head(&content, &server.limits) {
+                head_checked = true;
+                prepare(&mut head);
+                if let Some(response) = check(&head) {
+                    head_deadline = None;
+                    head_checked = false;
+                    expectation_checked = false;
+                    metrics.requests.fetch_add(1, Ordering::Relaxed);
+                    // A body that fully arrived is dropped, otherwise it's left unread
+                    let whole = matches!(
+                        request::take_request_or_head(
+                            &mut content,
+                            &mut pending,
+                            &server.limits,
+                            |_| false
+                        ),
+                        Some(Ok(_))
+                    );
+                    let keep_alive =
+                        whole && head.keep_alive() && !*shutdown.borrow() && !expired();
+                    if !respond(server, socket, &head, response, keep_alive).await {
+                        if !whole {
+                            linger(socket).await;
+                        }
+                        break;
+                    }
+                    tracked.touch(false);
+                    continue;
+                }
+            }
+        }
+
+        // Clients sending `Expect: 100-continue` wait for a go-ahead before sending the body
+        if !expectation_checked && request::has_head(&content) {
+            expectation_checked = true;
+            match request::expectation(&content, &server.limits) {
+                Some(Expectation::Continue) => {
+                    if let Err(e) = socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await {
+                        report_write_error(&e);
+                        break;
+                    }
+                }
+                Some(Expectation::TooLarge) => {
+                    // Refused before the client spends bandwidth on the body
+                    logging::debug(format_args!(
+                        "Refused a request whose body is over the limit, closing connection"
+                    ));
+                    metrics.requests.fetch_add(1, Ordering::Relaxed);
+                    let response = Response::new(417);
+                    if let Err(e) = response.write_to(&mut *socket, Some("close"), false).await {
+                        report_write_error(&e);
+                    }
+                    linger(socket).await;
+                    break;
+                }
+                None => {}
+            }
+        }
+
+        // Answer every request already buffered before reading again
+        let taken =
+            request::take_request_or_head(&mut content, &mut pending, &server.limits, |request| {
+                streams_body(server, request)
+            });
+        match taken {
+            Some(Ok(taken)) => {
+                let (mut request, framing) = match taken {
+                    Taken::Whole(request) => (request, None),
+                    Taken::Head(request, framing) => (request, Some(framing)),
+                };
+                head_deadline = None;
+                head_checked = false;
+                expectation_checked = false;
+                tracked.touch(true);
+                metrics.requests.fetch_add(1, Ordering::Relaxed);
+                prepare(&mut request);
+                let keep_alive = request.keep_alive() && !*shutdown.borrow();
+                let mut unread = false;
+                let answered = match framing {
+                    None => answer(server, &mut request, socket).await,
+                    Some(framing) => {
+                        let (answered, complete) =
+                            answer_streamed(server, &mut request, socket, &mut content, framing)
+                                .await;
+                        unread = !complete;
+                        answered
+                    }
+                };
+                let response = match answered {
+                    Ok(response) => response,
+                    Err(e) => {
+                        report_write_error(&e);
+                        break;
+                    }
+                };
+                // Checked once answered, the lifetime may have ended while the handler ran
+                let keep_alive = keep_alive && !unread && !expired();
+                if !respond(server, socket, &request, response, keep_alive).await {
+                    if unread {
+                        linger(socket).await;
+                    }
+                    break;
+                }
+                tracked.touch(false);
+                continue;
+            }
+            Some(Err(e)) => {
+                logging::debug(format_args!(
+                    "Rejected request ({:?}), closing connection",
+                    e
+                ));
+                let response = Response::new(e.status());
+                if let Err(e) = response.write_to(&mut *socket, Some("close"), false).await {
+                    report_write_error(&e);
+                }
+                break;
+            }
+            None => {}
+        }
+
+        head_deadline = match (server.header_timeout, request::has_head(&content)) {
+            (Some(timeout), false) if !content.is_empty() => {
+                Some(head_deadline.unwrap_or_else(|| Instant::now() + timeout))
+            }
+            _ => None,
+        };
+        let read = tokio::select! {
+            read = socket.read(&mut buffer) => Some(read),
+            _ = tokio::time::sleep_until(head_deadline.unwrap_or_else(Instant::now)), if head_deadline.is_some() => None,
+            // A request being received is still answered, only idle connections are closed
+            _ = shutdown.wait_for(|stopping| *stopping), if content.is_empty() => {
+                logging::debug(format_args!("Closing idle connection for shutdown"));
+                break;
+            }
+            _ = tokio::time::sleep_until(expires.unwrap_or_else(Instant::now)), if expires.is_some() && content.is_empty() => {
+                logging::debug(format_args!("Closing idle connection at the end of its lifetime"));
+                break;
+            }
+            _ = tracked.closed() => break,
+        };
+        let Some(read) = read else {
+            logging::debug(format_args!(
+                "Timed out receiving a request head, closing connection"
+            ));
+            let response = Response::new(408);
+            if let Err(e) = response.write_to(&mut *socket, Some("close"), false).await {
+                report_write_error(&e);
+            }
+            break;
+        };
+        match read {
+            Ok(0) if content.is_empty() => {
+                logging::debug(format_args!("Client disconnected"));
+                break;
+            }
             Ok(0) => {
-                println!("Client disconnected");
+                // The rest of the request will never come, the client may still read an answer
+                logging::debug(format_args!(
+                    "Client disconnected in the middle of a request"
+                ));
+                let response = Response::new(400);
+                if let Err(e) = response.write_to(&mut *socket, Some("close"), false).await {
+                    report_write_error(&e);
+                }
                 break;
             }
             Ok(size) => {
-                // TODO: Handle all posible header options including keep alive, if there is
-                // Connection: close message just close after sent answer
-                // else just maintain listening the socket until client close buffer, is IMPORTANT
-                // to reset the buffer on this case.
+                tracked.touch(false);
                 content.extend_from_slice(&buffer[..size]);
-                if content_length == 0 {
-                    if let Some(length) =
-                        get_content_length(String::from_utf8(content.clone()).unwrap())
-                    {
-                        content_length = length;
+            }
+            Err(e) => {
+                logging::debug(format_args!("Error reading from socket: {}", e));
+                break;
+            }
+        }
+    }
+}
+
+/// Returns the `503 Service Unavailable` of load that was shed, `Retry-After` in whole seconds.
+fn overloaded(retry_after: Duration) -> Response {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    Response::new(503).with_header("Retry-After", &seconds.to_string())
+}
+
+/// Answers a connection turned away by `max_per_ip` when `overload_response` is set.
+///
+/// The whole answer takes at most `LINGER_TIME`, clients turned away can't hold the task.
+async fn shed_connection(server: &Server, mut socket: TcpStream) {
+    // Nothing can be said on a TLS connection before its handshake
+    #[cfg(feature = "tls")]
+    if server.tls.is_some() {
+        return;
+    }
+    let Some(retry_after) = server.overload_retry_after else {
+        return;
+    };
+    let answering = async {
+        let response = overloaded(retry_after);
+        if response
+            .write_to(&mut socket, Some("close"), false)
+            .await
+            .is_ok()
+        {
+            linger(&mut socket).await;
+        }
+    };
+    let _ = tokio::time::timeout(LINGER_TIME, answering).await;
+}
+
+/// Drops what the client still sends for a while, before its connection is closed.
+///
+/// Closing a socket with unread data resets the connection, and the client could lose the
+/// response written just before.
+async fn linger<S>(socket: &mut S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _ = socket.shutdown().await;
+    let mut buffer = [0; 8 * 1024];
+    let draining = async {
+        let mut drained = 0;
+        while drained < MAX_LINGER_BYTES {
+            match socket.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(size) => drained += size,
+            }
+        }
+    };
+    let _ = tokio::time::timeout(LINGER_TIME, draining).await;
+}
+
+/// Socket failing its writes with `TimedOut` once one makes no progress for `timeout`.
+struct WriteTimeout<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    /// Armed while a write is pending, dropped once the socket takes bytes again.
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<S> WriteTimeout<S> {
+    fn new(inner: S, timeout: Option<Duration>) -> WriteTimeout<S> {
+        WriteTimeout {
+            inner,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    /// Passes on the outcome of a write, or fails it once it waited for too long.
+    fn limit<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let Some(timeout) = self.timeout else {
+            return poll;
+        };
+        if poll.is_ready() {
+            self.deadline = None;
+            return poll;
+        }
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no progress writing for {:?}", timeout),
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WriteTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.limit(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.limit(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.limit(cx, poll)
+    }
+}
+
+/// Writes the response to `request`, returning whether the connection stays open after it.
+async fn respond<S>(
+    server: &Server,
+    socket: &mut Metered<S>,
+    request: &Request,
+    response: Response,
+    mut keep_alive: bool,
+) -> bool
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut response = with_default_headers(server, response);
+    if let Some(timing) = request.extensions().get::<ServerTiming>() {
+        if let Some(value) = timing.header() {
+            response = response.with_header("Server-Timing", &value);
+        }
+    }
+    if server.dev_mode {
+        logging::info(format_args!("{}", dev::transaction(request, &response)));
+    }
+    server
+        .history
+        .record(request.method(), request.path(), response.status());
+    keep_alive &= !response.closes_connection();
+    let connection = connection_header(request.version(), keep_alive);
+    let head_only = *request.method() == Method::Head;
+    if let Err(e) = response.write_to(&mut *socket, connection, head_only).await {
+        // Nothing more can be sent on the connection, not even the next response
+        report_write_error(&e);
+        return false;
+    }
+    keep_alive
+}
+
+/// Adds the default charset and headers of the server to a response about to be written.
+fn with_default_headers(server: &Server, mut response: Response) -> Response {
+    if let Some(charset) = &server.default_charset {
+        response = response.with_default_charset(charset);
+    }
+    // Checked against the headers of the handler alone, not the ones added here
+    let missing: Vec<bool> = server
+        .default_headers
+        .iter()
+        .map(|header| header.append || response.header(&header.name).is_none())
+        .collect();
+    for (header, missing) in server.default_headers.iter().zip(missing) {
+        if missing {
+            response = response.with_header(&header.name, &header.value);
+        }
+    }
+    response
+}
+
+/// Answers a request whose body is streamed to its handler, reading it from `socket` meanwhile.
+///
+/// Also returns whether the whole body was read, so the connection can be reused.
+async fn answer_streamed<S>(
+    server: &Server,
+    request: &mut Request,
+    socket: &mut Metered<S>,
+    content: &mut Vec<u8>,
+    framing: Framing,
+) -> (io::Result<Response>, bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    // One chunk at a time, so a slow handler slows down reading instead of filling memory
+    let (sender, chunks) = mpsc::channel(1);
+    request.body_chunks = Some(Arc::new(std::sync::Mutex::new(Some(chunks))));
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let streaming = request::stream_body(
+        &mut reader,
+        content,
+        framing,
+        &server.limits,
+        sender,
+        server.read_buffer_size,
+    );
+    tokio::pin!(streaming);
+    let answering = answer(server, request, &mut writer);
+    tokio::pin!(answering);
+    let mut complete = None;
+    let answered = loop {
+        tokio::select! {
+            answered = &mut answering => break answered,
+            streamed = &mut streaming, if complete.is_none() => complete = Some(streamed),
+        }
+    };
+    (answered, complete == Some(true))
+}
+
+/// Returns whether the route answering `request` streams its body.
+fn streams_body(server: &Server, request: &Request) -> bool {
+    let Ok(router) = server.router_for(request) else {
+        return false;
+    };
+    let path = routed_path(server, request);
+    if let Some(scoped) = server.scoped_router(request) {
+        match scoped.find(request.method(), &path, server.max_path_segments) {
+            Match::Found(route, _) => return route.streams_body(),
+            Match::NotFound => {}
+            _ => return false,
+        }
+    }
+    if !router.streams_bodies() {
+        return false;
+    }
+    match router.find(request.method(), &path, server.max_path_segments) {
+        Match::Found(route, _) => route.streams_body(),
+        _ => false,
+    }
+}
+
+/// Returns the path of `request` the routes are matched against.
+fn routed_path(server: &Server, request: &Request) -> String {
+    let path = request::normalize_path(request.path());
+    match &server.path_rewrite {
+        Some(rewrite) => rewrite(&path),
+        None => path,
+    }
+}
+
+/// Answers a request, writing the informational responses sent by its handler meanwhile.
+///
+/// Fails if an informational response couldn't be written, the handler is dropped then since
+/// its response could never be sent either.
+async fn answer<S>(server: &Server, request: &mut Request, socket: &mut S) -> io::Result<Response>
+where
+    S: AsyncWrite + Unpin,
+{
+    // HTTP/1.0 clients don't expect informational responses, their handlers can't send any
+    if request.version() == Version::Http10 {
+        return Ok(dispatch_caught(server, request).await);
+    }
+    let (sender, mut informational) = mpsc::unbounded_channel();
+    request.informational = Some(sender);
+    let response = {
+        let dispatching = dispatch_caught(server, request);
+        tokio::pin!(dispatching);
+        loop {
+            tokio::select! {
+                response = &mut dispatching => break response,
+                Some(early) = informational.recv() => early.write_to(socket, None, false).await?,
+            }
+        }
+    };
+    request.informational = None;
+    // Synchronous handlers are done before anything could be written
+    while let Ok(early) = informational.try_recv() {
+        early.write_to(socket, None, false).await?;
+    }
+    Ok(response)
+}
+
+/// Logs an error writing to a client, after which the connection can't be used anymore.
+///
+/// Clients going away before the whole response is sent is expected, other errors aren't.
+fn report_write_error(e: &io::Error) {
+    match e.kind() {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => logging::debug(format_args!(
+            "Client disconnected before the response was sent: {}",
+            e
+        )),
+        io::ErrorKind::TimedOut => logging::debug(format_args!(
+            "Timed out writing the response, the client isn't reading it: {}",
+            e
+        )),
+        _ => logging::error(format_args!("Error writing response: {}", e)),
+    }
+}
+
+/// Dispatches a request like `dispatch` once `max_in_flight` lets it, answering `500` when
+/// its handling panics.
+async fn dispatch_caught(server: &Server, request: &mut Request) -> Response {
+    let _permit = match &server.in_flight {
+        None => None,
+        Some((limit, overflow)) => {
+            let retry_after = match overflow {
+                Overflow::Reject { retry_after } => Some(*retry_after),
+                Overflow::Wait => server.overload_retry_after,
+            };
+            match retry_after {
+                // The semaphore is never closed
+                None => limit.acquire().await.ok(),
+                Some(retry_after) => match limit.try_acquire() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => return overloaded(retry_after),
+                },
+            }
+        }
+    };
+    let (method, path) = (request.method().clone(), request.path().to_string());
+    let mut dispatching = std::pin::pin!(dispatch(server, request));
+    // Handlers are polled inline on the connection task, a panic would end the connection
+    let caught = std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| dispatching.as_mut().poll(cx))) {
+            Ok(Poll::Ready(response)) => Poll::Ready(Ok(response)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    })
+    .await;
+    match caught {
+        Ok(response) => response,
+        Err(payload) => {
+            let panic = HandlerPanic::new(method, path, payload.as_ref());
+            match &server.on_panic {
+                Some(on_panic) => on_panic(&panic),
+                None => logging::error(format_args!("{}", panic)),
+            }
+            Response::new(500)
+        }
+    }
+}
+
+/// Answers a request, running the middlewares around its routing.
+pub(crate) async fn dispatch(server: &Server, request: &mut Request) -> Response {
+    let mut ran = 0;
+    let mut answer = None;
+    for middleware in &server.middlewares {
+        ran += 1;
+        answer = middleware.before(request);
+        if answer.is_some() {
+            break;
+        }
+    }
+    let mut response = match answer {
+        Some(response) => response,
+        None => route(server, request).await,
+    };
+    for middleware in server.middlewares[..ran].iter().rev() {
+        response = middleware.after(request, response);
+    }
+    response
+}
+
+/// Finds the answer for a request.
+///
+/// Registered routes take precedence, the ones scoped to the listener of the request first,
+/// then static files. Unmatched requests are forwarded when their path belongs to a proxied
+/// prefix and answered with `404 Not Found` otherwise.
+///
+/// `TRACE` and `CONNECT` never reach routes: `TRACE` is refused with `405 Method Not Allowed`,
+/// listing the methods of the path, and `CONNECT`, whose target is a host instead of a path,
+/// with `501 Not Implemented`.
+async fn route(server: &Server, request: &mut Request) -> Response {
+    if *request.method() == Method::Connect {
+        return Response::new(501);
+    }
+    // The snapshot stays alive until the request is answered, even if the router is replaced
+    let router = match server.router_for(request) {
+        Ok(router) => router,
+        Err(unknown_host) => return unknown_host(request),
+    };
+    if *request.method() == Method::Trace {
+        let path = routed_path(server, request);
+        let max_segments = server.max_path_segments;
+        let scoped = server.scoped_router(request);
+        let mut allowed =
+            scoped.map_or(vec![], |scoped| scoped.allowed_methods(&path, max_segments));
+        if allowed.is_empty() {
+            allowed = router.allowed_methods(&path, max_segments);
+        }
+        return Response::new(405).with_header("Allow", &router::allow_header(&allowed));
+    }
+    // `OPTIONS *` asks about the server itself rather than a path
+    if request.path() == "*" {
+        let allow = router::allow_header(&router.methods());
+        return Response::new(204).with_header("Allow", &allow);
+    }
+    let path = routed_path(server, request);
+    if server.api_docs.as_ref() == Some(&path)
+        && matches!(request.method(), Method::Get | Method::Head)
+    {
+        return Response::new(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(docs::openapi(&server.current_router().routes()));
+    }
+    if server.status_endpoint
+        && path == STATUS_PATH
+        && matches!(request.method(), Method::Get | Method::Head)
+    {
+        return status(server);
+    }
+    if server.status_endpoint
+        && path == REQUESTS_PATH
+        && matches!(request.method(), Method::Get | Method::Head)
+    {
+        return recent_requests(server);
+    }
+    // Routes scoped to the listener come first, the shared ones answer what they don't match
+    let max_segments = server.max_path_segments;
+    let found = match server.scoped_router(request) {
+        Some(scoped) => match scoped.find(request.method(), &path, max_segments) {
+            Match::NotFound => router.find(request.method(), &path, max_segments),
+            found => found,
+        },
+        None => router.find(request.method(), &path, max_segments),
+    };
+    match found {
+        Match::Found(route, params) => {
+            request.params = params;
+            let started = Instant::now();
+            let handling = async {
+                match server.error_handlers.is_empty() {
+                    true => route.handle(request).await,
+                    false => {
+                        let handlers = Arc::clone(&server.error_handlers);
+                        // Synchronous handlers run when the route is called, inside the scope too
+                        let handling = async { route.handle(request).await };
+                        ERROR_HANDLERS.scope(handlers, handling).await
                     }
-                } else if content.len() >= content_length {
-                    // TODO: Sent answer after reading all data
                 }
-                if content.len() < 1024 {
-                    // TODO: Sent answer after reading all data
-                    // ###EXAMPLE
-                    // Example of type of answer as a server, the client will recieve a <h1>Hello, world!</h1>
-                    let response_body = "<h1>Hello, world!</h1>";
-                    let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: keep-alive\r\n\r\n{}",
-                response_body.len(),
-                response_body
+            };
+            let response = match server.handler_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, handling).await {
+                    Ok(response) => response,
+                    Err(_) => {
+                        logging::error(format_args!(
+                            "Handler of {} {} timed out after {:?}",
+                            request.method().as_str(),
+                            route.pattern(),
+                            timeout
+                        ));
+                        Response::new(503)
+                    }
+                },
+                None => handling.await,
+            };
+            let response = match (response.io_error(), &server.io_error_status) {
+                (Some(kind), Some(status)) => Response::new(status(kind)),
+                _ => response,
+            };
+            // Counted under the method of the route, a HEAD answered by a GET route included
+            route.stats().record(
+                &server.metrics,
+                || format!("{} {}", route.info().method.as_str(), route.pattern()),
+                started.elapsed(),
             );
-                    // Writing answer in socket
-                    if let Err(e) = socket.write_all(response.as_bytes()).await {
-                        eprintln!("Error writing response: {}", e);
+            return response;
+        }
+        Match::MethodNotAllowed(allowed) => {
+            let allow = router::allow_header(&allowed);
+            return match (request.method(), &server.method_not_allowed) {
+                (Method::Options, _) => Response::new(204).with_header("Allow", &allow),
+                (_, Some(handler)) => {
+                    let response = handler(request, &allowed);
+                    match response.header("Allow") {
+                        Some(_) => response,
+                        None => response.with_header("Allow", &allow),
                     }
-                    // ###END OF EXAMPLE
                 }
-            }
-            Err(e) => {
-                println!("Error reading from socket: {}", e);
-                break;
-            }
+                (_, None) => Response::new(405).with_header("Allow", &allow),
+            };
+        }
+        Match::NotFound => {}
+        Match::TooManySegments => return Response::new(400),
+    }
+    for files in &server.statics {
+        if let Some(file) = files.resolve(&path) {
+            return files.serve(request, &file).await;
         }
     }
+    match server.proxies.iter().find(|proxy| proxy.matches(&path)) {
+        Some(proxy) => proxy.forward(request, &path).await,
+        None => Response::new(404),
+    }
 }
 
-fn get_content_length(request: String) -> Option<usize> {
-    request
-        .lines()
-        .find(|line| line.starts_with("Content-Length:"))
-        .and_then(|line| line.split(' ').nth(1))
-        .and_then(|value| value.trim().parse::<usize>().ok())
+/// Answers the status endpoint with the counters of the server.
+fn status(server: &Server) -> Response {
+    let metrics = &server.metrics;
+    let body = format!(
+        "{{\"version\":\"{}\",\"uptime_secs\":{},\"active_connections\":{},\"requests\":{}}}",
+        env!("CARGO_PKG_VERSION"),
+        server.created.elapsed().as_secs(),
+        metrics.active_connections.load(Ordering::Relaxed),
+        metrics.requests.load(Ordering::Relaxed)
+    );
+    Response::new(200)
+        .with_header("Content-Type", "application/json")
+        .no_store()
+        .with_body(body)
+}
+
+/// Lists the recorded requests, oldest first.
+fn recent_requests(server: &Server) -> Response {
+    let entries: Vec<String> = server
+        .history
+        .list()
+        .iter()
+        .map(|recorded| {
+            let time = recorded
+                .time
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            format!(
+                "{{\"method\":\"{}\",\"path\":{},\"status\":{},\"time\":{}}}",
+                recorded.method.as_str(),
+                docs::json_string(&recorded.path),
+                recorded.status,
+                time.as_secs()
+            )
+        })
+        .collect();
+    Response::new(200)
+        .with_header("Content-Type", "application/json")
+        .no_store()
+        .with_body(format!("[{}]", entries.join(",")))
+}
+
+/// Returns the `Connection` header value matching the keep-alive decision for a request.
+///
+/// HTTP/1.1 connections are persistent by default, so the header is only sent when the
+/// decision differs from what the client would assume.
+fn connection_header(version: Version, keep_alive: bool) -> Option<&'static str> {
+    match (version, keep_alive) {
+        (_, false) => Some("close"),
+        (Version::Http10, true) => Some("keep-alive"),
+        (Version::Http11, true) => None,
+    }
 }
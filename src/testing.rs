@@ -0,0 +1,173 @@
+//! Helpers shared by the tests of the crate.
+
+#[cfg(feature = "tls")]
+use crate::tls::rustls::{
+    self,
+    pki_types::{PrivatePkcs8KeyDer, ServerName},
+};
+use crate::{
+    request::{self, Limits, Request},
+    server::{self, Server},
+};
+#[cfg(feature = "tls")]
+use std::{io, net::SocketAddr};
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Parses `raw` as if a client had sent it.
+pub(crate) fn request(raw: &str) -> Request {
+    let mut buffer = raw.as_bytes().to_vec();
+    request::take_request(&mut buffer, &Limits::default())
+        .unwrap()
+        .unwrap()
+}
+
+/// Answers `raw` with the middlewares and routes of `server`, returning the exact bytes of the
+/// response as text.
+///
+/// No connection is involved, so nothing is added to the response head but what `to_wire`
+/// writes.
+pub(crate) async fn wire(server: &Server, raw: &str) -> String {
+    let response = server::dispatch(server, &mut request(raw)).await;
+    response.to_wire_string().await.unwrap()
+}
+
+/// Returns an empty directory only used by the test called `name`.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mini_rest_{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Returns a configuration with a self-signed certificate for `localhost`, along with the
+/// roots a client trusting it needs.
+#[cfg(feature = "tls")]
+pub(crate) fn tls_config() -> (rustls::ServerConfig, rustls::RootCertStore) {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let certificate = generated.cert.der().clone();
+    let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate.clone()], key.into())
+        .unwrap();
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certificate).unwrap();
+    (config, roots)
+}
+
+/// Opens a TLS connection to `localhost` at `address`, offering `alpn` protocols.
+#[cfg(feature = "tls")]
+pub(crate) async fn tls_connect(
+    address: SocketAddr,
+    roots: rustls::RootCertStore,
+    alpn: &[&str],
+) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let mut config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let socket = TcpStream::connect(address).await?;
+    let name = ServerName::try_from("localhost").unwrap();
+    connector.connect(name, socket).await
+}
+
+/// Client side of a single connection served by a test server.
+pub(crate) struct Connection {
+    stream: TcpStream,
+    buffered: Vec<u8>,
+}
+
+/// Serves a single connection with `server` and returns the client side of it.
+pub(crate) async fn connect(server: Server) -> Connection {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = Arc::new(server);
+    tokio::spawn(server::shut_down_when_stopped(Arc::clone(&server)));
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        server::handle_client(socket, server, None).await;
+    });
+
+    Connection::new(TcpStream::connect(address).await.unwrap())
+}
+
+/// Serves a single connection with `server` and sends `raw` through it.
+///
+/// Returns everything the server answered until it closed the connection, so `raw` must end
+/// with a request asking to close it.
+pub(crate) async fn send(server: Server, raw: &str) -> String {
+    let mut connection = connect(server).await;
+    connection.stream.write_all(raw.as_bytes()).await.unwrap();
+    connection.read_to_end().await
+}
+
+/// Same as `send`, but the client stops writing right after `raw`.
+pub(crate) async fn send_and_close(server: Server, raw: &str) -> String {
+    let mut connection = connect(server).await;
+    connection.stream.write_all(raw.as_bytes()).await.unwrap();
+    connection.stream.shutdown().await.unwrap();
+    connection.read_to_end().await
+}
+
+impl Connection {
+    /// Client side of a connection to any server, opened with `stream`.
+    pub(crate) fn new(stream: TcpStream) -> Connection {
+        Connection {
+            stream,
+            buffered: vec![],
+        }
+    }
+
+    /// Sends `raw` and reads a single response framed by its `Content-Length`.
+    ///
+    /// The connection stays open, so several requests can be sent one after another through it.
+    /// Answers to `HEAD` requests are read without body.
+    pub(crate) async fn request(&mut self, raw: &str) -> String {
+        self.stream.write_all(raw.as_bytes()).await.unwrap();
+        let head_only = raw.starts_with("HEAD ");
+        loop {
+            if let Some(length) = self.response_length(head_only) {
+                let response = self.buffered.drain(..length).collect::<Vec<u8>>();
+                return String::from_utf8_lossy(&response).into_owned();
+            }
+            let mut buffer = [0; 1024];
+            let size = self.stream.read(&mut buffer).await.unwrap();
+            assert!(size > 0, "connection closed before a full response arrived");
+            self.buffered.extend_from_slice(&buffer[..size]);
+        }
+    }
+
+    /// Sends `raw` without waiting for an answer.
+    pub(crate) async fn send(&mut self, raw: &str) {
+        self.stream.write_all(raw.as_bytes()).await.unwrap();
+    }
+
+    /// Reads everything until the server closes the connection.
+    pub(crate) async fn read_to_end(&mut self) -> String {
+        self.stream.read_to_end(&mut self.buffered).await.unwrap();
+        String::from_utf8_lossy(&std::mem::take(&mut self.buffered)).into_owned()
+    }
+
+    /// Returns the length of the first buffered response if it fully arrived.
+    fn response_length(&self, head_only: bool) -> Option<usize> {
+        let head_end = self.buffered.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+        let head = String::from_utf8_lossy(&self.buffered[..head_end]);
+        let body_length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.trim().parse::<usize>().unwrap());
+        let length = match head_only {
+            true => head_end,
+            false => head_end + body_length,
+        };
+        (self.buffered.len() >= length).then_some(length)
+    }
+}
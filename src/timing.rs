@@ -0,0 +1,83 @@
+//! Durations reported to the client in a `Server-Timing` header, see `Request::timing`.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Measurements recorded while answering a request.
+///
+/// Copies share the same measurements, so one taken before an `.await` records into the
+/// request all the same.
+#[derive(Debug, Clone, Default)]
+pub struct ServerTiming {
+    metrics: Arc<Mutex<Vec<Metric>>>,
+}
+
+#[derive(Debug)]
+struct Metric {
+    name: String,
+    duration: Duration,
+    description: Option<String>,
+}
+
+impl ServerTiming {
+    /// Records that `name` took `duration`, a name recorded twice is sent twice.
+    ///
+    /// # Panics
+    /// If `name` isn't a token, with only letters, digits and `!#$%&'*+-.^_`|~`.
+    pub fn record(&self, name: &str, duration: Duration) {
+        self.push(name, duration, None);
+    }
+
+    /// Same as `record`, with a description shown by the developer tools of browsers.
+    ///
+    /// Control characters are left out of the description, a line break would end the header.
+    pub fn record_described(&self, name: &str, duration: Duration, description: &str) {
+        let description = description.chars().filter(|c| !c.is_control()).collect();
+        self.push(name, duration, Some(description));
+    }
+
+    fn push(&self, name: &str, duration: Duration, description: Option<String>) {
+        assert!(
+            crate::request::is_token(name),
+            "Invalid Server-Timing metric name {:?}",
+            name
+        );
+        self.metrics.lock().unwrap().push(Metric {
+            name: name.to_string(),
+            duration,
+            description,
+        });
+    }
+
+    /// Returns the value of the `Server-Timing` header, `None` if nothing was recorded.
+    pub(crate) fn header(&self) -> Option<String> {
+        let metrics = self.metrics.lock().unwrap();
+        if metrics.is_empty() {
+            return None;
+        }
+        let entries: Vec<String> = metrics
+            .iter()
+            .map(|metric| {
+                let mut entry = metric.name.clone();
+                if let Some(description) = &metric.description {
+                    let escaped = description.replace('\\', "\\\\").replace('"', "\\\"");
+                    entry.push_str(&format!(";desc=\"{}\"", escaped));
+                }
+                entry.push_str(&format!(";dur={}", milliseconds(metric.duration)));
+                entry
+            })
+            .collect();
+        Some(entries.join(", "))
+    }
+}
+
+/// Formats `duration` in milliseconds, down to the microsecond and without trailing zeros.
+fn milliseconds(duration: Duration) -> String {
+    let formatted = format!("{:.3}", duration.as_micros() as f64 / 1000.0);
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
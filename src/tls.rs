@@ -0,0 +1,32 @@
+//! Serving over TLS, with the `tls` feature.
+//!
+//! The server takes a `rustls` configuration as is, see `ServerActions::tls`, so certificates,
+//! client authentication and protocol versions are set up with `rustls` itself.
+
+use crate::request::TlsInfo;
+use tokio_rustls::rustls::ServerConnection;
+
+pub use tokio_rustls::rustls;
+
+/// Describes the session negotiated on a connection once its handshake is done.
+pub(crate) fn info(connection: &ServerConnection) -> TlsInfo {
+    TlsInfo {
+        alpn: connection
+            .alpn_protocol()
+            .map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+        cipher_suite: connection
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .unwrap_or_default(),
+        protocol_version: connection
+            .protocol_version()
+            .map(|version| format!("{:?}", version))
+            .unwrap_or_default(),
+        peer_certificates: connection
+            .peer_certificates()
+            .unwrap_or_default()
+            .iter()
+            .map(|certificate| certificate.to_vec())
+            .collect(),
+    }
+}